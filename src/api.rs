@@ -4,40 +4,112 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use secp256k1::PublicKey;
 use crate::blockchain::Blockchain;
-use crate::block::Block;
-use crate::server::start_node_server;
-use crate::smart_contract::{ContractManager, SmartContract, ContractType};  // Import ContractType
+use crate::block::{Block, BlockHash};
+use crate::merkle::MerkleProof;
+use crate::finality::Justification;
+use crate::transaction::{Receipt, Transaction, TxHash};
+use crate::network::Network;
+use crate::peer_manager::{PeerManager, PeerStatus};
+use crate::smart_contract::{ContractAction, ContractRequest, SmartContract, ContractType};  // Import ContractType
+use crate::transaction::TransactionPayload;
+use crate::wasm::MAX_CALL_GAS;
 use crate::public_key_serde::SerializablePublicKey;
 use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
 
 #[derive(Serialize)]
 struct BlockchainStatusResponse {
     block_height: usize,
-    current_hash: String,
+    current_hash: BlockHash,
+    finalized_height: u64,
+    finalized_hash: BlockHash,
+    reorg_count: u64,
+}
+
+#[derive(Serialize)]
+struct PeersResponse {
+    inbound: usize,
+    outbound: usize,
+    peers: Vec<PeerStatus>,
+}
+
+#[derive(Serialize)]
+struct FinalityResponse {
+    finalized_height: u64,
+    finalized_hash: BlockHash,
+    justification: Option<Justification>,
 }
 
 #[derive(Serialize)]
 struct BlockResponse {
+    version: u32,
     index: u64,
     timestamp: u128,
-    previous_hash: String,
-    hash: String,
+    previous_hash: BlockHash,
+    hash: BlockHash,
     data: String,
+    merkle_root: BlockHash,
     node_id: String,
+    transactions: Vec<Transaction>,
+}
+
+#[derive(Serialize)]
+struct MempoolResponse {
+    pending: usize,
+    transactions: Vec<MempoolTransactionResponse>,
+}
+
+#[derive(Serialize)]
+struct MempoolTransactionResponse {
+    hash: TxHash,
+    sender: SerializablePublicKey,
+    nonce: u64,
+    fee: u64,
 }
 
+#[derive(Serialize)]
+struct TransactionProofResponse {
+    block_index: u64,
+    block_hash: BlockHash,
+    merkle_root: BlockHash,
+    tx_hash: TxHash,
+    proof: MerkleProof,
+}
+
+// Deploy and execute requests become transactions from the owner or caller
+// with a `TransactionPayload::Contract` payload for this node's chain; the
+// signature is over that transaction.
 #[derive(Serialize, Deserialize)]
 struct ContractOperationRequest {
     id: String,
+    // Hex-encoded public key of the owner, who signs the transaction.
     owner: String,
     code: Vec<u8>,
     contract_type: ContractType,  // Add contract_type field
+    nonce: u64,
+    #[serde(default)]
+    fee: u64,
+    // Hex-encoded DER signature.
+    signature: String,
 }
 
 #[derive(Serialize, Deserialize)]
 struct ContractExecutionRequest {
     id: String,
     input: String,
+    // Hex-encoded public key of the signer, whom the contract sees as its caller.
+    caller: String,
+    #[serde(default = "default_gas_limit")]
+    gas_limit: u64,
+    nonce: u64,
+    #[serde(default)]
+    fee: u64,
+    signature: String,
+}
+
+fn default_gas_limit() -> u64 {
+    MAX_CALL_GAS
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +117,18 @@ struct ContractCheckRequest {
     id: String,
 }
 
+#[derive(Serialize, Deserialize)]
+struct ContractNonceRequest {
+    caller: String,
+}
+
+// What a client needs to sign its next contract transaction.
+#[derive(Serialize)]
+struct ContractNonceResponse {
+    chain_id: String,
+    nonce: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ContractDetails {
     id: String,
@@ -63,27 +147,28 @@ pub struct OperationResponse {
 impl From<&Block> for BlockResponse {
     fn from(block: &Block) -> Self {
         BlockResponse {
+            version: block.version,
             index: block.index,
             timestamp: block.timestamp,
-            previous_hash: block.previous_hash.clone(),
-            hash: block.hash.clone(),
+            previous_hash: block.previous_hash,
+            hash: block.hash,
             data: block.data.clone(),
+            merkle_root: block.merkle_root,
             node_id: block.node_id.clone(),
+            transactions: block.transactions.clone(),
         }
     }
 }
 
-fn with_contract_manager(contract_manager: Arc<Mutex<ContractManager>>) -> impl Filter<Extract = (Arc<Mutex<ContractManager>>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || contract_manager.clone())
-}
+fn contract_routes(network: Network) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let network_filter = warp::any().map(move || network.clone());
 
-fn contract_routes(contract_manager: Arc<Mutex<ContractManager>>) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     let deploy_contract = warp::path("contract")
         .and(warp::path("deploy"))
         .and(warp::path::end())
         .and(warp::post())
         .and(json_body::<ContractOperationRequest>())
-        .and(with_contract_manager(contract_manager.clone()))
+        .and(network_filter.clone())
         .and_then(deploy_contract_handler);
 
     let execute_contract = warp::path("contract")
@@ -91,7 +176,7 @@ fn contract_routes(contract_manager: Arc<Mutex<ContractManager>>) -> impl Filter
         .and(warp::path::end())
         .and(warp::post())
         .and(json_body::<ContractExecutionRequest>())
-        .and(with_contract_manager(contract_manager.clone()))
+        .and(network_filter.clone())
         .and_then(execute_contract_handler);
 
     let check_contract = warp::path("contract")
@@ -99,27 +184,40 @@ fn contract_routes(contract_manager: Arc<Mutex<ContractManager>>) -> impl Filter
         .and(warp::path::end())
         .and(warp::get())
         .and(warp::query::<ContractCheckRequest>())
-        .and(with_contract_manager(contract_manager.clone()))
+        .and(network_filter.clone())
         .and_then(check_contract_exists_handler);
 
-    deploy_contract.or(execute_contract).or(check_contract)
+    let contract_nonce = warp::path("contract")
+        .and(warp::path("nonce"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<ContractNonceRequest>())
+        .and(network_filter)
+        .and_then(contract_nonce_handler);
+
+    deploy_contract.or(execute_contract).or(check_contract).or(contract_nonce)
 }
 
-pub async fn start_api(blockchain: Arc<Mutex<Blockchain>>, contract_manager: Arc<Mutex<ContractManager>>) {
+// Binds the API server and returns the future that serves it.
+pub fn start_api(network: Network, address: SocketAddr) -> Result<impl Future<Output = ()>, String> {
+    let blockchain = network.blockchain.clone();
+    let peers = network.peers.clone();
+    let contract_mgmt_routes = contract_routes(network.clone());
     let blockchain_filter = warp::any().map(move || blockchain.clone());
+    let peers_filter = warp::any().map(move || peers.clone());
+    let network_filter = warp::any().map(move || network.clone());
 
+    // The node runtime starts the P2P listener before the API, so this
+    // only reports where it is listening.
     let start_node_route = warp::path("start_node")
         .and(warp::post())
-        .and(blockchain_filter.clone())
-        .and_then(move |blockchain: Arc<Mutex<Blockchain>>| async move {
-            let ip = "0.0.0.0:6397".to_string();
-            tokio::spawn(async move {
-                start_node_server(ip, blockchain).await;
-            });
-            Ok::<_, Rejection>(warp::reply::json(&OperationResponse { 
-                success: true, 
-                message: "Node started successfully".to_string(),
-                details: None 
+        .and(network_filter.clone())
+        .and_then(|network: Network| async move {
+            let address = network.identity.listen_address.clone().unwrap_or_default();
+            Ok::<_, Rejection>(warp::reply::json(&OperationResponse {
+                success: true,
+                message: format!("Node running on {}", address),
+                details: None
             }))
         });
 
@@ -130,11 +228,109 @@ pub async fn start_api(blockchain: Arc<Mutex<Blockchain>>, contract_manager: Arc
             let blockchain = blockchain.lock().await;
             let status = BlockchainStatusResponse {
                 block_height: blockchain.blocks.len(),
-                current_hash: blockchain.blocks.last().map_or(String::new(), |b| b.hash.clone()),
+                current_hash: blockchain.blocks.last().map_or(BlockHash::ZERO, |b| b.hash),
+                finalized_height: blockchain.finality.finalized_height,
+                finalized_hash: blockchain.finality.finalized_hash,
+                reorg_count: blockchain.reorg_count,
             };
             Ok::<_, Rejection>(warp::reply::json(&status))
         });
 
+    let finality_route = warp::path("finality")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|blockchain: Arc<Mutex<Blockchain>>| async move {
+            let blockchain = blockchain.lock().await;
+            let finalized_height = blockchain.finality.finalized_height;
+            let response = FinalityResponse {
+                finalized_height,
+                finalized_hash: blockchain.finality.finalized_hash,
+                justification: blockchain.justification(finalized_height),
+            };
+            Ok::<_, Rejection>(warp::reply::json(&response))
+        });
+
+    let proof_route = warp::path!("blocks" / u64 / "transactions" / usize / "proof")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|index: u64, tx_index: usize, blockchain: Arc<Mutex<Blockchain>>| async move {
+            let blockchain = blockchain.lock().await;
+            let response = blockchain.blocks.get(index as usize)
+                .and_then(|block| {
+                    block.transaction_proof(tx_index).map(|proof| TransactionProofResponse {
+                        block_index: block.index,
+                        block_hash: block.hash,
+                        merkle_root: block.merkle_root,
+                        tx_hash: block.transactions[tx_index].hash(),
+                        proof,
+                    })
+                });
+            match response {
+                Some(response) => Ok(warp::reply::json(&response)),
+                None => Err(warp::reject::not_found()),
+            }
+        });
+
+    let receipts_route = warp::path!("blocks" / u64 / "receipts")
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|index: u64, blockchain: Arc<Mutex<Blockchain>>| async move {
+            let blockchain = blockchain.lock().await;
+            let receipts: Option<Vec<Receipt>> = blockchain.blocks.get(index as usize)
+                .and_then(|block| blockchain.receipts(&block.hash).ok().flatten());
+            match receipts {
+                Some(receipts) => Ok(warp::reply::json(&receipts)),
+                None => Err(warp::reject::not_found()),
+            }
+        });
+
+    let submit_transaction_route = warp::path("transactions")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(json_body::<Transaction>())
+        .and(network_filter.clone())
+        .and_then(|tx: Transaction, network: Network| async move {
+            let submitted = network.blockchain.lock().await.submit_transaction(tx.clone());
+            if submitted.is_ok() {
+                network.announce_transaction(&tx).await;
+            }
+            let response = match submitted {
+                Ok(hash) => OperationResponse {
+                    success: true,
+                    message: hash.to_hex(),
+                    details: None,
+                },
+                Err(e) => OperationResponse {
+                    success: false,
+                    message: e,
+                    details: None,
+                },
+            };
+            Ok::<_, Rejection>(warp::reply::json(&response))
+        });
+
+    let mempool_route = warp::path("mempool")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(blockchain_filter.clone())
+        .and_then(|blockchain: Arc<Mutex<Blockchain>>| async move {
+            let blockchain = blockchain.lock().await;
+            let transactions = blockchain.mempool.entries().into_iter()
+                .map(|entry| MempoolTransactionResponse {
+                    hash: entry.hash,
+                    sender: entry.transaction.sender.clone(),
+                    nonce: entry.transaction.nonce,
+                    fee: entry.transaction.fee,
+                })
+                .collect();
+            let response = MempoolResponse {
+                pending: blockchain.mempool.len(),
+                transactions,
+            };
+            Ok::<_, Rejection>(warp::reply::json(&response))
+        });
+
     let blocks_route = warp::path("blocks")
         .and(warp::get())
         .and(blockchain_filter)
@@ -144,14 +340,27 @@ pub async fn start_api(blockchain: Arc<Mutex<Blockchain>>, contract_manager: Arc
             Ok::<_, Rejection>(warp::reply::json(&blocks))
         });
 
-    let contract_mgmt_routes = contract_routes(contract_manager);
+    let peers_route = warp::path("peers")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(peers_filter)
+        .and_then(|peers: Arc<Mutex<PeerManager>>| async move {
+            let peers = peers.lock().await;
+            let (inbound, outbound) = peers.connection_counts();
+            let response = PeersResponse {
+                inbound,
+                outbound,
+                peers: peers.statuses(),
+            };
+            Ok::<_, Rejection>(warp::reply::json(&response))
+        });
 
-    let routes = start_node_route.or(status_route).or(blocks_route).or(contract_mgmt_routes)
+    let routes = start_node_route.or(status_route).or(finality_route).or(proof_route).or(receipts_route).or(blocks_route).or(submit_transaction_route).or(mempool_route).or(peers_route).or(contract_mgmt_routes)
         .recover(handle_rejection);
 
-    tokio::spawn(async move {
-        warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    });
+    let (_, server) = warp::serve(routes).try_bind_ephemeral(address)
+        .map_err(|e| format!("Cannot serve the API on {}: {}", address, e))?;
+    Ok(server)
 }
 
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
@@ -182,61 +391,94 @@ where
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
-async fn deploy_contract_handler(body: ContractOperationRequest, contract_manager: Arc<Mutex<ContractManager>>) -> Result<Json, Rejection> {
-    let mut manager = contract_manager.lock().await;
-    let public_key = PublicKey::from_slice(&hex::decode(&body.owner).unwrap()).unwrap(); // Consider proper error handling
-    let contract_id = body.id.clone();  // Clone id to avoid move
-    match manager.deploy_contract(contract_id, public_key, body.code, body.contract_type) {
-        Ok(contract) => Ok(warp::reply::json(&OperationResponse { 
-            success: true, 
-            message: "Contract deployed successfully".to_string(),
-            details: Some(ContractDetails {
-                id: body.id,  // Use the original body.id
-                owner: contract.owner,
-                code: contract.code,
-                state: contract.state,
-            })
-        })),
-        Err(e) => Ok(warp::reply::json(&OperationResponse { 
-            success: false, 
-            message: e, 
-            details: None 
-        })),
+fn parse_public_key(key: &str) -> Option<PublicKey> {
+    hex::decode(key).ok().and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+}
+
+// Submits a contract transaction to the mempool and announces it to peers.
+// What it did shows up in its receipt once a block includes it.
+async fn submit_contract_transaction(network: &Network, sender: &str, nonce: u64, fee: u64, signature: String, payload: impl FnOnce(String) -> ContractRequest) -> OperationResponse {
+    let sender = match parse_public_key(sender) {
+        Some(key) => key,
+        None => return OperationResponse {
+            success: false,
+            message: "Invalid public key".to_string(),
+            details: None,
+        },
+    };
+    let chain_id = network.blockchain.lock().await.chain_id.clone();
+    let mut tx = Transaction::new(sender, nonce, fee, TransactionPayload::Contract(payload(chain_id)));
+    tx.signature = signature;
+    let submitted = network.blockchain.lock().await.submit_transaction(tx.clone());
+    match submitted {
+        Ok(hash) => {
+            network.announce_transaction(&tx).await;
+            OperationResponse {
+                success: true,
+                message: hash.to_hex(),
+                details: None,
+            }
+        }
+        Err(e) => OperationResponse {
+            success: false,
+            message: e,
+            details: None,
+        },
     }
 }
 
-async fn execute_contract_handler(body: ContractExecutionRequest, contract_manager: Arc<Mutex<ContractManager>>) -> Result<Json, Rejection> {
-    let mut manager = contract_manager.lock().await;
-    let output = manager.execute_contract(&body.id, &body.input);
-    Ok(warp::reply::json(&OperationResponse { 
-        success: true, 
-        message: output, 
-        details: None 
-    }))
+async fn deploy_contract_handler(body: ContractOperationRequest, network: Network) -> Result<Json, Rejection> {
+    let action = ContractAction::Deploy { code: body.code, contract_type: body.contract_type };
+    let response = submit_contract_transaction(&network, &body.owner, body.nonce, body.fee, body.signature, |chain_id| ContractRequest {
+        chain_id,
+        contract_id: body.id,
+        action,
+    }).await;
+    Ok(warp::reply::json(&response))
+}
+
+async fn execute_contract_handler(body: ContractExecutionRequest, network: Network) -> Result<Json, Rejection> {
+    let action = ContractAction::Execute { input: body.input, gas_limit: body.gas_limit };
+    let response = submit_contract_transaction(&network, &body.caller, body.nonce, body.fee, body.signature, |chain_id| ContractRequest {
+        chain_id,
+        contract_id: body.id,
+        action,
+    }).await;
+    Ok(warp::reply::json(&response))
 }
 
-async fn check_contract_exists_handler(query: ContractCheckRequest, contract_manager: Arc<Mutex<ContractManager>>) -> Result<Json, Rejection> {
-    let manager = contract_manager.lock().await;
-    let exists = manager.check_contract_exists(&query.id);
-    let response = if exists {
-        let contract = manager.contracts.get(&query.id).unwrap();  // Assuming contract is in the HashMap
-        let details = ContractDetails {
-            id: query.id.clone(),
-            owner: contract.owner.clone(),
-            code: contract.code.clone(),
-            state: contract.state.clone(),
-        };
-        OperationResponse {
+async fn check_contract_exists_handler(query: ContractCheckRequest, network: Network) -> Result<Json, Rejection> {
+    let blockchain = network.blockchain.lock().await;
+    let response = match blockchain.contract_manager.contract(&query.id) {
+        Ok(Some(contract)) => OperationResponse {
             success: true,
             message: "Contract exists".to_string(),
-            details: Some(details),
-        }
-    } else {
-        OperationResponse {
+            details: Some(ContractDetails {
+                id: query.id.clone(),
+                owner: contract.owner,
+                code: contract.code,
+                state: contract.state,
+            }),
+        },
+        Ok(None) => OperationResponse {
             success: false,
             message: "Contract does not exist".to_string(),
             details: None,
-        }
+        },
+        Err(e) => OperationResponse {
+            success: false,
+            message: format!("Cannot read contract {}: {}", query.id, e),
+            details: None,
+        },
     };
     Ok(warp::reply::json(&response))
 }
+
+async fn contract_nonce_handler(query: ContractNonceRequest, network: Network) -> Result<Json, Rejection> {
+    let caller = parse_public_key(&query.caller).ok_or_else(warp::reject::not_found)?;
+    let blockchain = network.blockchain.lock().await;
+    Ok(warp::reply::json(&ContractNonceResponse {
+        chain_id: blockchain.chain_id.clone(),
+        nonce: blockchain.pending_nonce(&caller),
+    }))
+}
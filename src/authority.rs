@@ -0,0 +1,190 @@
+use std::collections::{BTreeSet, HashMap};
+use serde::{Serialize, Deserialize};
+use secp256k1::PublicKey;
+use crate::node::Node;
+use crate::public_key_serde::SerializablePublicKey;
+
+// Authority set changes only take effect at multiples of this height.
+pub const EPOCH_LENGTH: u64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthoritySet {
+    pub from_height: u64,
+    pub authorities: Vec<Node>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AuthorityProposal {
+    pub node_id: String,
+    pub public_key: SerializablePublicKey,
+    pub add: bool,
+}
+
+// History of authority sets by starting height plus the running tally of votes
+// for proposals that have not reached a majority yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuthoritySchedule {
+    pub history: Vec<AuthoritySet>,
+    votes: HashMap<AuthorityProposal, BTreeSet<String>>,
+}
+
+impl AuthoritySchedule {
+    pub fn new(genesis_authorities: Vec<Node>) -> Self {
+        AuthoritySchedule {
+            history: vec![AuthoritySet { from_height: 0, authorities: genesis_authorities }],
+            votes: HashMap::new(),
+        }
+    }
+
+    // Only the genesis set, as before any block was applied.
+    pub fn genesis(&self) -> Self {
+        AuthoritySchedule {
+            history: self.history.first().cloned().into_iter().collect(),
+            votes: HashMap::new(),
+        }
+    }
+
+    // Open proposals and who voted for them, in no particular order.
+    pub fn votes(&self) -> impl Iterator<Item = (&AuthorityProposal, &BTreeSet<String>)> {
+        self.votes.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    // The set that seals and validates the block at `height`.
+    pub fn authorities_at(&self, height: u64) -> &[Node] {
+        self.history.iter()
+            .rev()
+            .find(|set| set.from_height <= height)
+            .map_or(&[], |set| set.authorities.as_slice())
+    }
+
+    pub fn authority_by_key(&self, height: u64, public_key: &PublicKey) -> Option<&Node> {
+        self.authorities_at(height).iter().find(|node| node.is_authority && node.public_key == *public_key)
+    }
+
+    pub fn next_epoch_start(height: u64) -> u64 {
+        (height / EPOCH_LENGTH + 1) * EPOCH_LENGTH
+    }
+
+    // Records a vote cast in the block at `height`. Returns the height at which
+    // the change takes effect once the proposal reaches a strict majority.
+    pub fn apply_vote(&mut self, height: u64, voter_key: &PublicKey, proposal: AuthorityProposal) -> Result<Option<u64>, String> {
+        let voter = self.authority_by_key(height, voter_key)
+            .ok_or_else(|| "Vote sender is not an authority".to_string())?
+            .id
+            .clone();
+
+        let current = self.authorities_at(height);
+        let is_member = current.iter().any(|node| node.id == proposal.node_id);
+        if proposal.add == is_member {
+            return Err(format!("Proposal for {} does not change the authority set", proposal.node_id));
+        }
+        let set_size = current.iter().filter(|node| node.is_authority).count();
+        let current_ids: BTreeSet<String> = current.iter().map(|node| node.id.clone()).collect();
+
+        let voters = self.votes.entry(proposal.clone()).or_default();
+        voters.insert(voter);
+        // Votes from nodes that have since left the set no longer count.
+        voters.retain(|id| current_ids.contains(id));
+        if voters.len() * 2 <= set_size {
+            return Ok(None);
+        }
+
+        self.votes.remove(&proposal);
+        let effective_height = Self::next_epoch_start(height);
+        self.schedule_change(effective_height, &proposal)?;
+        Ok(Some(effective_height))
+    }
+
+    fn schedule_change(&mut self, effective_height: u64, proposal: &AuthorityProposal) -> Result<(), String> {
+        let already_scheduled = self.history.last().map_or(false, |set| set.from_height == effective_height);
+        let mut next = self.history.last().map(|set| set.authorities.clone()).unwrap_or_default();
+        if proposal.add {
+            if !next.iter().any(|node| node.id == proposal.node_id) {
+                next.push(Node {
+                    id: proposal.node_id.clone(),
+                    is_authority: true,
+                    public_key: proposal.public_key.0,
+                });
+            }
+        } else {
+            next.retain(|node| node.id != proposal.node_id);
+            if next.is_empty() {
+                return Err("Cannot remove the last authority".to_string());
+            }
+        }
+
+        if already_scheduled {
+            self.history.last_mut().expect("Scheduled set exists").authorities = next;
+        } else {
+            self.history.push(AuthoritySet { from_height: effective_height, authorities: next });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Secp256k1, SecretKey};
+
+    fn key(seed: u8) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[seed; 32]).unwrap())
+    }
+
+    fn authorities(count: u8) -> Vec<Node> {
+        (1..=count).map(|seed| Node { id: format!("node{}", seed), is_authority: true, public_key: key(seed) }).collect()
+    }
+
+    fn proposal(seed: u8, add: bool) -> AuthorityProposal {
+        AuthorityProposal { node_id: format!("node{}", seed), public_key: SerializablePublicKey(key(seed)), add }
+    }
+
+    fn ids(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(|node| node.id.as_str()).collect()
+    }
+
+    #[test]
+    fn strict_majority_takes_effect_at_the_next_epoch() {
+        let mut schedule = AuthoritySchedule::new(authorities(4));
+        assert_eq!(schedule.apply_vote(130, &key(1), proposal(5, true)), Ok(None));
+        // A second vote from the same authority does not count again.
+        assert_eq!(schedule.apply_vote(131, &key(1), proposal(5, true)), Ok(None));
+        // Two of four is not a strict majority.
+        assert_eq!(schedule.apply_vote(132, &key(2), proposal(5, true)), Ok(None));
+        assert_eq!(schedule.apply_vote(133, &key(3), proposal(5, true)), Ok(Some(200)));
+
+        assert_eq!(schedule.votes().count(), 0);
+        assert_eq!(ids(schedule.authorities_at(199)), vec!["node1", "node2", "node3", "node4"]);
+        assert_eq!(ids(schedule.authorities_at(200)), vec!["node1", "node2", "node3", "node4", "node5"]);
+        assert!(schedule.authority_by_key(200, &key(5)).is_some());
+    }
+
+    #[test]
+    fn removal_needs_a_majority_of_the_current_set() {
+        let mut schedule = AuthoritySchedule::new(authorities(3));
+        assert_eq!(schedule.apply_vote(10, &key(1), proposal(3, false)), Ok(None));
+        assert_eq!(schedule.apply_vote(11, &key(2), proposal(3, false)), Ok(Some(100)));
+        assert_eq!(ids(schedule.authorities_at(100)), vec!["node1", "node2"]);
+        assert_eq!(ids(schedule.authorities_at(99)), vec!["node1", "node2", "node3"]);
+    }
+
+    #[test]
+    fn votes_that_change_nothing_or_come_from_outsiders_are_rejected() {
+        let mut schedule = AuthoritySchedule::new(authorities(2));
+        assert!(schedule.apply_vote(1, &key(9), proposal(5, true)).is_err());
+        assert!(schedule.apply_vote(1, &key(1), proposal(2, true)).is_err());
+        assert!(schedule.apply_vote(1, &key(1), proposal(5, false)).is_err());
+        assert_eq!(schedule.votes().count(), 0);
+    }
+
+    #[test]
+    fn the_last_authority_cannot_be_removed() {
+        let mut schedule = AuthoritySchedule::new(authorities(1));
+        assert!(schedule.apply_vote(1, &key(1), proposal(1, false)).is_err());
+        assert_eq!(ids(schedule.authorities_at(100)), vec!["node1"]);
+    }
+}
@@ -1,42 +1,270 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use sha2::{Sha256, Digest};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fmt;
 use secp256k1::{Secp256k1, SecretKey, Message};
+use crate::merkle::{merkle_root, merkle_proof, MerkleProof};
+use crate::transaction::Transaction;
+use crate::poa::now_millis;
+
+// Blocks written before the binary header encoding existed. Their hash is the
+// SHA-256 of the concatenated field strings and is kept as-is after upgrading.
+pub const LEGACY_BLOCK_VERSION: u32 = 0;
+// From version 1 the hash is the SHA-256 of the length-prefixed binary header
+// (see `header_bytes`); version 2 adds the root of the state the block was
+// built on.
+pub const STATE_ROOT_BLOCK_VERSION: u32 = 2;
+pub const BLOCK_VERSION: u32 = STATE_ROOT_BLOCK_VERSION;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct BlockHash(pub [u8; 32]);
+
+impl BlockHash {
+    pub const ZERO: BlockHash = BlockHash([0u8; 32]);
+
+    pub fn from_hex(s: &str) -> Result<Self, String> {
+        let bytes = hex::decode(s).map_err(|e| format!("Invalid hash hex: {}", e))?;
+        Self::from_slice(&bytes)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != 32 {
+            return Err(format!("Hash must be 32 bytes, got {}", bytes.len()));
+        }
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(bytes);
+        Ok(BlockHash(hash))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == BlockHash::ZERO
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockHash({})", self.to_hex())
+    }
+}
+
+// Hex string for JSON (API, peers), raw 32 bytes for bincode (sled).
+impl Serialize for BlockHash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_hex())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            BlockHash::from_hex(&s).map_err(serde::de::Error::custom)
+        } else {
+            Ok(BlockHash(<[u8; 32]>::deserialize(deserializer)?))
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Block {
+    pub version: u32,
+    pub index: u64,
+    pub timestamp: u128,
+    pub previous_hash: BlockHash,
+    pub hash: BlockHash,
+    pub nonce: u64,
+    pub data: String,
+    pub merkle_root: BlockHash,
+    // Root of the chain state after the parent block (see `snapshot`). Zero
+    // for genesis and for blocks older than `STATE_ROOT_BLOCK_VERSION`.
+    pub state_root: BlockHash,
+    pub signature: String,
+    pub node_id: String,
+    pub transactions: Vec<Transaction>,
+}
+
+// Everything in a block except its transactions, which the merkle root commits
+// to. Peers exchange headers to check a chain before downloading bodies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: u32,
     pub index: u64,
     pub timestamp: u128,
-    pub previous_hash: String,
-    pub hash: String,
+    pub previous_hash: BlockHash,
+    pub hash: BlockHash,
     pub nonce: u64,
     pub data: String,
+    pub merkle_root: BlockHash,
+    pub state_root: BlockHash,
     pub signature: String,
     pub node_id: String,
 }
 
+// On-disk layout of blocks before the state root was added.
+#[derive(Deserialize)]
+struct BlockWithoutStateRoot {
+    version: u32,
+    index: u64,
+    timestamp: u128,
+    previous_hash: BlockHash,
+    hash: BlockHash,
+    nonce: u64,
+    data: String,
+    merkle_root: BlockHash,
+    signature: String,
+    node_id: String,
+    transactions: Vec<Transaction>,
+}
+
+// On-disk layout of blocks before `version` and `BlockHash` were introduced.
+#[derive(Deserialize)]
+struct LegacyBlock {
+    index: u64,
+    timestamp: u128,
+    previous_hash: String,
+    hash: String,
+    nonce: u64,
+    data: String,
+    signature: String,
+    node_id: String,
+}
+
 impl Block {
-    pub fn new(index: u64, previous_hash: String, data: String, node_id: String) -> Self {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    pub fn new(index: u64, previous_hash: BlockHash, state_root: BlockHash, data: String, transactions: Vec<Transaction>, node_id: String) -> Self {
+        let timestamp = now_millis();
         let mut block = Block {
+            version: BLOCK_VERSION,
             index,
             timestamp,
             previous_hash,
-            hash: String::new(),
+            hash: BlockHash::ZERO,
             nonce: 0,
             data,
+            merkle_root: BlockHash::ZERO,
+            state_root,
             signature: String::new(),
             node_id,
+            transactions,
         };
+        block.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash();
         block
     }
 
-    pub fn calculate_hash(&self) -> String {
-        let data = format!("{}{}{}{}{}{}", self.index, self.timestamp, self.previous_hash, self.nonce, self.data, self.node_id);
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        format!("{:x}", hasher.finalize())
+    // Decodes a block stored in the pre-versioning format, keeping its original hash.
+    pub fn from_legacy_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let legacy: LegacyBlock = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+        let previous_hash = if legacy.previous_hash == "0" {
+            BlockHash::ZERO
+        } else {
+            BlockHash::from_hex(&legacy.previous_hash)?
+        };
+        Ok(Block {
+            version: LEGACY_BLOCK_VERSION,
+            index: legacy.index,
+            timestamp: legacy.timestamp,
+            previous_hash,
+            hash: BlockHash::from_hex(&legacy.hash)?,
+            nonce: legacy.nonce,
+            data: legacy.data,
+            merkle_root: BlockHash::ZERO,
+            state_root: BlockHash::ZERO,
+            signature: legacy.signature,
+            node_id: legacy.node_id,
+            transactions: Vec::new(),
+        })
+    }
+
+    // Decodes a block stored before the state root was added.
+    pub fn from_bytes_without_state_root(bytes: &[u8]) -> Result<Self, String> {
+        let old: BlockWithoutStateRoot = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+        Ok(Block {
+            version: old.version,
+            index: old.index,
+            timestamp: old.timestamp,
+            previous_hash: old.previous_hash,
+            hash: old.hash,
+            nonce: old.nonce,
+            data: old.data,
+            merkle_root: old.merkle_root,
+            state_root: BlockHash::ZERO,
+            signature: old.signature,
+            node_id: old.node_id,
+            transactions: old.transactions,
+        })
+    }
+
+    // A block restored from a snapshot, of which only the header is known.
+    pub fn from_header(header: BlockHeader) -> Self {
+        Block {
+            version: header.version,
+            index: header.index,
+            timestamp: header.timestamp,
+            previous_hash: header.previous_hash,
+            hash: header.hash,
+            nonce: header.nonce,
+            data: header.data,
+            merkle_root: header.merkle_root,
+            state_root: header.state_root,
+            signature: header.signature,
+            node_id: header.node_id,
+            transactions: Vec::new(),
+        }
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            index: self.index,
+            timestamp: self.timestamp,
+            previous_hash: self.previous_hash,
+            hash: self.hash,
+            nonce: self.nonce,
+            data: self.data.clone(),
+            merkle_root: self.merkle_root,
+            state_root: self.state_root,
+            signature: self.signature.clone(),
+            node_id: self.node_id.clone(),
+        }
+    }
+
+    pub fn transaction_hashes(&self) -> Vec<BlockHash> {
+        self.transactions.iter().map(Transaction::hash).collect()
+    }
+
+    pub fn calculate_merkle_root(&self) -> BlockHash {
+        merkle_root(&self.transaction_hashes())
+    }
+
+    pub fn transaction_proof(&self, tx_index: usize) -> Option<MerkleProof> {
+        merkle_proof(&self.transaction_hashes(), tx_index)
+    }
+
+    pub fn calculate_hash(&self) -> BlockHash {
+        self.header().calculate_hash()
     }
 
     pub fn sign_block(&mut self, secret_key: &SecretKey) {
@@ -48,12 +276,52 @@ impl Block {
     }
 
     fn calculate_hash_bytes(&self) -> Message {
-        let data = format!("{}{}{}{}{}{}", self.index, self.timestamp, self.previous_hash, self.nonce, self.data, self.node_id);
+        let hash = self.calculate_hash();
+        Message::from_slice(hash.as_bytes()).expect("Hash should be 32 bytes")
+    }
+}
+
+impl BlockHeader {
+    // Canonical header encoding: fixed-width integers are big-endian, variable
+    // length fields are prefixed with their length as a u32. The state root
+    // is only part of version 2 headers.
+    pub fn header_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128 + self.data.len() + self.node_id.len());
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.index.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(self.previous_hash.as_bytes());
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        write_bytes(&mut buf, self.data.as_bytes());
+        buf.extend_from_slice(self.merkle_root.as_bytes());
+        if self.version >= STATE_ROOT_BLOCK_VERSION {
+            buf.extend_from_slice(self.state_root.as_bytes());
+        }
+        write_bytes(&mut buf, self.node_id.as_bytes());
+        buf
+    }
+
+    pub fn calculate_hash(&self) -> BlockHash {
         let mut hasher = Sha256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
-        let hash_bytes = result.as_slice();
-        println!("Hash bytes: {:?}", hash_bytes);
-        Message::from_slice(hash_bytes).expect("Hash should be 32 bytes")
+        if self.version == LEGACY_BLOCK_VERSION {
+            hasher.update(self.legacy_preimage());
+        } else {
+            hasher.update(self.header_bytes());
+        }
+        BlockHash(hasher.finalize().into())
+    }
+
+    fn legacy_preimage(&self) -> String {
+        let previous_hash = if self.previous_hash.is_zero() {
+            "0".to_string()
+        } else {
+            self.previous_hash.to_hex()
+        };
+        format!("{}{}{}{}{}{}", self.index, self.timestamp, previous_hash, self.nonce, self.data, self.node_id)
     }
 }
+
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
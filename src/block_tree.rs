@@ -0,0 +1,109 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use crate::block::{Block, BlockHash};
+
+// Every validated block, including those on competing branches, keyed by hash.
+// The weight of a block is the number of authority-signed blocks from genesis
+// up to and including it.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    blocks: HashMap<BlockHash, Block>,
+    children: HashMap<BlockHash, Vec<BlockHash>>,
+    weights: HashMap<BlockHash, u64>,
+    genesis: Option<BlockHash>,
+}
+
+impl BlockTree {
+    pub fn new(genesis: Block) -> Self {
+        let mut tree = BlockTree::default();
+        let hash = genesis.hash;
+        tree.weights.insert(hash, 0);
+        tree.blocks.insert(hash, genesis);
+        tree.genesis = Some(hash);
+        tree
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn weight(&self, hash: &BlockHash) -> u64 {
+        self.weights.get(hash).copied().unwrap_or(0)
+    }
+
+    // The caller is responsible for validating the block against its parent.
+    pub fn insert(&mut self, block: Block) -> Result<(), String> {
+        if self.blocks.contains_key(&block.hash) {
+            return Ok(());
+        }
+        let parent_weight = *self.weights.get(&block.previous_hash)
+            .ok_or_else(|| format!("Block {}: parent {} is unknown", block.index, block.previous_hash))?;
+        self.children.entry(block.previous_hash).or_default().push(block.hash);
+        self.weights.insert(block.hash, parent_weight + 1);
+        self.blocks.insert(block.hash, block);
+        Ok(())
+    }
+
+    // Fork choice: heaviest branch, ties broken by the lower block hash.
+    pub fn best_tip(&self) -> Option<BlockHash> {
+        self.blocks.keys()
+            .filter(|hash| !self.children.contains_key(*hash))
+            .max_by_key(|hash| (self.weight(hash), Reverse(**hash)))
+            .copied()
+    }
+
+    // Blocks from genesis up to and including `hash`.
+    pub fn branch(&self, hash: &BlockHash) -> Vec<Block> {
+        let mut branch = Vec::new();
+        let mut current = self.blocks.get(hash);
+        while let Some(block) = current {
+            branch.push(block.clone());
+            if Some(block.hash) == self.genesis {
+                break;
+            }
+            current = self.blocks.get(&block.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    pub fn is_ancestor(&self, ancestor: &BlockHash, descendant: &BlockHash) -> bool {
+        let mut current = self.blocks.get(descendant);
+        while let Some(block) = current {
+            if block.hash == *ancestor {
+                return true;
+            }
+            if Some(block.hash) == self.genesis {
+                break;
+            }
+            current = self.blocks.get(&block.previous_hash);
+        }
+        false
+    }
+
+    // Drops every block that neither leads to nor descends from the finalized block.
+    pub fn prune(&mut self, finalized: &BlockHash) {
+        if !self.blocks.contains_key(finalized) {
+            return;
+        }
+        let mut keep: HashSet<BlockHash> = self.branch(finalized).iter().map(|block| block.hash).collect();
+        let mut stack = vec![*finalized];
+        while let Some(hash) = stack.pop() {
+            keep.insert(hash);
+            if let Some(children) = self.children.get(&hash) {
+                stack.extend(children.iter().copied());
+            }
+        }
+        self.blocks.retain(|hash, _| keep.contains(hash));
+        self.weights.retain(|hash, _| keep.contains(hash));
+        self.children.retain(|hash, _| keep.contains(hash));
+        for children in self.children.values_mut() {
+            children.retain(|hash| keep.contains(hash));
+        }
+        self.children.retain(|_, children| !children.is_empty());
+    }
+}
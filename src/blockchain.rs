@@ -1,158 +1,815 @@
-use crate::block::Block;
+use crate::block::{Block, BlockHash, BlockHeader, STATE_ROOT_BLOCK_VERSION};
 use crate::node::Node;
 //use crate::network::synchronize_with_peers;
 //use std::str::FromStr;
 //use std::sync::Arc;
 //use tokio::sync::Mutex;
 use secp256k1::{Secp256k1, SecretKey, ecdsa::Signature, Message};
-use sled::Db;
+use std::sync::Arc;
 use serde::{Serialize};
-use bincode;
-//use std::collections::HashMap;
+use std::collections::HashMap;
+use std::fmt;
 use hex::decode;
 use log;
 use secp256k1::PublicKey;
-use crate::smart_contract::ContractManager;
+use crate::smart_contract::{ContractAction, ContractManager, ContractRequest};
 use crate::resource_manager::ResourceManager;
+use crate::transaction::{Receipt, Transaction, TransactionPayload, TxHash};
+use crate::mempool::Mempool;
+use crate::poa::{PoA, now_millis};
+use crate::authority::{AuthoritySchedule, AuthorityProposal};
+use crate::finality::{FinalityGadget, Justification, Precommit};
+use crate::block_tree::BlockTree;
+use crate::storage::{ChainStore, WriteBatch};
+#[cfg(test)]
+use crate::storage::MemoryStore;
+#[cfg(test)]
+use crate::snapshot::DEFAULT_SNAPSHOT_INTERVAL;
+use crate::smart_contract::GPUResourceContract;
+use crate::snapshot::{self, SerializedSnapshot, SnapshotManifest, StateSnapshot, SNAPSHOTS_KEPT};
+use crate::public_key_serde::SerializablePublicKey;
+use crate::wasm::{CallContext, MAX_CALL_GAS};
 
+pub const MAX_BLOCK_TRANSACTIONS: usize = 500;
+pub const DEFAULT_CHAIN_ID: &str = "cognichain";
+// Most gas the transactions of one block may ask for in total.
+pub const MAX_BLOCK_GAS: u64 = 100_000_000;
+
+#[derive(Debug)]
+pub struct LoadError {
+    // Height of the block where loading stopped, if the problem is block-specific.
+    pub height: Option<u64>,
+    pub message: String,
+}
+
+impl LoadError {
+    fn new(height: Option<u64>, message: String) -> Self {
+        LoadError { height, message }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.height {
+            Some(height) => write!(f, "corrupt chain at height {}: {}", height, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
 
 #[derive(Serialize, Debug, Clone)]
 pub struct Blockchain {
     pub blocks: Vec<Block>,
+    // Authority set for the next block; see `authority_schedule` for history.
     pub authorities: Vec<Node>,
     #[serde(skip)]
-    pub db: Option<Db>,
+    pub authority_schedule: AuthoritySchedule,
+    #[serde(skip)]
+    pub store: Arc<dyn ChainStore>,
+    // Contract transactions must name this chain.
+    #[serde(skip)]
+    pub chain_id: String,
     #[serde(skip)]
     pub contract_manager: ContractManager,
     #[serde(skip)]
     pub resource_manager: ResourceManager,
+    #[serde(skip)]
+    pub mempool: Mempool,
+    #[serde(skip)]
+    pub poa: PoA,
+    #[serde(skip)]
+    pub finality: FinalityGadget,
+    // All known blocks including competing branches; `blocks` is the best branch.
+    #[serde(skip)]
+    pub tree: BlockTree,
+    #[serde(skip)]
+    pub reorg_count: u64,
+    // Next unused transaction nonce per sender, derived from the blocks applied so far.
+    #[serde(skip)]
+    pub nonces: HashMap<PublicKey, u64>,
+    // A state snapshot is taken after every block at a multiple of this height.
+    #[serde(skip)]
+    pub snapshot_interval: u64,
+    // Canonical blocks up to this height came from a snapshot and are headers only.
+    #[serde(skip)]
+    pub snapshot_base: u64,
+    // Snapshots taken since the last commit.
+    #[serde(skip)]
+    pending_snapshots: Vec<StateSnapshot>,
+    // Receipts of the blocks applied since the last commit.
+    #[serde(skip)]
+    pending_receipts: Vec<(BlockHash, Vec<Receipt>)>,
+    // The snapshot last served to peers, kept encoded between chunk requests.
+    #[serde(skip)]
+    served_snapshot: Option<SerializedSnapshot>,
 }
 
 impl Blockchain {
-    // Constructor for an entirely new blockchain without any database initialization
+    // Constructor for an entirely new blockchain kept only in memory
+    #[cfg(test)]
     pub fn new_empty() -> Self {
+        let store: Arc<dyn ChainStore> = Arc::new(MemoryStore::new());
         Blockchain {
             blocks: vec![],
             authorities: vec![],
-            db: None,
-            contract_manager: ContractManager::new(None),  // Initialize ContractManager without DB
+            authority_schedule: AuthoritySchedule::default(),
+            store: store.clone(),
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            contract_manager: ContractManager::new(store),
             resource_manager: ResourceManager::new(),
+            mempool: Mempool::default(),
+            poa: PoA::default(),
+            finality: FinalityGadget::default(),
+            tree: BlockTree::default(),
+            reorg_count: 0,
+            nonces: HashMap::new(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            snapshot_base: 0,
+            pending_snapshots: Vec::new(),
+            pending_receipts: Vec::new(),
+            served_snapshot: None,
         }
     }
 
-    // Set the database after creating a new empty blockchain
-    pub fn set_db(&mut self, db: Db) {
-        self.db = Some(db.clone());
-        self.contract_manager = ContractManager::new(Some(db));  // Update ContractManager with DB
-    }
-
-    // Constructor for creating a new blockchain with an existing database
-    pub fn new(db: Db) -> Self {
+    // Constructor for creating a new blockchain from an existing store. Every
+    // stored block is checked and applied, starting from the newest snapshot
+    // on the chain, so contract, resource, nonce and authority state match
+    // the chain once this returns.
+    pub fn new(store: Arc<dyn ChainStore>, chain_id: String, poa: PoA, snapshot_interval: u64) -> Result<Self, LoadError> {
         let mut blockchain = Blockchain {
             blocks: vec![],
             authorities: vec![],
-            db: Some(db.clone()),
-            contract_manager: ContractManager::new(Some(db)),  // Initialize ContractManager with DB
+            authority_schedule: AuthoritySchedule::default(),
+            store: store.clone(),
+            chain_id,
+            contract_manager: ContractManager::new(store),
             resource_manager: ResourceManager::new(),
+            mempool: Mempool::default(),
+            poa,
+            finality: FinalityGadget::default(),
+            tree: BlockTree::default(),
+            reorg_count: 0,
+            nonces: HashMap::new(),
+            snapshot_interval,
+            snapshot_base: 0,
+            pending_snapshots: Vec::new(),
+            pending_receipts: Vec::new(),
+            served_snapshot: None,
         };
 
-        // Load blocks from the database
         println!("Loading blockchain from database...");
-        if let Ok(block_bytes) = blockchain.db.as_ref().unwrap().get("0".as_bytes()) {
-            if let Some(bytes) = block_bytes {
-                println!("Found genesis block in database, deserializing...");
-                let genesis_block: Block = bincode::deserialize(&bytes).unwrap();
-                blockchain.blocks.push(genesis_block);
-            } else {
-                println!("Genesis block not found in database, creating new genesis block...");
-                let genesis_block = Block::new(0, String::from("0"), String::from("Genesis Block"), String::from("genesis"));
-                blockchain.db.as_ref().unwrap().insert("0".as_bytes(), bincode::serialize(&genesis_block).unwrap()).unwrap();
-                blockchain.blocks.push(genesis_block);
-            }
-        }
-
-        // Load authorities from the database
-        if let Ok(authorities_bytes) = blockchain.db.as_ref().unwrap().get("authorities".as_bytes()) {
-            if let Some(bytes) = authorities_bytes {
-                println!("Found authorities in database, deserializing...");
-                blockchain.authorities = bincode::deserialize(&bytes).unwrap();
-            } else {
-                println!("Authorities not found in database, initializing empty list...");
-            }
-        }
-        
-        blockchain.load_data_from_db();
-        
-        blockchain
+        blockchain.load_data_from_db()?;
+        println!("Loaded {} blocks from database", blockchain.blocks.len());
+        Ok(blockchain)
     }
-    
-    // Example function to load data from database, to be implemented as needed
-    fn load_data_from_db(&mut self) {
-    // Load blocks, authorities, etc.
-    // This function would include code to load existing blocks and possibly contracts
+
+    fn load_data_from_db(&mut self) -> Result<(), LoadError> {
+        let store = self.store.clone();
+
+        // The stored schedule only seeds the genesis set; later sets are
+        // rebuilt from the votes in the blocks below.
+        match store.authority_schedule().map_err(|e| LoadError::new(None, e))? {
+            Some(schedule) => self.authority_schedule = schedule.genesis(),
+            None => println!("Authorities not found in database, initializing empty list..."),
+        }
+
+        // Contracts as the last commit left them, to compare with the replay below.
+        let stored_contracts = store.contracts().map_err(|e| LoadError::new(None, e))?;
+
+        // Only blocks up to the head record were committed completely.
+        let head = store.head().map_err(|e| LoadError::new(None, e))?;
+        let block_count = head.map_or(0, |(height, _)| height + 1);
+
+        // State is restored from the newest snapshot of a stored block; only
+        // the blocks after it are applied.
+        self.snapshot_base = store.snapshot_base().map_err(|e| LoadError::new(None, e))?;
+        let snapshot = self.stored_snapshot(block_count).map_err(|e| LoadError::new(None, e))?;
+        if snapshot.is_none() && self.snapshot_base > 0 {
+            return Err(LoadError::new(Some(self.snapshot_base), "No snapshot to restore the state from".to_string()));
+        }
+        let replay_from = snapshot.as_ref().map_or(0, |snapshot| snapshot.height);
+
+        for height in 0..block_count {
+            let block = store.block_at(height)
+                .map_err(|e| LoadError::new(Some(height), e))?
+                .ok_or_else(|| LoadError::new(Some(height), "Block below the head is missing".to_string()))?;
+            if block.index != height {
+                return Err(LoadError::new(Some(height), format!("Stored under height {} but has index {}", height, block.index)));
+            }
+            match self.blocks.last() {
+                None => {
+                    if block.hash != block.calculate_hash() {
+                        return Err(LoadError::new(Some(height), "Invalid genesis hash".to_string()));
+                    }
+                }
+                Some(parent) if height <= replay_from => {
+                    check_linkage(&block.header(), &parent.header()).map_err(|e| LoadError::new(Some(height), e))?
+                }
+                Some(parent) => {
+                    self.check_block(&block, parent)
+                        .and_then(|_| self.check_state_root(&block))
+                        .map_err(|e| LoadError::new(Some(height), e))?
+                }
+            }
+            self.blocks.push(block.clone());
+            match &snapshot {
+                Some(snapshot) if height == replay_from => self.restore_snapshot(snapshot),
+                _ if height > replay_from => self.apply_block_transactions(&block),
+                _ => {}
+            }
+        }
+        if let Some((height, hash)) = head {
+            if self.blocks.last().map(|block| block.hash) != Some(hash) {
+                return Err(LoadError::new(Some(height), format!("Head {} does not match the stored block", hash)));
+            }
+        }
+        self.refresh_authorities();
+        self.rebuild_tree();
+
+        // Blocks above the head belong to a commit that never finished.
+        let partial = store.block_at(block_count).map_err(|e| LoadError::new(Some(block_count), e))?.is_some();
+        if partial {
+            log::warn!("Discarding partially committed blocks from height {}", block_count);
+        }
+        // Rewriting the replayed state also repairs anything a partial commit
+        // left behind. Contracts already stored as replayed are left alone.
+        self.contract_manager.keep_stored(&stored_contracts);
+        self.commit_blocks(&[], partial.then_some(block_count)).map_err(|e| LoadError::new(None, e))?;
+
+        if let Some((height, hash)) = store.finalized().map_err(|e| LoadError::new(None, e))? {
+            match self.blocks.get(height as usize) {
+                Some(block) if block.hash == hash => {}
+                _ => return Err(LoadError::new(Some(height), format!("Finalized block {} is missing from the chain", hash))),
+            }
+            println!("Latest finalized block: {} at height {}", hash, height);
+            self.finality.set_finalized(height, hash);
+            self.tree.prune(&hash);
+        }
+        Ok(())
     }
 
-    pub fn add_block(&mut self, data: String, node_id: String, secret_key: &SecretKey) -> Result<Block, &'static str> {
+    pub fn add_block(&mut self, data: String, transactions: Vec<Transaction>, node_id: String, secret_key: &SecretKey) -> Result<Block, String> {
+        if transactions.iter().any(|tx| tx.verify_signature().is_err()) {
+            return Err("Block contains a transaction with an invalid signature".to_string());
+        }
+        if !self.nonces_in_order(&transactions) {
+            return Err("Block contains a transaction with an out-of-order nonce".to_string());
+        }
         if self.is_authority(&node_id) {
             let previous_block = &self.blocks[self.blocks.len() - 1];
-            let mut new_block = Block::new(previous_block.index + 1, previous_block.hash.clone(), data, node_id.clone());
+            let mut new_block = Block::new(previous_block.index + 1, previous_block.hash, self.state_root(), data, transactions, node_id.clone());
+            self.poa.validate_block(self.authorities_at(new_block.index), &new_block.header(), &previous_block.header(), now_millis())?;
             new_block.sign_block(secret_key);
-            self.db.as_ref().unwrap().insert(new_block.index.to_string().as_bytes(), bincode::serialize(&new_block).unwrap()).unwrap();
-            self.blocks.push(new_block.clone());
+            self.import_block(new_block.clone())?;
             println!("New block added and saved to database: {:?}", new_block);
             Ok(new_block)
         } else {
-            Err("Node is not an authority")
+            Err("Node is not an authority".to_string())
+        }
+    }
+
+    // Validates a block from any source, adds it to the block tree and moves the
+    // canonical chain to the best tip, reorganising if another branch wins.
+    pub fn import_block(&mut self, block: Block) -> Result<(), String> {
+        if self.tree.contains(&block.hash) {
+            return Ok(());
+        }
+        let parent = self.tree.get(&block.previous_hash).cloned()
+            .ok_or_else(|| format!("Block {}: parent {} is unknown", block.index, block.previous_hash))?;
+        self.check_block(&block, &parent)?;
+        // Blocks on other branches are checked against their state when replayed.
+        if Some(block.previous_hash) == self.blocks.last().map(|tip| tip.hash) {
+            self.check_state_root(&block)?;
+        }
+
+        let finalized_height = self.finality.finalized_height;
+        if finalized_height > 0
+            && (block.index <= finalized_height || !self.tree.is_ancestor(&self.finality.finalized_hash, &parent.hash)) {
+            return Err(format!("Block {}: does not build on the finalized block at height {}", block.index, finalized_height));
+        }
+
+        self.tree.insert(block)?;
+        self.update_best_chain()
+    }
+
+    fn update_best_chain(&mut self) -> Result<(), String> {
+        let best = match self.tree.best_tip() {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        let current = self.blocks.last().map(|block| block.hash);
+        if Some(best) == current {
+            return Ok(());
+        }
+
+        let best_block = self.tree.get(&best).cloned().expect("Best tip is in the tree");
+        if Some(best_block.previous_hash) == current {
+            self.blocks.push(best_block.clone());
+            self.apply_block_transactions(&best_block);
+            return self.commit_blocks(&[best_block], None);
+        }
+        self.reorganize(best)
+    }
+
+    fn reorganize(&mut self, new_tip: BlockHash) -> Result<(), String> {
+        let new_chain = self.tree.branch(&new_tip);
+        if !self.keeps_finalized(&new_chain) {
+            return Err(format!("Refusing to reorganise to {}: it does not contain the finalized block", new_tip));
+        }
+        let old_chain = std::mem::take(&mut self.blocks);
+        let common = old_chain.iter().zip(new_chain.iter()).take_while(|(old, new)| old.hash == new.hash).count();
+
+        self.reorg_count += 1;
+        log::warn!(
+            "Reorg #{}: rolling back {} blocks above height {}, new tip {} at height {}",
+            self.reorg_count,
+            old_chain.len() - common,
+            common.saturating_sub(1),
+            new_tip,
+            new_chain.len() - 1
+        );
+
+        // Replays add receipts and snapshots; a failed reorg keeps only those
+        // that were pending before it.
+        let pending = (self.pending_snapshots.len(), self.pending_receipts.len());
+        if let Err(e) = self.replay_chain(&new_chain) {
+            self.blocks = old_chain.clone();
+            let restored = self.replay_chain(&old_chain);
+            self.pending_snapshots.truncate(pending.0);
+            self.pending_receipts.truncate(pending.1);
+            if let Err(restore) = restored {
+                log::error!("Failed to restore the previous chain: {}", restore);
+                return Err(format!("Reorganising to {} failed ({}) and the previous chain could not be restored: {}", new_tip, e, restore));
+            }
+            return Err(format!("Refusing to reorganise to {}: {}", new_tip, e));
+        }
+        let truncate_from = (new_chain.len() < old_chain.len()).then_some(new_chain.len() as u64);
+        self.commit_blocks(&new_chain[common..], truncate_from)?;
+
+        // Transactions that only made it into the abandoned branch go back to the mempool.
+        for block in &old_chain[common..] {
+            for tx in &block.transactions {
+                let _ = self.submit_transaction(tx.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // Rebuilds nonces, contract, resource and authority state by applying
+    // `chain` from its newest stored snapshot, or from genesis, then makes it
+    // the canonical chain. Fails on a block whose state root does not match.
+    fn replay_chain(&mut self, chain: &[Block]) -> Result<(), String> {
+        let snapshot = self.stored_snapshot(chain.len() as u64)?
+            .filter(|snapshot| chain[snapshot.height as usize].hash == snapshot.block_hash);
+        let base = match snapshot {
+            Some(snapshot) => {
+                self.restore_snapshot(&snapshot);
+                snapshot.height as usize
+            }
+            None if self.snapshot_base > 0 => return Err("No snapshot to rebuild the state from".to_string()),
+            None => {
+                self.contract_manager.clear();
+                self.resource_manager.clear();
+                self.nonces.clear();
+                self.authority_schedule = self.authority_schedule.genesis();
+                0
+            }
+        };
+        self.blocks = chain[..=base].to_vec();
+        for block in &chain[base + 1..] {
+            self.check_state_root(block)?;
+            self.blocks.push(block.clone());
+            self.apply_block_transactions(block);
+        }
+        self.refresh_authorities();
+        Ok(())
+    }
+
+    // Root of the state after our tip, which the next block commits to.
+    pub fn state_root(&self) -> BlockHash {
+        snapshot::state_root(
+            self.contract_manager.contracts.iter(),
+            self.resource_manager.registrations(),
+            &self.authority_schedule,
+            self.nonces.iter(),
+        )
+    }
+
+    fn check_state_root(&self, block: &Block) -> Result<(), String> {
+        if block.version >= STATE_ROOT_BLOCK_VERSION && block.state_root != self.state_root() {
+            return Err(format!("Block {}: State root does not match", block.index));
+        }
+        Ok(())
+    }
+
+    fn take_snapshot(&self, block: &Block) -> StateSnapshot {
+        StateSnapshot {
+            height: block.index,
+            block_hash: block.hash,
+            contracts: self.contract_manager.contracts.iter().map(|(id, c)| (id.clone(), c.clone())).collect(),
+            resources: self.resource_manager.registrations().map(|(id, r)| (id.clone(), r.clone())).collect(),
+            authority_schedule: self.authority_schedule.clone(),
+            nonces: self.nonces.iter().map(|(key, nonce)| (SerializablePublicKey(*key), *nonce)).collect(),
+        }
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &StateSnapshot) {
+        self.contract_manager.restore(snapshot.contracts.clone());
+        self.resource_manager.restore(snapshot.resources.clone());
+        self.authority_schedule = snapshot.authority_schedule.clone();
+        self.nonces = snapshot.nonces.iter().map(|(key, nonce)| (key.0, *nonce)).collect();
+    }
+
+    // The newest stored snapshot of a canonical block below `height`.
+    fn stored_snapshot(&self, height: u64) -> Result<Option<StateSnapshot>, String> {
+        for snapshot_height in self.store.snapshot_heights()?.into_iter().rev().filter(|h| *h < height) {
+            let snapshot = match self.store.snapshot(snapshot_height)? {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            let canonical = match self.blocks.get(snapshot_height as usize) {
+                Some(block) => Some(block.hash),
+                None => self.store.block_at(snapshot_height)?.map(|block| block.hash),
+            };
+            if canonical == Some(snapshot.block_hash) {
+                return Ok(Some(snapshot));
+            }
+        }
+        Ok(None)
+    }
+
+    // A snapshot we can offer peers: on our chain, and with a later block
+    // committing to it so they can verify it. It is encoded once and reused
+    // while peers fetch its chunks.
+    fn servable_snapshot(&mut self, height: u64) -> Option<&SerializedSnapshot> {
+        let next = self.blocks.get(height as usize + 1)?;
+        if next.version < STATE_ROOT_BLOCK_VERSION {
+            return None;
+        }
+        let block_hash = self.blocks[height as usize].hash;
+        let cached = self.served_snapshot.as_ref()
+            .is_some_and(|served| served.height == height && served.block_hash == block_hash);
+        if !cached {
+            let snapshot = self.store.snapshot(height).ok()??;
+            if snapshot.block_hash != block_hash {
+                return None;
+            }
+            self.served_snapshot = Some(SerializedSnapshot::new(&snapshot).ok()?);
+        }
+        self.served_snapshot.as_ref()
+    }
+
+    pub fn snapshot_manifest(&mut self) -> Option<SnapshotManifest> {
+        let heights = self.store.snapshot_heights().ok()?;
+        let height = heights.into_iter().rev().find(|height| self.servable_snapshot(*height).is_some())?;
+        self.servable_snapshot(height).map(SerializedSnapshot::manifest)
+    }
+
+    pub fn snapshot_chunk(&mut self, height: u64, index: u32) -> Option<Vec<u8>> {
+        self.servable_snapshot(height)?.chunk(index)
+    }
+
+    // Checks a snapshot fetched from a peer. `headers` continue our chain up
+    // to the block after the snapshot, whose state root must match it. Their
+    // signatures are checked against the snapshot's own authority schedule,
+    // so the snapshot is only as trustworthy as the genesis authorities and
+    // the ones they voted in.
+    pub fn verify_snapshot(&self, snapshot: &StateSnapshot, headers: &[BlockHeader]) -> Result<(), String> {
+        if snapshot.authority_schedule.authorities_at(0) != self.authorities_at(0) {
+            return Err("Snapshot has a different genesis authority set".to_string());
+        }
+        let mut parent = self.blocks.last().ok_or("No genesis block")?.header();
+        for header in headers {
+            self.check_header_with(snapshot.authority_schedule.authorities_at(header.index), header, &parent)?;
+            parent = header.clone();
+        }
+        let (block, next) = match headers {
+            [.., block, next] => (block, next),
+            _ => return Err("Snapshot is not ahead of our chain".to_string()),
+        };
+        if block.index != snapshot.height || block.hash != snapshot.block_hash {
+            return Err(format!("Snapshot does not match block {}", block.index));
+        }
+        if next.version < STATE_ROOT_BLOCK_VERSION || next.state_root != snapshot.state_root() {
+            return Err(format!("Snapshot does not match the state root of block {}", next.index));
+        }
+        Ok(())
+    }
+
+    // Makes a verified snapshot the base of our chain: the blocks up to it are
+    // kept as headers only, and the state is the snapshot's.
+    pub fn install_snapshot(&mut self, snapshot: StateSnapshot, headers: Vec<BlockHeader>) -> Result<(), String> {
+        let blocks: Vec<Block> = headers.into_iter().map(Block::from_header).collect();
+        self.blocks.extend(blocks.iter().cloned());
+        self.restore_snapshot(&snapshot);
+        self.refresh_authorities();
+        self.rebuild_tree();
+        self.snapshot_base = snapshot.height;
+        self.pending_snapshots.push(snapshot);
+        self.commit_blocks(&blocks, None)
+    }
+
+    fn rebuild_tree(&mut self) {
+        if let Some(genesis) = self.blocks.first() {
+            let mut tree = BlockTree::new(genesis.clone());
+            for block in &self.blocks[1..] {
+                if let Err(e) = tree.insert(block.clone()) {
+                    log::error!("Failed to rebuild block tree: {}", e);
+                    break;
+                }
+            }
+            self.tree = tree;
+        }
+    }
+
+    // Writes `blocks` and the contract, resource and authority changes they
+    // caused as one atomic batch, optionally dropping stored blocks from
+    // `truncate_from` upwards first. The head record is written last.
+    fn commit_blocks(&mut self, blocks: &[Block], truncate_from: Option<u64>) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        if let Some(height) = truncate_from {
+            batch.truncate_blocks(height);
+        }
+        for block in blocks {
+            batch.put_block(block.clone());
+        }
+        for (block_hash, receipts) in std::mem::take(&mut self.pending_receipts) {
+            batch.put_receipts(block_hash, receipts);
+        }
+        batch.put_authority_schedule(self.authority_schedule.clone());
+        self.contract_manager.take_changes(&mut batch);
+        self.resource_manager.take_changes(&mut batch);
+        // Only the newest snapshots are kept.
+        if !self.pending_snapshots.is_empty() {
+            let mut heights = self.store.snapshot_heights()?;
+            for snapshot in std::mem::take(&mut self.pending_snapshots) {
+                log::info!("Took state snapshot at height {}", snapshot.height);
+                heights.push(snapshot.height);
+                batch.put_snapshot(snapshot);
+            }
+            heights.sort_unstable();
+            heights.dedup();
+            for height in heights.iter().rev().skip(SNAPSHOTS_KEPT) {
+                batch.remove_snapshot(*height);
+            }
+        }
+        if self.snapshot_base > 0 {
+            batch.set_snapshot_base(self.snapshot_base);
+        }
+        if let Some(tip) = self.blocks.last() {
+            batch.set_head(tip.index, tip.hash);
+        }
+        self.store.write(batch).map_err(|e| format!("Failed to commit blocks: {}", e))
+    }
+
+    // Whether `node_id` is the scheduled proposer for the next block.
+    pub fn is_turn(&self, node_id: &str) -> bool {
+        self.poa.is_turn(self.authorities_at(self.blocks.len() as u64), self.blocks.len() as u64, node_id)
+    }
+
+    pub fn next_nonce(&self, sender: &PublicKey) -> u64 {
+        self.nonces.get(sender).copied().unwrap_or(0)
+    }
+
+    // Nonce to use for a new transaction from `sender`, counting ones still in the mempool.
+    pub fn pending_nonce(&self, sender: &PublicKey) -> u64 {
+        self.mempool.next_nonce(sender, self.next_nonce(sender))
+    }
+
+    // Entry point for transactions from the API and from peers.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<TxHash, String> {
+        self.check_transaction(&tx)?;
+        let expected_nonce = self.next_nonce(&tx.sender.0);
+        let hash = self.mempool.insert(tx, expected_nonce)?;
+        log::info!("Accepted transaction {} into mempool ({} pending)", hash, self.mempool.len());
+        Ok(hash)
+    }
+
+    // Transactions a block producer should put in the next block.
+    pub fn pending_transactions(&self) -> Vec<Transaction> {
+        self.mempool.select(MAX_BLOCK_TRANSACTIONS, MAX_BLOCK_GAS, &self.nonces)
+    }
+
+    // Receipts of the transactions in a block we applied.
+    pub fn receipts(&self, block_hash: &BlockHash) -> Result<Option<Vec<Receipt>>, String> {
+        self.store.receipts(block_hash)
+    }
+
+    fn nonces_in_order(&self, transactions: &[Transaction]) -> bool {
+        let mut next_nonces: HashMap<PublicKey, u64> = HashMap::new();
+        transactions.iter().all(|tx| {
+            let next = next_nonces.entry(tx.sender.0).or_insert_with(|| self.next_nonce(&tx.sender.0));
+            let in_order = tx.nonce == *next;
+            *next += 1;
+            in_order
+        })
+    }
+
+    // Advances sender nonces, runs the payloads against contract and resource
+    // state, records a receipt for each transaction and drops the included
+    // ones from the mempool. A payload that fails still consumes its nonce; a
+    // transaction with the wrong nonce is skipped entirely.
+    fn apply_block_transactions(&mut self, block: &Block) {
+        let mut receipts = Vec::with_capacity(block.transactions.len());
+        for tx in &block.transactions {
+            let tx_hash = tx.hash();
+            let sender = tx.sender.0;
+            let expected_nonce = self.next_nonce(&sender);
+            if tx.nonce != expected_nonce {
+                log::warn!("Block {}: transaction {} skipped, expected nonce {} but got {}", block.index, tx_hash, expected_nonce, tx.nonce);
+                receipts.push(Receipt {
+                    tx_hash,
+                    success: false,
+                    gas_used: 0,
+                    output: format!("Skipped: expected nonce {}", expected_nonce),
+                    events: Vec::new(),
+                });
+                continue;
+            }
+            self.nonces.insert(sender, tx.nonce + 1);
+
+            let mut gas_used = 0;
+            let mut events = Vec::new();
+            let context = CallContext { caller: Some(sender), block_height: block.index };
+            let result = match &tx.payload {
+                TransactionPayload::Contract(ContractRequest { contract_id: id, action: ContractAction::Deploy { code, contract_type }, .. }) => self.contract_manager
+                    .deploy_contract(id.clone(), sender, code.clone(), contract_type.clone())
+                    .map(|_| format!("Contract {} deployed", id)),
+                TransactionPayload::Contract(ContractRequest { contract_id: id, action: ContractAction::Execute { input, .. }, .. }) => {
+                    let output = self.contract_manager.execute_contract(id, input, context, tx.gas_limit());
+                    gas_used = output.gas_used;
+                    events = output.events;
+                    output.result
+                }
+                TransactionPayload::GpuRegistration { node_id, gpu_type, vram_capacity, cuda_cores } => {
+                    let contract = GPUResourceContract::new(sender, gpu_type.clone(), *vram_capacity, *cuda_cores);
+                    self.resource_manager.register_gpu(node_id.clone(), contract);
+                    Ok(format!("GPU registered for {}", node_id))
+                }
+                TransactionPayload::AuthorityVote { node_id, public_key, add } => {
+                    let proposal = AuthorityProposal { node_id: node_id.clone(), public_key: public_key.clone(), add: *add };
+                    self.authority_schedule.apply_vote(block.index, &sender, proposal).map(|scheduled| match scheduled {
+                        Some(height) => format!("Authority change for {} takes effect at height {}", node_id, height),
+                        None => format!("Vote recorded for {}", node_id),
+                    })
+                }
+            };
+            match &result {
+                Ok(output) => log::debug!("Block {}: transaction {} applied: {}", block.index, tx_hash, output),
+                Err(e) => log::warn!("Block {}: transaction {} failed: {}", block.index, tx_hash, e),
+            }
+            receipts.push(Receipt {
+                tx_hash,
+                success: result.is_ok(),
+                gas_used,
+                output: result.unwrap_or_else(|e| e),
+                events,
+            });
+        }
+        self.pending_receipts.push((block.hash, receipts));
+        self.mempool.remove_included(&block.transactions, &self.nonces);
+        self.refresh_authorities();
+        if self.snapshot_interval > 0 && block.index.is_multiple_of(self.snapshot_interval) {
+            self.pending_snapshots.push(self.take_snapshot(block));
+        }
+    }
+
+    // Records a precommit from an authority and finalizes its block once more
+    // than two thirds of the set have signed. Returns whether it finalized a block.
+    pub fn add_precommit(&mut self, precommit: Precommit) -> Result<bool, String> {
+        let authorities = self.authorities_at(precommit.height).to_vec();
+        match self.finality.add_precommit(precommit, &authorities)? {
+            Some(justification) => self.finalize(justification).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn finalize(&mut self, justification: Justification) -> Result<(), String> {
+        let height = justification.height;
+        match self.blocks.get(height as usize) {
+            Some(block) if block.hash == justification.block_hash => {}
+            _ => return Err(format!("Block {} at height {} is justified but not on the local chain", justification.block_hash, height)),
         }
+        justification.verify(self.authorities_at(height))?;
+
+        let mut batch = WriteBatch::default();
+        batch.put_justification(justification.clone());
+        batch.put_finalized(height, justification.block_hash);
+        self.store.write(batch)?;
+        self.finality.set_finalized(height, justification.block_hash);
+        self.tree.prune(&justification.block_hash);
+        log::info!("Finalized block {} at height {}", justification.block_hash, height);
+        Ok(())
+    }
+
+    pub fn justification(&self, height: u64) -> Option<Justification> {
+        self.store.justification(height).ok()?
+    }
+
+    // A competing chain may only replace ours if it keeps our finalized block.
+    pub fn keeps_finalized(&self, blocks: &[Block]) -> bool {
+        let height = self.finality.finalized_height;
+        height == 0 || blocks.get(height as usize).map_or(false, |block| block.hash == self.finality.finalized_hash)
+    }
+
+    pub fn authorities_at(&self, height: u64) -> &[Node] {
+        self.authority_schedule.authorities_at(height)
     }
 
-    pub fn add_authority(&mut self, node: Node) {
-        println!("Adding authority node: {:?}", node);
-        self.authorities.push(node);
-        self.db.as_ref().unwrap().insert("authorities", bincode::serialize(&self.authorities).unwrap()).unwrap();
+    fn refresh_authorities(&mut self) {
+        self.authorities = self.authorities_at(self.blocks.len() as u64).to_vec();
     }
 
+    // Whether `node_id` may seal the next block.
     pub fn is_authority(&self, node_id: &str) -> bool {
-        let is_auth = self.authorities.iter().any(|node| node.id == node_id && node.is_authority);
+        let is_auth = PoA::is_authority(self.authorities_at(self.blocks.len() as u64), node_id);
         println!("Is node_id '{}' an authority? {}", node_id, is_auth);
         is_auth
     }
 
+    // Blocks restored from a snapshot have no bodies to check.
     pub fn is_valid(&self) -> bool {
-        for i in 1..self.blocks.len() {
-            let current_block = &self.blocks[i];
-            let previous_block = &self.blocks[i - 1];
-
-            if current_block.hash != current_block.calculate_hash() {
-                println!("Block {}: Invalid hash", current_block.index);
+        for i in (self.snapshot_base as usize + 1)..self.blocks.len() {
+            if let Err(e) = self.check_block(&self.blocks[i], &self.blocks[i - 1]) {
+                println!("{}", e);
                 return false;
             }
+        }
+        true
+    }
 
-            if current_block.previous_hash != previous_block.hash {
-                println!("Block {}: Previous hash does not match", current_block.index);
-                return false;
-            }
+    // Everything a header must satisfy relative to its parent, checkable
+    // before the block body is downloaded.
+    pub fn check_header(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<(), String> {
+        self.check_header_with(self.authorities_at(header.index), header, parent)
+    }
 
-            if !self.validate_block(current_block) {
-                println!("Block {}: Invalid block signature", current_block.index);
-                return false;
+    // `check_header` against a given authority set, e.g. one from a snapshot.
+    pub fn check_header_with(&self, authorities: &[Node], header: &BlockHeader, parent: &BlockHeader) -> Result<(), String> {
+        check_linkage(header, parent)?;
+        self.poa.validate_block(authorities, header, parent, now_millis())?;
+        if !Self::validate_block(authorities, header) {
+            return Err(format!("Block {}: Invalid block signature", header.index));
+        }
+        Ok(())
+    }
+
+    // Everything a block must satisfy relative to its parent.
+    pub fn check_block(&self, block: &Block, parent: &Block) -> Result<(), String> {
+        self.check_header(&block.header(), &parent.header())?;
+        if block.merkle_root != block.calculate_merkle_root() {
+            return Err(format!("Block {}: Merkle root does not match transactions", block.index));
+        }
+        if let Some((i, e)) = block.transactions.iter().enumerate()
+            .find_map(|(i, tx)| tx.verify_signature().and_then(|_| self.check_transaction(tx)).err().map(|e| (i, e))) {
+            return Err(format!("Block {}: Transaction {} invalid: {}", block.index, i, e));
+        }
+        let gas: u64 = block.transactions.iter().map(Transaction::gas_limit).sum();
+        if gas > MAX_BLOCK_GAS {
+            return Err(format!("Block {}: Transactions ask for {} gas, more than the {} allowed", block.index, gas, MAX_BLOCK_GAS));
+        }
+        Ok(())
+    }
+
+    // Rules a transaction must meet beyond its signature and nonce.
+    fn check_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        if tx.gas_limit() > MAX_CALL_GAS {
+            return Err(format!("Gas limit {} is above the {} a call may use", tx.gas_limit(), MAX_CALL_GAS));
+        }
+        if let TransactionPayload::Contract(request) = &tx.payload {
+            if request.chain_id != self.chain_id {
+                return Err(format!("Transaction is for chain {}, not {}", request.chain_id, self.chain_id));
             }
         }
-        true
+        Ok(())
     }
 
-    pub fn validate_block(&self, block: &Block) -> bool {
+    // The highest block whose authority set is fixed by the blocks we hold:
+    // votes in later blocks take effect at the next epoch at the earliest.
+    pub fn verifiable_height(&self) -> u64 {
+        AuthoritySchedule::next_epoch_start(self.blocks.len() as u64) - 1
+    }
+
+    pub fn validate_block(authorities: &[Node], block: &BlockHeader) -> bool {
         log::info!("Validating block: {:?}", block);
 
-        if let Some(authority) = self.authorities.iter().find(|node| node.id == block.node_id) {
+        if let Some(authority) = authorities.iter().find(|node| node.id == block.node_id) {
             log::debug!("Found authority for block {}: {:?}", block.index, authority);
 
             let secp = Secp256k1::new();
-            if let Ok(sig) = Signature::from_der(&decode(&block.signature).expect("Decoding hex should succeed")).map_err(|e| {
+            // Headers come from peers, so the signature may be anything.
+            let sig_bytes = match decode(&block.signature) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    log::error!("Block {}: Signature is not valid hex: {}", block.index, e);
+                    return false;
+                }
+            };
+            if let Ok(sig) = Signature::from_der(&sig_bytes).map_err(|e| {
                 log::error!("Block {}: Failed to parse signature: {}", block.index, e);
                 e
             }) {
-                let message = Message::from_slice(&decode(&block.hash).expect("Decoding hex should succeed")).expect("32 bytes");
+                let message = Message::from_slice(block.hash.as_bytes()).expect("32 bytes");
 
                 // Log signature and verification result:
                 log::debug!("Signature for verification: {:?}", sig);
@@ -172,33 +829,254 @@ impl Blockchain {
         false
     }
 
-    pub fn synchronize_from_peer(&mut self, peer_blockchain: Blockchain) {
-        for block in peer_blockchain.blocks {
-            if !self.blocks.contains(&block) {
-                self.blocks.push(block.clone());
-                self.db.as_ref().unwrap().insert(block.index.to_string().as_bytes(), bincode::serialize(&block).unwrap()).unwrap();
-            }
-        }
-        for authority in peer_blockchain.authorities {
-            if !self.authorities.contains(&authority) {
-                self.authorities.push(authority.clone());
-                self.db.as_ref().unwrap().insert("authorities", bincode::serialize(&self.authorities).unwrap()).unwrap();
-            }
-        }
-    }
-
     pub async fn initialize_genesis(&mut self, node_id: &str, secret_key: &SecretKey, public_key: PublicKey) {
+        let mut new_blocks = Vec::new();
         if self.blocks.is_empty() {
             println!("Creating genesis block...");
-            let genesis_block = Block::new(0, "0".to_string(), "Genesis Block".to_string(), node_id.to_string());
+            let genesis_block = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "Genesis Block".to_string(), Vec::new(), node_id.to_string());
             self.blocks.push(genesis_block.clone());
-            self.db.as_ref().unwrap().insert("0".as_bytes(), bincode::serialize(&genesis_block).unwrap()).unwrap();
+            new_blocks.push(genesis_block);
+            self.rebuild_tree();
         }
 
-        if !self.is_authority(node_id) {
-            println!("Adding authority node: {}", node_id);
+        // The genesis authority set is the only one not decided by votes.
+        let new_schedule = self.authority_schedule.is_empty();
+        if new_schedule {
+            println!("Adding genesis authority node: {}", node_id);
             let node = Node { id: node_id.to_string(), is_authority: true, public_key };
-            self.add_authority(node);
+            self.authority_schedule = AuthoritySchedule::new(vec![node]);
+            self.refresh_authorities();
+        }
+
+        if !new_blocks.is_empty() || new_schedule {
+            if let Err(e) = self.commit_blocks(&new_blocks, None) {
+                log::error!("Failed to store genesis: {}", e);
+            }
+        }
+    }
+}
+
+// Whether `header` extends `parent`; all that is checked for blocks that
+// came from a snapshot.
+fn check_linkage(header: &BlockHeader, parent: &BlockHeader) -> Result<(), String> {
+    if header.index != parent.index + 1 {
+        return Err(format!("Block {}: does not follow parent height {}", header.index, parent.index));
+    }
+    if header.hash != header.calculate_hash() {
+        return Err(format!("Block {}: Invalid hash", header.index));
+    }
+    if header.previous_hash != parent.hash {
+        return Err(format!("Block {}: Previous hash does not match", header.index));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smart_contract::ContractType;
+    use crate::snapshot::SNAPSHOT_CHUNK_SIZE;
+    use crate::storage::SledStore;
+
+    fn secret() -> SecretKey {
+        SecretKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    // A chain with one authority, `node1`, and one-millisecond slots so
+    // blocks can be built back to back. Snapshots are taken every other block.
+    async fn chain(store: Arc<dyn ChainStore>) -> Blockchain {
+        let mut blockchain = Blockchain::new(store, DEFAULT_CHAIN_ID.to_string(), PoA::new(1), 2).unwrap();
+        blockchain.initialize_genesis("node1", &secret(), PublicKey::from_secret_key(&Secp256k1::new(), &secret())).await;
+        blockchain
+    }
+
+    fn block_on(parent: &Block, state_root: BlockHash, data: &str, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(parent.index + 1, parent.hash, state_root, data.to_string(), transactions, "node1".to_string());
+        block.timestamp = parent.timestamp + 1;
+        block.hash = block.calculate_hash();
+        block.sign_block(&secret());
+        block
+    }
+
+    fn signed(nonce: u64, payload: TransactionPayload) -> Transaction {
+        let mut tx = Transaction::new(PublicKey::from_secret_key(&Secp256k1::new(), &secret()), nonce, 0, payload);
+        tx.sign(&secret());
+        tx
+    }
+
+    fn gpu_registration(nonce: u64) -> Transaction {
+        signed(nonce, TransactionPayload::GpuRegistration {
+            node_id: "node1".to_string(),
+            gpu_type: "A100".to_string(),
+            vram_capacity: 80.0,
+            cuda_cores: 6912,
+        })
+    }
+
+    fn contract(nonce: u64, chain_id: &str, id: &str, action: ContractAction) -> Transaction {
+        signed(nonce, TransactionPayload::Contract(ContractRequest {
+            chain_id: chain_id.to_string(),
+            contract_id: id.to_string(),
+            action,
+        }))
+    }
+
+    fn deploy(nonce: u64, id: &str) -> Transaction {
+        contract(nonce, DEFAULT_CHAIN_ID, id, ContractAction::Deploy {
+            code: Vec::new(),
+            contract_type: ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 },
+        })
+    }
+
+    fn call(nonce: u64, id: &str, input: &str) -> Transaction {
+        contract(nonce, DEFAULT_CHAIN_ID, id, ContractAction::Execute { input: input.to_string(), gas_limit: 1_000_000 })
+    }
+
+    // Extends the tip with a valid block holding `transactions`.
+    fn extend(blockchain: &mut Blockchain, transactions: Vec<Transaction>) -> Block {
+        let parent = blockchain.blocks.last().unwrap().clone();
+        let block = block_on(&parent, blockchain.state_root(), "main", transactions);
+        blockchain.import_block(block.clone()).unwrap();
+        block
+    }
+
+    fn hashes(blockchain: &Blockchain) -> Vec<BlockHash> {
+        blockchain.blocks.iter().map(|block| block.hash).collect()
+    }
+
+    #[tokio::test]
+    async fn failed_reorg_keeps_the_current_chain_and_state() {
+        let mut blockchain = chain(Arc::new(MemoryStore::new())).await;
+        let genesis = blockchain.blocks[0].clone();
+        let genesis_root = blockchain.state_root();
+        let tip = block_on(&genesis, genesis_root, "main", vec![gpu_registration(0)]);
+        blockchain.import_block(tip.clone()).unwrap();
+
+        let state_root = blockchain.state_root();
+        assert_ne!(state_root, genesis_root);
+        assert!(blockchain.pending_receipts.is_empty());
+
+        // The fork is longer but its first block commits to the wrong state.
+        let bad = block_on(&genesis, BlockHash([9; 32]), "fork", Vec::new());
+        let longer = block_on(&bad, BlockHash([9; 32]), "fork", Vec::new());
+        let _ = blockchain.import_block(bad);
+        assert!(blockchain.import_block(longer).is_err());
+
+        assert_eq!(hashes(&blockchain), vec![genesis.hash, tip.hash]);
+        assert_eq!(blockchain.state_root(), state_root);
+        assert_eq!(blockchain.next_nonce(&tip.transactions[0].sender.0), 1);
+        assert!(blockchain.pending_receipts.is_empty());
+        assert!(blockchain.pending_snapshots.is_empty());
+        assert_eq!(blockchain.store.block_at(1).unwrap(), Some(tip));
+    }
+
+    #[tokio::test]
+    async fn reloading_the_store_gives_the_same_head_finality_and_state() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut blockchain = chain(Arc::new(SledStore::open(db.clone()).unwrap())).await;
+        extend(&mut blockchain, vec![deploy(0, "kv"), gpu_registration(1)]);
+        let finalized = extend(&mut blockchain, vec![call(2, "kv", "set a 1")]);
+        let tip = extend(&mut blockchain, vec![call(3, "kv", "set b 2")]);
+        let precommit = Precommit::new(finalized.hash, finalized.index, "node1".to_string(), &secret());
+        assert_eq!(blockchain.add_precommit(precommit), Ok(true));
+
+        let reloaded = Blockchain::new(Arc::new(SledStore::open(db).unwrap()), DEFAULT_CHAIN_ID.to_string(), PoA::new(1), 2).unwrap();
+        assert_eq!(hashes(&reloaded), hashes(&blockchain));
+        assert_eq!(reloaded.store.head().unwrap(), Some((tip.index, tip.hash)));
+        assert_eq!((reloaded.finality.finalized_height, reloaded.finality.finalized_hash), (finalized.index, finalized.hash));
+        assert_eq!(reloaded.state_root(), blockchain.state_root());
+        assert_eq!(reloaded.contract_manager.contracts["kv"].state, blockchain.contract_manager.contracts["kv"].state);
+        assert_eq!(reloaded.contract_manager.contracts["kv"].state.len(), 2);
+        assert_eq!(reloaded.next_nonce(&tip.transactions[0].sender.0), 4);
+        assert_eq!(reloaded.receipts(&tip.hash).unwrap(), blockchain.receipts(&tip.hash).unwrap());
+        assert!(reloaded.receipts(&tip.hash).unwrap().unwrap()[0].success);
+        assert_eq!(reloaded.store.snapshot_heights().unwrap(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn served_snapshot_reassembles_to_the_committed_state() {
+        let mut blockchain = chain(Arc::new(MemoryStore::new())).await;
+        // Enough state for the snapshot to span more than one chunk.
+        let value = "x".repeat(SNAPSHOT_CHUNK_SIZE / 4);
+        let writes = (0..5).map(|i| contract(2 + i, DEFAULT_CHAIN_ID, "kv", ContractAction::Execute {
+            input: format!("set key{} {}", i, value),
+            gas_limit: MAX_CALL_GAS,
+        }));
+        extend(&mut blockchain, vec![deploy(0, "kv"), gpu_registration(1)]);
+        extend(&mut blockchain, writes.collect());
+        assert!(blockchain.snapshot_manifest().is_none());
+        let next = extend(&mut blockchain, Vec::new());
+
+        let manifest = blockchain.snapshot_manifest().unwrap();
+        assert_eq!((manifest.height, manifest.block_hash), (2, blockchain.blocks[2].hash));
+        assert!(manifest.chunk_count > 1);
+        let mut bytes = Vec::new();
+        for index in 0..manifest.chunk_count {
+            bytes.extend(blockchain.snapshot_chunk(manifest.height, index).unwrap());
+        }
+        assert_eq!(blockchain.snapshot_chunk(manifest.height, manifest.chunk_count), None);
+        assert_eq!(blockchain.snapshot_chunk(1, 0), None);
+        assert_eq!(bytes.len() as u64, manifest.size);
+
+        let snapshot = StateSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.state_root(), next.state_root);
+        assert_eq!(snapshot.contracts["kv"].state.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn blocks_with_forged_or_foreign_transactions_are_rejected() {
+        let mut blockchain = chain(Arc::new(MemoryStore::new())).await;
+        extend(&mut blockchain, vec![deploy(0, "kv")]);
+        let parent = blockchain.blocks.last().unwrap().clone();
+
+        // Signed by another key in the name of node1's.
+        let mut forged = call(1, "kv", "set a 1");
+        forged.sign(&SecretKey::from_slice(&[2; 32]).unwrap());
+        let foreign = contract(1, "other-chain", "kv", ContractAction::Execute { input: "set a 1".to_string(), gas_limit: 1_000_000 });
+        for (tx, reason) in [(forged, "signature verification failed"), (foreign, "for chain other-chain")] {
+            assert!(blockchain.submit_transaction(tx.clone()).unwrap_err().contains(reason));
+            let block = block_on(&parent, blockchain.state_root(), "main", vec![tx]);
+            let e = blockchain.check_block(&block, &parent).unwrap_err();
+            assert!(e.contains("Transaction 0 invalid") && e.contains(reason), "{}", e);
+            assert!(blockchain.import_block(block).is_err());
+        }
+        assert_eq!(blockchain.blocks.len(), 2);
+        assert!(blockchain.contract_manager.contracts["kv"].state.is_empty());
+
+        let valid = block_on(&parent, blockchain.state_root(), "main", vec![call(1, "kv", "set a 1")]);
+        assert!(blockchain.check_block(&valid, &parent).is_ok());
+    }
+
+    #[tokio::test]
+    async fn replayed_transactions_are_refused_or_skipped() {
+        let mut blockchain = chain(Arc::new(MemoryStore::new())).await;
+        let first = call(1, "kv", "set a 1");
+        extend(&mut blockchain, vec![deploy(0, "kv"), first.clone()]);
+        extend(&mut blockchain, vec![call(2, "kv", "set a 2")]);
+
+        assert!(blockchain.submit_transaction(first.clone()).unwrap_err().contains("Nonce too low"));
+        let e = blockchain.add_block("main".to_string(), vec![first.clone()], "node1".to_string(), &secret()).unwrap_err();
+        assert!(e.contains("out-of-order nonce"), "{}", e);
+
+        // A block from a peer may still carry it; it is skipped without effect.
+        let block = extend(&mut blockchain, vec![first]);
+        let receipt = &blockchain.receipts(&block.hash).unwrap().unwrap()[0];
+        assert!(!receipt.success && receipt.output.starts_with("Skipped"));
+        assert_eq!(blockchain.contract_manager.contracts["kv"].state["a"], "2");
+        assert_eq!(blockchain.next_nonce(&block.transactions[0].sender.0), 3);
+    }
+
+    #[tokio::test]
+    async fn headers_with_malformed_signatures_are_rejected() {
+        let mut blockchain = chain(Arc::new(MemoryStore::new())).await;
+        let genesis = blockchain.blocks[0].clone();
+        for signature in ["zz", "00", ""] {
+            let mut block = block_on(&genesis, blockchain.state_root(), "main", Vec::new());
+            block.signature = signature.to_string();
+            let e = blockchain.check_header(&block.header(), &genesis.header()).unwrap_err();
+            assert!(e.contains("Invalid block signature"), "{}", e);
+            assert!(blockchain.import_block(block).is_err());
         }
+        assert_eq!(hashes(&blockchain), vec![genesis.hash]);
     }
 }
@@ -1,11 +1,8 @@
 use structopt::StructOpt;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use secp256k1::SecretKey;
-use crate::blockchain::Blockchain;
-use crate::network::broadcast_new_block;
-use crate::server;
-use std::env;
+use secp256k1::{Secp256k1, PublicKey};
+use crate::public_key_serde::SerializablePublicKey;
+use crate::runtime::{shutdown_signal, NodeRuntime};
+use crate::transaction::{Transaction, TransactionPayload};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cognichain")]
@@ -21,18 +18,32 @@ pub enum Cli {
     CheckValidity,
     #[structopt(about = "Start the node and keep it running")]
     StartNode,
+    #[structopt(about = "Vote to add or remove an authority")]
+    VoteAuthority {
+        #[structopt(help = "Node ID of the candidate")]
+        node_id: String,
+        #[structopt(help = "Hex-encoded public key of the candidate")]
+        public_key: String,
+        #[structopt(long, help = "Vote to remove the node instead of adding it")]
+        remove: bool,
+    },
 }
 
 impl Cli {
-    pub async fn run(&self, blockchain: &Arc<Mutex<Blockchain>>, secret_key: &SecretKey, peer_addresses: Vec<String>) {
+    pub async fn run(&self, runtime: &NodeRuntime) {
+        let network = &runtime.network;
+        let (blockchain, identity) = (&network.blockchain, &network.identity);
+        let secret_key = &identity.secret_key;
         match self {
             Cli::AddBlock { data } => {
                 let new_block = {
                     let mut blockchain = blockchain.lock().await; // Using async lock
-                    blockchain.add_block(data.clone(), env::var("NODE_ID").unwrap_or_else(|_| "node1".to_string()), secret_key).unwrap()
+                    let transactions = blockchain.pending_transactions();
+                    blockchain.add_block(data.clone(), transactions, identity.node_id.clone(), secret_key).unwrap()
                 };
                 println!("New block added: {:?}", new_block);
-                broadcast_new_block(&new_block, peer_addresses).await; // Correctly using .await on an async function
+                network.announce_block(&new_block).await;
+                network.linger().await;
             },
             Cli::ViewChain => {
                 let blockchain = blockchain.lock().await; // Using async lock
@@ -46,8 +57,42 @@ impl Cli {
             },
             Cli::StartNode => {
                 println!("Starting the node...");
-                let node_ip = "0.0.0.0:6397".to_string(); // Example IP and port
-                server::start_node_server(node_ip, blockchain.clone()).await;
+                if let Err(e) = runtime.start().await {
+                    println!("Failed to start the node: {}", e);
+                    return;
+                }
+                shutdown_signal().await;
+                println!("Shutting down...");
+            }
+            Cli::VoteAuthority { node_id, public_key, remove } => {
+                let candidate_key = match hex::decode(public_key).ok().and_then(|bytes| PublicKey::from_slice(&bytes).ok()) {
+                    Some(key) => key,
+                    None => {
+                        println!("Invalid candidate public key");
+                        return;
+                    }
+                };
+                let own_key = PublicKey::from_secret_key(&Secp256k1::new(), secret_key);
+                let tx = {
+                    let mut blockchain = blockchain.lock().await;
+                    let payload = TransactionPayload::AuthorityVote {
+                        node_id: node_id.clone(),
+                        public_key: SerializablePublicKey(candidate_key),
+                        add: !remove,
+                    };
+                    let mut tx = Transaction::new(own_key, blockchain.pending_nonce(&own_key), 0, payload);
+                    tx.sign(secret_key);
+                    match blockchain.submit_transaction(tx.clone()) {
+                        Ok(hash) => println!("Vote submitted: {}", hash),
+                        Err(e) => {
+                            println!("Vote rejected: {}", e);
+                            return;
+                        }
+                    }
+                    tx
+                };
+                network.announce_transaction(&tx).await;
+                network.linger().await;
             }
         }
     }
@@ -0,0 +1,219 @@
+use std::collections::{BTreeSet, HashMap};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use secp256k1::{Secp256k1, SecretKey, PublicKey, Message, ecdsa::Signature};
+use hex::decode;
+use crate::block::BlockHash;
+use crate::node::Node;
+
+const PRECOMMIT_DOMAIN: &[u8] = b"cognichain/precommit";
+
+// Votes needed to finalize a block: strictly more than two thirds of the set.
+pub fn finality_threshold(authority_count: usize) -> usize {
+    authority_count * 2 / 3 + 1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Precommit {
+    pub block_hash: BlockHash,
+    pub height: u64,
+    pub voter: String,
+    pub signature: String,
+}
+
+impl Precommit {
+    pub fn new(block_hash: BlockHash, height: u64, voter: String, secret_key: &SecretKey) -> Self {
+        let secp = Secp256k1::new();
+        let message = Self::message(&block_hash, height);
+        let signature = secp.sign_ecdsa(&message, secret_key).to_string();
+        Precommit { block_hash, height, voter, signature }
+    }
+
+    fn message(block_hash: &BlockHash, height: u64) -> Message {
+        let mut hasher = Sha256::new();
+        hasher.update(PRECOMMIT_DOMAIN);
+        hasher.update(height.to_be_bytes());
+        hasher.update(block_hash.as_bytes());
+        Message::from_slice(&hasher.finalize()).expect("Hash should be 32 bytes")
+    }
+
+    pub fn verify(&self, public_key: &PublicKey) -> Result<(), String> {
+        let secp = Secp256k1::new();
+        let sig_bytes = decode(&self.signature).map_err(|_| "Precommit signature is not valid hex".to_string())?;
+        let sig = Signature::from_der(&sig_bytes).map_err(|_| "Invalid precommit signature format".to_string())?;
+        secp.verify_ecdsa(&Self::message(&self.block_hash, self.height), &sig, public_key)
+            .map_err(|_| format!("Invalid precommit signature from {}", self.voter))
+    }
+
+    fn verify_against(&self, authorities: &[Node]) -> Result<(), String> {
+        let voter = authorities.iter()
+            .find(|node| node.is_authority && node.id == self.voter)
+            .ok_or_else(|| format!("Precommit voter {} is not an authority at height {}", self.voter, self.height))?;
+        self.verify(&voter.public_key)
+    }
+}
+
+// Proof that a block is final: precommits from more than two thirds of the
+// authority set active at its height. Stored next to the block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Justification {
+    pub block_hash: BlockHash,
+    pub height: u64,
+    pub precommits: Vec<Precommit>,
+}
+
+impl Justification {
+    pub fn verify(&self, authorities: &[Node]) -> Result<(), String> {
+        let mut voters = BTreeSet::new();
+        for precommit in &self.precommits {
+            if precommit.block_hash != self.block_hash || precommit.height != self.height {
+                return Err("Justification contains a precommit for another block".to_string());
+            }
+            precommit.verify_against(authorities)?;
+            voters.insert(precommit.voter.as_str());
+        }
+        let set_size = authorities.iter().filter(|node| node.is_authority).count();
+        if voters.len() < finality_threshold(set_size) {
+            return Err(format!("Justification has {} of {} required precommits", voters.len(), finality_threshold(set_size)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FinalityGadget {
+    pub finalized_height: u64,
+    pub finalized_hash: BlockHash,
+    votes: HashMap<BlockHash, Vec<Precommit>>,
+}
+
+impl FinalityGadget {
+    pub fn set_finalized(&mut self, height: u64, hash: BlockHash) {
+        self.finalized_height = height;
+        self.finalized_hash = hash;
+        self.votes.retain(|_, precommits| precommits.first().map_or(false, |p| p.height > height));
+    }
+
+    // Records a verified precommit and returns a justification once the block
+    // it votes for has enough of them.
+    pub fn add_precommit(&mut self, precommit: Precommit, authorities: &[Node]) -> Result<Option<Justification>, String> {
+        // Genesis and everything up to the finalized block need no more votes.
+        if precommit.height <= self.finalized_height {
+            return Ok(None);
+        }
+        precommit.verify_against(authorities)?;
+
+        let precommits = self.votes.entry(precommit.block_hash).or_default();
+        if precommits.iter().any(|p| p.voter == precommit.voter) {
+            return Ok(None);
+        }
+        let block_hash = precommit.block_hash;
+        let height = precommit.height;
+        precommits.push(precommit);
+
+        let set_size = authorities.iter().filter(|node| node.is_authority).count();
+        if precommits.len() < finality_threshold(set_size) {
+            return Ok(None);
+        }
+        Ok(Some(Justification { block_hash, height, precommits: precommits.clone() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn authorities(count: u8) -> Vec<Node> {
+        (1..=count).map(|seed| Node {
+            id: format!("node{}", seed),
+            is_authority: true,
+            public_key: PublicKey::from_secret_key(&Secp256k1::new(), &secret(seed)),
+        }).collect()
+    }
+
+    fn precommit(seed: u8, block_hash: BlockHash, height: u64) -> Precommit {
+        Precommit::new(block_hash, height, format!("node{}", seed), &secret(seed))
+    }
+
+    fn block(n: u8) -> BlockHash {
+        BlockHash([n; 32])
+    }
+
+    #[test]
+    fn threshold_is_more_than_two_thirds() {
+        assert_eq!(finality_threshold(1), 1);
+        assert_eq!(finality_threshold(3), 3);
+        assert_eq!(finality_threshold(4), 3);
+        assert_eq!(finality_threshold(7), 5);
+    }
+
+    #[test]
+    fn block_is_final_at_exactly_the_threshold() {
+        for count in [4, 7] {
+            let authorities = authorities(count);
+            let threshold = finality_threshold(count as usize) as u8;
+            let mut gadget = FinalityGadget::default();
+            for seed in 1..threshold {
+                assert_eq!(gadget.add_precommit(precommit(seed, block(1), 1), &authorities), Ok(None));
+            }
+            let justification = gadget.add_precommit(precommit(threshold, block(1), 1), &authorities).unwrap().unwrap();
+            assert_eq!(justification.precommits.len(), threshold as usize);
+            assert!(justification.verify(&authorities).is_ok());
+
+            let mut short = justification;
+            short.precommits.pop();
+            assert!(short.verify(&authorities).is_err());
+        }
+    }
+
+    #[test]
+    fn duplicate_precommit_is_counted_once() {
+        let authorities = authorities(4);
+        let mut gadget = FinalityGadget::default();
+        assert_eq!(gadget.add_precommit(precommit(1, block(1), 1), &authorities), Ok(None));
+        assert_eq!(gadget.add_precommit(precommit(1, block(1), 1), &authorities), Ok(None));
+        assert_eq!(gadget.add_precommit(precommit(2, block(1), 1), &authorities), Ok(None));
+        assert!(gadget.add_precommit(precommit(3, block(1), 1), &authorities).unwrap().is_some());
+
+        let duplicated = Justification {
+            block_hash: block(1),
+            height: 1,
+            precommits: vec![precommit(1, block(1), 1), precommit(1, block(1), 1), precommit(2, block(1), 1)],
+        };
+        assert!(duplicated.verify(&authorities).is_err());
+    }
+
+    #[test]
+    fn votes_for_different_blocks_do_not_add_up() {
+        let authorities = authorities(4);
+        let mut gadget = FinalityGadget::default();
+        assert_eq!(gadget.add_precommit(precommit(1, block(1), 1), &authorities), Ok(None));
+        assert_eq!(gadget.add_precommit(precommit(2, block(2), 1), &authorities), Ok(None));
+        assert_eq!(gadget.add_precommit(precommit(3, block(1), 1), &authorities), Ok(None));
+
+        let mixed = Justification {
+            block_hash: block(1),
+            height: 1,
+            precommits: vec![precommit(1, block(1), 1), precommit(2, block(2), 1), precommit(3, block(1), 1)],
+        };
+        assert!(mixed.verify(&authorities).is_err());
+    }
+
+    #[test]
+    fn unknown_forged_and_already_final_precommits_are_not_counted() {
+        let authorities = authorities(4);
+        let mut gadget = FinalityGadget::default();
+        assert!(gadget.add_precommit(precommit(9, block(1), 1), &authorities).is_err());
+        let forged = Precommit { voter: "node2".to_string(), ..precommit(1, block(1), 1) };
+        assert!(gadget.add_precommit(forged, &authorities).is_err());
+
+        gadget.set_finalized(5, block(5));
+        for seed in 1..=4 {
+            assert_eq!(gadget.add_precommit(precommit(seed, block(4), 4), &authorities), Ok(None));
+        }
+    }
+}
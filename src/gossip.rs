@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use crate::block::{Block, BlockHash};
+use crate::blockchain::Blockchain;
+use crate::finality::Precommit;
+use crate::handshake::PeerInfo;
+use crate::network::Network;
+use crate::peer_manager::Offence;
+use crate::poa::now_millis;
+use crate::protocol::{Codec, InventoryItem, Message, MAX_INVENTORY_ITEMS};
+use crate::server::handle_message;
+use crate::sync;
+use crate::transport::SecureStream;
+
+const SEEN_CACHE_SIZE: usize = 20_000;
+// How long we wait for a requested item before asking another peer.
+const REQUEST_EXPIRY_MS: u128 = 30_000;
+// Blocks waiting for their parent; the pool is dropped when it overflows.
+const MAX_ORPHANS: usize = 256;
+// Messages queued for a peer before further ones are dropped.
+const OUTBOX_SIZE: usize = 1024;
+
+// The most recent `capacity` items, oldest evicted first.
+#[derive(Debug)]
+pub struct SeenCache<T> {
+    order: VecDeque<T>,
+    items: HashSet<T>,
+    capacity: usize,
+}
+
+impl<T: Hash + Eq + Clone> SeenCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache { order: VecDeque::new(), items: HashSet::new(), capacity }
+    }
+
+    // Records `item` and returns whether it was new.
+    pub fn insert(&mut self, item: T) -> bool {
+        if !self.items.insert(item.clone()) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+}
+
+// Gossip state shared by every open peer session: who we are connected to,
+// what we have already seen and what we are waiting for.
+#[derive(Debug)]
+pub struct Gossip {
+    seen: SeenCache<InventoryItem>,
+    seen_precommits: SeenCache<(BlockHash, String)>,
+    in_flight: HashMap<InventoryItem, u128>,
+    // Blocks keyed by the parent they are waiting for.
+    orphans: HashMap<BlockHash, Vec<Block>>,
+    orphan_count: usize,
+    outboxes: HashMap<String, mpsc::Sender<Message>>,
+}
+
+impl Gossip {
+    pub fn new() -> Self {
+        Gossip {
+            seen: SeenCache::new(SEEN_CACHE_SIZE),
+            seen_precommits: SeenCache::new(SEEN_CACHE_SIZE),
+            in_flight: HashMap::new(),
+            orphans: HashMap::new(),
+            orphan_count: 0,
+            outboxes: HashMap::new(),
+        }
+    }
+
+    // Registers a session; refused if we already have one with `address`.
+    fn connect(&mut self, address: &str, outbox: mpsc::Sender<Message>) -> bool {
+        if self.outboxes.contains_key(address) {
+            return false;
+        }
+        self.outboxes.insert(address.to_string(), outbox);
+        true
+    }
+
+    fn disconnect(&mut self, address: &str) {
+        self.outboxes.remove(address);
+    }
+
+    pub fn is_connected(&self, address: &str) -> bool {
+        self.outboxes.contains_key(address)
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.outboxes.len()
+    }
+
+    // Queues a message for one session; false if it is gone or backed up.
+    pub fn send_to(&self, address: &str, message: Message) -> bool {
+        self.outboxes.get(address).is_some_and(|outbox| outbox.try_send(message).is_ok())
+    }
+
+    // Sends to every session except `source`. Slow peers miss messages
+    // rather than holding everyone up.
+    pub fn send_to_all(&self, message: &Message, source: Option<&str>) {
+        for (address, outbox) in &self.outboxes {
+            if Some(address.as_str()) != source && outbox.try_send(message.clone()).is_err() {
+                log::debug!("Dropped gossip to {}: outbox full", address);
+            }
+        }
+    }
+
+    // Marks an item we hold as seen and announces it to every peer but the
+    // one it came from.
+    pub fn announce(&mut self, item: InventoryItem, source: Option<&str>) {
+        self.seen.insert(item);
+        self.send_to_all(&Message::Inventory(vec![item]), source);
+    }
+
+    // Forwards a precommit we accepted, unless it already went round.
+    pub fn relay_precommit(&mut self, precommit: &Precommit, source: Option<&str>) {
+        self.seen_precommits.insert((precommit.block_hash, precommit.voter.clone()));
+        self.send_to_all(&Message::Precommit(precommit.clone()), source);
+    }
+
+    fn has_seen_precommit(&self, precommit: &Precommit) -> bool {
+        self.seen_precommits.contains(&(precommit.block_hash, precommit.voter.clone()))
+    }
+
+    // The announced items worth requesting: not held, not seen and not
+    // already requested from another peer.
+    fn wanted(&mut self, items: Vec<InventoryItem>, have: impl Fn(&InventoryItem) -> bool) -> Vec<InventoryItem> {
+        let now = now_millis();
+        self.in_flight.retain(|_, requested_at| now < *requested_at + REQUEST_EXPIRY_MS);
+        let mut wanted = Vec::new();
+        for item in items {
+            if self.seen.contains(&item) || self.in_flight.contains_key(&item) || have(&item) {
+                continue;
+            }
+            self.in_flight.insert(item, now);
+            wanted.push(item);
+        }
+        wanted
+    }
+
+    // Records the arrival of an item's data; false if we had seen it before.
+    fn received(&mut self, item: InventoryItem) -> bool {
+        self.in_flight.remove(&item);
+        self.seen.insert(item)
+    }
+
+    fn add_orphan(&mut self, block: Block) {
+        if self.orphan_count >= MAX_ORPHANS {
+            log::warn!("Orphan pool full, dropping {} blocks", self.orphan_count);
+            self.orphans.clear();
+            self.orphan_count = 0;
+        }
+        self.orphans.entry(block.previous_hash).or_default().push(block);
+        self.orphan_count += 1;
+    }
+
+    fn take_orphans(&mut self, parent: &BlockHash) -> Vec<Block> {
+        let orphans = self.orphans.remove(parent).unwrap_or_default();
+        self.orphan_count -= orphans.len();
+        orphans
+    }
+
+    // Imports the parked blocks that `parents` unblock, and the ones those
+    // unblock in turn. Returns the hashes imported.
+    pub fn connect_orphans(&mut self, blockchain: &mut Blockchain, parents: Vec<BlockHash>) -> Vec<BlockHash> {
+        let mut imported = Vec::new();
+        let mut unblocked = parents;
+        while let Some(parent) = unblocked.pop() {
+            for orphan in self.take_orphans(&parent) {
+                let orphan_hash = orphan.hash;
+                match blockchain.import_block(orphan) {
+                    Ok(()) => {
+                        imported.push(orphan_hash);
+                        unblocked.push(orphan_hash);
+                    }
+                    Err(e) => log::debug!("Dropped orphan block {}: {}", orphan_hash, e),
+                }
+            }
+        }
+        imported
+    }
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Gossip::new()
+    }
+}
+
+fn has_item(blockchain: &Blockchain, item: &InventoryItem) -> bool {
+    match item {
+        InventoryItem::Block(hash) => blockchain.tree.contains(hash),
+        InventoryItem::Transaction(hash) => blockchain.mempool.get(hash).is_some(),
+    }
+}
+
+fn reply(outbox: &mpsc::Sender<Message>, message: Message) -> Result<(), String> {
+    outbox.try_send(message).map_err(|_| "Outbox to peer is full".to_string())
+}
+
+// An authenticated connection registered for gossip and sync. `address`
+// identifies the peer in the peer manager.
+pub struct Session {
+    peer: PeerInfo,
+    address: String,
+    outbox: mpsc::Sender<Message>,
+    queue: mpsc::Receiver<Message>,
+}
+
+impl Session {
+    // Registers the connection; refused if we already have one with `address`.
+    pub async fn open(network: &Network, peer: PeerInfo, address: String) -> Result<Self, String> {
+        let (outbox, queue) = mpsc::channel(OUTBOX_SIZE);
+        if !network.gossip.lock().await.connect(&address, outbox.clone()) {
+            return Err(format!("Already connected to {}", address));
+        }
+        let has_tip = network.blockchain.lock().await.tree.contains(&peer.best_hash);
+        network.sync.lock().await.add_peer(&address, peer.best_height, has_tip);
+        Ok(Session { peer, address, outbox, queue })
+    }
+
+    // Runs the connection until either side closes it: requests are
+    // answered, announcements fetched, and verified data relayed to the
+    // other sessions.
+    pub async fn run<S>(self, stream: SecureStream<S>, network: Network) -> Result<(), String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Session { peer, address, outbox, mut queue } = self;
+        let codec = Codec::default();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let writer_task = tokio::spawn(async move {
+            while let Some(message) = queue.recv().await {
+                if let Err(e) = codec.write(&mut writer, &message).await {
+                    log::debug!("Stopped writing to peer: {}", e);
+                    break;
+                }
+            }
+        });
+        sync::step(&network).await;
+
+        let result = async {
+            while let Some(message) = codec.read(&mut reader).await? {
+                let offence = Offence::for_message(&message);
+                if let Err(e) = handle_gossip(&network, &peer, &address, &outbox, message).await {
+                    log::warn!("Rejected message from peer {}: {}", peer.node_id, e);
+                    let mut peers = network.peers.lock().await;
+                    peers.penalize(&address, offence);
+                    if peers.is_banned(&address) {
+                        return Err(format!("Peer {} is banned", peer.node_id));
+                    }
+                }
+            }
+            Ok(())
+        }.await;
+
+        network.gossip.lock().await.disconnect(&address);
+        network.sync.lock().await.remove_peer(&address);
+        writer_task.abort();
+        result
+    }
+}
+
+async fn handle_gossip(network: &Network, peer: &PeerInfo, address: &str, outbox: &mpsc::Sender<Message>, message: Message) -> Result<(), String> {
+    match message {
+        Message::Inventory(items) => {
+            if items.len() > MAX_INVENTORY_ITEMS {
+                return Err(format!("Announcement of {} items exceeds the limit", items.len()));
+            }
+            let wanted = {
+                let blockchain = network.blockchain.lock().await;
+                let mut gossip = network.gossip.lock().await;
+                gossip.wanted(items, |item| has_item(&blockchain, item))
+            };
+            if !wanted.is_empty() {
+                reply(outbox, Message::GetData(wanted))?;
+            }
+        }
+        Message::GetData(items) => {
+            if items.len() > MAX_INVENTORY_ITEMS {
+                return Err(format!("Request for {} items exceeds the limit", items.len()));
+            }
+            let blockchain = network.blockchain.lock().await;
+            for item in items {
+                let data = match item {
+                    InventoryItem::Block(hash) => blockchain.tree.get(&hash)
+                        .filter(|block| block.index > blockchain.snapshot_base)
+                        .cloned()
+                        .map(Message::NewBlock),
+                    InventoryItem::Transaction(hash) => blockchain.mempool.get(&hash).cloned().map(Message::NewTransaction),
+                };
+                if let Some(data) = data {
+                    reply(outbox, data)?;
+                }
+            }
+        }
+        Message::Headers(headers) => {
+            {
+                let blockchain = network.blockchain.lock().await;
+                network.sync.lock().await.on_headers(address, headers, &blockchain)?;
+            }
+            sync::step(network).await;
+        }
+        Message::Blocks(blocks) => {
+            network.sync.lock().await.on_blocks(address, blocks)?;
+            sync::step(network).await;
+        }
+        // Answer to the status poll in `sync::run_sync`.
+        Message::Status { best_height, best_hash, .. } => {
+            let has_tip = network.blockchain.lock().await.tree.contains(&best_hash);
+            if !has_tip {
+                network.sync.lock().await.peer_advanced(address, best_height);
+                sync::step(network).await;
+            }
+        }
+        Message::NewBlock(block) => receive_block(network, address, block).await?,
+        Message::NewTransaction(tx) => {
+            let item = InventoryItem::Transaction(tx.hash());
+            if !network.gossip.lock().await.received(item) {
+                return Ok(());
+            }
+            network.blockchain.lock().await.submit_transaction(tx).map_err(|e| format!("transaction: {}", e))?;
+            network.gossip.lock().await.announce(item, Some(address));
+        }
+        Message::Precommit(precommit) => {
+            if network.gossip.lock().await.has_seen_precommit(&precommit) {
+                return Ok(());
+            }
+            network.blockchain.lock().await.add_precommit(precommit.clone()).map_err(|e| format!("precommit: {}", e))?;
+            network.gossip.lock().await.relay_precommit(&precommit, Some(address));
+        }
+        other => {
+            let response = {
+                let mut blockchain = network.blockchain.lock().await;
+                let peers = network.peers.lock().await;
+                handle_message(&mut blockchain, &peers, other)?
+            };
+            if let Some(response) = response {
+                reply(outbox, response)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Imports a gossiped block, or parks it until sync fetches its ancestors,
+// then imports any parked blocks it unblocks and announces everything
+// imported.
+async fn receive_block(network: &Network, address: &str, block: Block) -> Result<(), String> {
+    let (index, hash) = (block.index, block.hash);
+    if !network.gossip.lock().await.received(InventoryItem::Block(hash)) {
+        return Ok(());
+    }
+    let mut blockchain = network.blockchain.lock().await;
+    if blockchain.tree.contains(&hash) {
+        return Ok(());
+    }
+    if !blockchain.tree.contains(&block.previous_hash) {
+        network.gossip.lock().await.add_orphan(block);
+        network.sync.lock().await.peer_advanced(address, index);
+        drop(blockchain);
+        sync::step(network).await;
+        return Ok(());
+    }
+    blockchain.import_block(block).map_err(|e| format!("block {} at height {}: {}", hash, index, e))?;
+    log::info!("Imported block {} at height {} from {}", hash, index, address);
+
+    let mut gossip = network.gossip.lock().await;
+    let mut imported = vec![hash];
+    imported.extend(gossip.connect_orphans(&mut blockchain, vec![hash]));
+    drop(blockchain);
+    for hash in imported {
+        gossip.announce(InventoryItem::Block(hash), Some(address));
+    }
+    Ok(())
+}
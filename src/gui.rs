@@ -10,7 +10,14 @@ use secp256k1::SecretKey;
 use secp256k1::PublicKey;
 use secp256k1::Secp256k1;
 use crate::api::OperationResponse;
-use crate::smart_contract::ContractType;
+use crate::smart_contract::{ContractAction, ContractRequest, ContractType};
+use crate::transaction::{Transaction, TransactionPayload};
+
+#[derive(Deserialize)]
+struct ContractNonce {
+    chain_id: String,
+    nonce: u64,
+}
 
 #[derive(Clone, Data, Lens, Deserialize)]
 struct Block {
@@ -118,11 +125,32 @@ async fn create_or_update_contract(client: &Client, public_key: &str, gpu_type:
         ram_capacity,
     };
 
+    // Deploys are transactions signed with the node's key.
+    let secret_key = env::var("SECRET_KEY").ok()
+        .and_then(|key| hex::decode(key).ok())
+        .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+        .ok_or_else(|| anyhow::anyhow!("SECRET_KEY must be set to a valid secret key"))?;
+    let ContractNonce { chain_id, nonce } = client.get("http://127.0.0.1:3030/contract/nonce")
+        .query(&[("caller", public_key)])
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    let request = ContractRequest {
+        chain_id,
+        contract_id: public_key.to_string(),
+        action: ContractAction::Deploy { code: Vec::new(), contract_type: contract_type.clone() },
+    };
+    let owner = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+    let mut tx = Transaction::new(owner, nonce, 0, TransactionPayload::Contract(request));
+    tx.sign(&secret_key);
+
     let body: serde_json::Value = serde_json::json!({
         "id": public_key,
         "owner": public_key,
         "code": vec![] as Vec<u8>,
-        "contract_type": contract_type
+        "contract_type": contract_type,
+        "nonce": nonce,
+        "signature": tx.signature,
     });
 
     let response = client.post(url)
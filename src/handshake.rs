@@ -0,0 +1,248 @@
+use secp256k1::{Secp256k1, SecretKey, PublicKey, Message as SecpMessage, ecdsa::Signature};
+use sha2::{Sha256, Digest};
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::block::BlockHash;
+use crate::blockchain::Blockchain;
+use crate::node::Node;
+use crate::protocol::{Codec, Message, PROTOCOL_VERSION};
+use crate::public_key_serde::SerializablePublicKey;
+use crate::transport::{Encryption, SecureStream};
+
+const HANDSHAKE_DOMAIN: &[u8] = b"cognichain/handshake";
+
+// The key this node signs handshakes, blocks and votes with.
+#[derive(Clone)]
+pub struct NodeIdentity {
+    pub node_id: String,
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+    pub encryption: Encryption,
+    // Where this node accepts peer connections, announced in the handshake.
+    pub listen_address: Option<String>,
+}
+
+impl NodeIdentity {
+    pub fn new(node_id: String, secret_key: SecretKey) -> Self {
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        NodeIdentity { node_id, secret_key, public_key, encryption: Encryption::default(), listen_address: None }
+    }
+
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    pub fn with_listen_address(mut self, listen_address: String) -> Self {
+        self.listen_address = Some(listen_address);
+        self
+    }
+
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_address.as_ref()
+            .and_then(|address| address.rsplit(':').next())
+            .and_then(|port| port.parse().ok())
+    }
+}
+
+// Our chain as announced in the handshake, plus the authorities whose keys a
+// peer claiming their node ID has to match.
+#[derive(Debug, Clone)]
+pub struct LocalChain {
+    pub genesis_hash: BlockHash,
+    pub best_height: u64,
+    pub best_hash: BlockHash,
+    pub authorities: Vec<Node>,
+}
+
+impl LocalChain {
+    pub fn of(blockchain: &Blockchain) -> Self {
+        LocalChain {
+            genesis_hash: blockchain.blocks.first().map_or(BlockHash::ZERO, |block| block.hash),
+            best_height: blockchain.blocks.len().saturating_sub(1) as u64,
+            best_hash: blockchain.blocks.last().map_or(BlockHash::ZERO, |block| block.hash),
+            authorities: blockchain.authorities.clone(),
+        }
+    }
+}
+
+// A peer that proved it holds the private key for `public_key`.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub node_id: String,
+    pub public_key: PublicKey,
+    pub genesis_hash: BlockHash,
+    pub best_height: u64,
+    pub best_hash: BlockHash,
+    pub listen_port: Option<u16>,
+    pub encrypted: bool,
+}
+
+// Client side: negotiate the transport, send our hello, check the peer's,
+// then exchange signatures over each other's challenge and the session.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(stream: S, codec: &Codec, identity: &NodeIdentity, local: &LocalChain) -> Result<(SecureStream<S>, PeerInfo), String> {
+    let mut stream = SecureStream::connect(stream, codec, identity.encryption).await?;
+    let session = stream.session_id();
+    let challenge: [u8; 32] = rand::random();
+    let response = codec.request(&mut stream, &hello(identity, local, challenge)).await?;
+    let (mut peer, peer_challenge) = check_hello(response, local)?;
+    peer.encrypted = stream.is_encrypted();
+
+    let proof = Message::HelloProof { signature: sign_challenge(identity, &peer_challenge, &session) };
+    match codec.request(&mut stream, &proof).await? {
+        Message::HelloProof { signature } => verify_challenge(&peer, &challenge, &session, &signature)?,
+        other => return Err(format!("Expected handshake proof, got {:?}", other)),
+    }
+    Ok((stream, peer))
+}
+
+// Server side of `initiate`.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(stream: S, codec: &Codec, identity: &NodeIdentity, local: &LocalChain) -> Result<(SecureStream<S>, PeerInfo), String> {
+    let mut stream = SecureStream::accept(stream, codec, identity.encryption).await?;
+    let session = stream.session_id();
+    let request = codec.read(&mut stream).await?.ok_or_else(|| "Peer closed the connection".to_string())?;
+    let (mut peer, peer_challenge) = check_hello(request, local)?;
+    peer.encrypted = stream.is_encrypted();
+
+    let challenge: [u8; 32] = rand::random();
+    match codec.request(&mut stream, &hello(identity, local, challenge)).await? {
+        Message::HelloProof { signature } => verify_challenge(&peer, &challenge, &session, &signature)?,
+        other => return Err(format!("Expected handshake proof, got {:?}", other)),
+    }
+    let proof = Message::HelloProof { signature: sign_challenge(identity, &peer_challenge, &session) };
+    codec.write(&mut stream, &proof).await?;
+    Ok((stream, peer))
+}
+
+fn hello(identity: &NodeIdentity, local: &LocalChain, challenge: [u8; 32]) -> Message {
+    Message::Hello {
+        node_id: identity.node_id.clone(),
+        public_key: SerializablePublicKey(identity.public_key),
+        protocol_version: PROTOCOL_VERSION,
+        genesis_hash: local.genesis_hash,
+        best_height: local.best_height,
+        best_hash: local.best_hash,
+        listen_port: identity.listen_port(),
+        challenge,
+    }
+}
+
+fn check_hello(message: Message, local: &LocalChain) -> Result<(PeerInfo, [u8; 32]), String> {
+    let (node_id, public_key, protocol_version, genesis_hash, best_height, best_hash, listen_port, challenge) = match message {
+        Message::Hello { node_id, public_key, protocol_version, genesis_hash, best_height, best_hash, listen_port, challenge } =>
+            (node_id, public_key.0, protocol_version, genesis_hash, best_height, best_hash, listen_port, challenge),
+        other => return Err(format!("Expected hello, got {:?}", other)),
+    };
+    if protocol_version != PROTOCOL_VERSION {
+        return Err(format!("Peer {} speaks protocol version {}, expected {}", node_id, protocol_version, PROTOCOL_VERSION));
+    }
+    // A node without any blocks yet can join any chain.
+    if !genesis_hash.is_zero() && !local.genesis_hash.is_zero() && genesis_hash != local.genesis_hash {
+        return Err(format!("Peer {} has genesis {}, ours is {}", node_id, genesis_hash, local.genesis_hash));
+    }
+    if let Some(authority) = local.authorities.iter().find(|node| node.id == node_id) {
+        if authority.public_key != public_key {
+            return Err(format!("Peer claims to be authority {} with a different key", node_id));
+        }
+    }
+    Ok((PeerInfo { node_id, public_key, genesis_hash, best_height, best_hash, listen_port, encrypted: false }, challenge))
+}
+
+fn challenge_message(challenge: &[u8; 32], signer: &PublicKey, session: &[u8; 32]) -> SecpMessage {
+    let mut hasher = Sha256::new();
+    hasher.update(HANDSHAKE_DOMAIN);
+    hasher.update(challenge);
+    hasher.update(signer.serialize());
+    hasher.update(session);
+    SecpMessage::from_slice(&hasher.finalize()).expect("Hash should be 32 bytes")
+}
+
+fn sign_challenge(identity: &NodeIdentity, challenge: &[u8; 32], session: &[u8; 32]) -> String {
+    let secp = Secp256k1::new();
+    let message = challenge_message(challenge, &identity.public_key, session);
+    secp.sign_ecdsa(&message, &identity.secret_key).to_string()
+}
+
+fn verify_challenge(peer: &PeerInfo, challenge: &[u8; 32], session: &[u8; 32], signature: &str) -> Result<(), String> {
+    let secp = Secp256k1::new();
+    let sig_bytes = hex::decode(signature).map_err(|_| "Handshake signature is not valid hex".to_string())?;
+    let sig = Signature::from_der(&sig_bytes).map_err(|_| "Invalid handshake signature format".to_string())?;
+    secp.verify_ecdsa(&challenge_message(challenge, &peer.public_key, session), &sig, &peer.public_key)
+        .map_err(|_| format!("Peer {} failed to prove its identity", peer.node_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(node_id: &str, seed: u8) -> NodeIdentity {
+        NodeIdentity::new(node_id.to_string(), SecretKey::from_slice(&[seed; 32]).unwrap())
+    }
+
+    fn local(authorities: Vec<Node>) -> LocalChain {
+        LocalChain { genesis_hash: BlockHash([1; 32]), best_height: 3, best_hash: BlockHash([3; 32]), authorities }
+    }
+
+    fn peer_info(identity: &NodeIdentity) -> PeerInfo {
+        PeerInfo {
+            node_id: identity.node_id.clone(),
+            public_key: identity.public_key,
+            genesis_hash: BlockHash::ZERO,
+            best_height: 0,
+            best_hash: BlockHash::ZERO,
+            listen_port: None,
+            encrypted: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_learn_each_others_identity() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let codec = Codec::default();
+        let client = identity("node1", 1).with_listen_address("127.0.0.1:9001".to_string());
+        let server = identity("node2", 2);
+        let chain = local(Vec::new());
+        let (initiated, responded) = tokio::join!(initiate(a, &codec, &client, &chain), respond(b, &codec, &server, &chain));
+        let ((client_stream, seen_by_client), (server_stream, seen_by_server)) = (initiated.unwrap(), responded.unwrap());
+        assert_eq!((seen_by_client.node_id.as_str(), seen_by_client.public_key), ("node2", server.public_key));
+        assert_eq!((seen_by_server.node_id.as_str(), seen_by_server.public_key), ("node1", client.public_key));
+        assert_eq!(seen_by_server.listen_port, Some(9001));
+        assert!(seen_by_client.encrypted && seen_by_server.encrypted);
+        assert_eq!(client_stream.session_id(), server_stream.session_id());
+    }
+
+    #[test]
+    fn proof_only_holds_for_its_own_challenge_and_session() {
+        let signer = identity("node1", 1);
+        let challenge = [7; 32];
+        let session = [8; 32];
+        let signature = sign_challenge(&signer, &challenge, &session);
+        assert!(verify_challenge(&peer_info(&signer), &challenge, &session, &signature).is_ok());
+        assert!(verify_challenge(&peer_info(&signer), &challenge, &[9; 32], &signature).is_err());
+        assert!(verify_challenge(&peer_info(&signer), &[9; 32], &session, &signature).is_err());
+        assert!(verify_challenge(&peer_info(&identity("node1", 2)), &challenge, &session, &signature).is_err());
+    }
+
+    #[test]
+    fn hello_must_match_our_protocol_genesis_and_authority_keys() {
+        let peer = identity("node1", 1);
+        let ours = local(Vec::new());
+        assert!(check_hello(hello(&peer, &ours, [0; 32]), &ours).is_ok());
+
+        let mut other_version = hello(&peer, &ours, [0; 32]);
+        if let Message::Hello { protocol_version, .. } = &mut other_version {
+            *protocol_version += 1;
+        }
+        assert!(check_hello(other_version, &ours).is_err());
+
+        let other_chain = LocalChain { genesis_hash: BlockHash([2; 32]), ..ours.clone() };
+        assert!(check_hello(hello(&peer, &other_chain, [0; 32]), &ours).is_err());
+        // A peer without blocks may still join.
+        let empty = LocalChain { genesis_hash: BlockHash::ZERO, ..ours.clone() };
+        assert!(check_hello(hello(&peer, &empty, [0; 32]), &ours).is_ok());
+
+        let impostor = identity("node1", 2);
+        let with_authority = local(vec![Node { id: "node1".to_string(), is_authority: true, public_key: peer.public_key }]);
+        assert!(check_hello(hello(&peer, &ours, [0; 32]), &with_authority).is_ok());
+        assert!(check_hello(hello(&impostor, &ours, [0; 32]), &with_authority).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use secp256k1::PublicKey;
+use serde::Serialize;
+use crate::transaction::{Transaction, TxHash};
+
+pub const DEFAULT_MAX_TRANSACTIONS: usize = 5000;
+// How far past the sender's next chain nonce a transaction may be queued.
+pub const MAX_NONCE_GAP: u64 = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolEntry {
+    pub hash: TxHash,
+    pub transaction: Transaction,
+    pub arrival: u64,
+}
+
+// Highest fee first, earlier arrival breaks ties.
+type Priority = (Reverse<u64>, u64, TxHash);
+
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<TxHash, MempoolEntry>,
+    ordering: BTreeSet<Priority>,
+    by_sender_nonce: HashMap<(PublicKey, u64), TxHash>,
+    // Next nonce per sender according to the chain, as last seen by `insert`
+    // or `remove_included`. Tells runnable transactions from future ones.
+    chain_nonces: HashMap<PublicKey, u64>,
+    next_arrival: u64,
+    max_size: usize,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            ordering: BTreeSet::new(),
+            by_sender_nonce: HashMap::new(),
+            chain_nonces: HashMap::new(),
+            next_arrival: 0,
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Next nonce for `sender` once its transactions queued here are included.
+    pub fn next_nonce(&self, sender: &PublicKey, chain_nonce: u64) -> u64 {
+        let mut nonce = chain_nonce;
+        while self.by_sender_nonce.contains_key(&(*sender, nonce)) {
+            nonce += 1;
+        }
+        nonce
+    }
+
+    // Entries in the order a block producer would consider them.
+    pub fn entries(&self) -> Vec<&MempoolEntry> {
+        self.ordering.iter().map(|(_, _, hash)| &self.entries[hash]).collect()
+    }
+
+    // `expected_nonce` is the sender's next unused nonce according to the chain.
+    pub fn insert(&mut self, tx: Transaction, expected_nonce: u64) -> Result<TxHash, String> {
+        let hash = tx.hash();
+        if self.entries.contains_key(&hash) {
+            return Err("Transaction already in mempool".to_string());
+        }
+        tx.verify_signature()?;
+        if tx.nonce < expected_nonce {
+            return Err(format!("Nonce too low: expected at least {}, got {}", expected_nonce, tx.nonce));
+        }
+        if tx.nonce > expected_nonce + MAX_NONCE_GAP {
+            return Err(format!("Nonce too far ahead: expected at most {}, got {}", expected_nonce + MAX_NONCE_GAP, tx.nonce));
+        }
+
+        // Only one transaction per sender and nonce; a higher fee replaces the old one.
+        let sender_nonce = (tx.sender.0, tx.nonce);
+        if let Some(existing) = self.by_sender_nonce.get(&sender_nonce).copied() {
+            if self.entries[&existing].transaction.fee >= tx.fee {
+                return Err("A transaction with this nonce is already pending with an equal or higher fee".to_string());
+            }
+            self.remove(&existing);
+        }
+
+        if self.entries.len() >= self.max_size {
+            // A transaction that cannot run yet never pushes out one that can.
+            let runnable = tx.nonce <= self.next_nonce(&tx.sender.0, expected_nonce);
+            let (victim, victim_runnable) = self.eviction_candidate(&tx).expect("Full mempool has entries");
+            let victim_fee = self.entries[&victim].transaction.fee;
+            if (runnable, tx.fee) <= (victim_runnable, victim_fee) {
+                return Err("Mempool is full and the transaction fee is too low".to_string());
+            }
+            log::info!("Mempool full, evicting transaction {}", victim);
+            self.remove(&victim);
+        }
+
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
+        self.ordering.insert((Reverse(tx.fee), arrival, hash));
+        self.by_sender_nonce.insert(sender_nonce, hash);
+        self.chain_nonces.insert(tx.sender.0, expected_nonce);
+        self.entries.insert(hash, MempoolEntry { hash, transaction: tx, arrival });
+        Ok(hash)
+    }
+
+    // The entry to drop for `incoming` when the pool is full, and whether it
+    // could run. Only a sender's last queued nonce is dropped, so no sender is
+    // left with a gap; among those, the lowest priority one that cannot run
+    // yet goes first. Entries `incoming` would have to follow are kept.
+    fn eviction_candidate(&self, incoming: &Transaction) -> Option<(TxHash, bool)> {
+        self.ordering.iter().rev()
+            .map(|(_, _, hash)| &self.entries[hash].transaction)
+            .filter(|tx| !self.by_sender_nonce.contains_key(&(tx.sender.0, tx.nonce + 1)))
+            .filter(|tx| tx.sender != incoming.sender || tx.nonce > incoming.nonce)
+            .map(|tx| {
+                let chain_nonce = self.chain_nonces.get(&tx.sender.0).copied().unwrap_or(0);
+                (tx.hash(), tx.nonce < self.next_nonce(&tx.sender.0, chain_nonce))
+            })
+            .min_by_key(|(_, runnable)| *runnable)
+    }
+
+    pub fn get(&self, hash: &TxHash) -> Option<&Transaction> {
+        self.entries.get(hash).map(|entry| &entry.transaction)
+    }
+
+    pub fn remove(&mut self, hash: &TxHash) -> Option<Transaction> {
+        let entry = self.entries.remove(hash)?;
+        self.ordering.remove(&(Reverse(entry.transaction.fee), entry.arrival, entry.hash));
+        self.by_sender_nonce.remove(&(entry.transaction.sender.0, entry.transaction.nonce));
+        Some(entry.transaction)
+    }
+
+    // Picks up to `max` transactions asking for at most `max_gas` in total by
+    // priority while keeping each sender's nonces gap-free, starting from the
+    // chain's next nonce for that sender. A transaction that does not fit
+    // holds back its sender's later ones.
+    pub fn select(&self, max: usize, max_gas: u64, chain_nonces: &HashMap<PublicKey, u64>) -> Vec<Transaction> {
+        let mut next_nonces: HashMap<PublicKey, u64> = HashMap::new();
+        let mut selected = Vec::new();
+        let mut gas = 0;
+        let mut taken: BTreeSet<TxHash> = BTreeSet::new();
+
+        loop {
+            let mut progressed = false;
+            for (_, _, hash) in &self.ordering {
+                if selected.len() >= max {
+                    return selected;
+                }
+                if taken.contains(hash) {
+                    continue;
+                }
+                let tx = &self.entries[hash].transaction;
+                let next = next_nonces
+                    .entry(tx.sender.0)
+                    .or_insert_with(|| chain_nonces.get(&tx.sender.0).copied().unwrap_or(0));
+                if tx.nonce == *next && gas + tx.gas_limit() <= max_gas {
+                    gas += tx.gas_limit();
+                    *next += 1;
+                    taken.insert(*hash);
+                    selected.push(tx.clone());
+                    progressed = true;
+                }
+            }
+            if !progressed {
+                return selected;
+            }
+        }
+    }
+
+    // Drops transactions that were included in a block, plus any whose nonce
+    // has been used up by the chain in the meantime.
+    pub fn remove_included(&mut self, included: &[Transaction], chain_nonces: &HashMap<PublicKey, u64>) {
+        for tx in included {
+            self.remove(&tx.hash());
+        }
+        let stale: Vec<TxHash> = self.entries.values()
+            .filter(|entry| {
+                let next = chain_nonces.get(&entry.transaction.sender.0).copied().unwrap_or(0);
+                entry.transaction.nonce < next
+            })
+            .map(|entry| entry.hash)
+            .collect();
+        for hash in stale {
+            self.remove(&hash);
+        }
+        self.chain_nonces = self.entries.values()
+            .map(|entry| entry.transaction.sender.0)
+            .map(|sender| (sender, chain_nonces.get(&sender).copied().unwrap_or(0)))
+            .collect();
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MAX_TRANSACTIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Secp256k1, SecretKey};
+    use crate::smart_contract::{ContractAction, ContractRequest};
+    use crate::transaction::TransactionPayload;
+
+    fn sender(seed: u8) -> (SecretKey, PublicKey) {
+        let secret_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+        (secret_key, PublicKey::from_secret_key(&Secp256k1::new(), &secret_key))
+    }
+
+    fn call(seed: u8, nonce: u64, fee: u64, gas_limit: u64) -> Transaction {
+        let (secret_key, public_key) = sender(seed);
+        let payload = TransactionPayload::Contract(ContractRequest {
+            chain_id: "test".to_string(),
+            contract_id: "counter".to_string(),
+            action: ContractAction::Execute { input: String::new(), gas_limit },
+        });
+        let mut tx = Transaction::new(public_key, nonce, fee, payload);
+        tx.sign(&secret_key);
+        tx
+    }
+
+    #[test]
+    fn nonces_may_run_at_most_the_gap_ahead() {
+        let mut mempool = Mempool::default();
+        assert!(mempool.insert(call(1, 4, 1, 0), 5).is_err());
+        assert!(mempool.insert(call(1, 5 + MAX_NONCE_GAP + 1, 1, 0), 5).is_err());
+        assert!(mempool.insert(call(1, 5 + MAX_NONCE_GAP, 1, 0), 5).is_ok());
+        assert!(mempool.insert(call(1, 5, 1, 0), 5).is_ok());
+        assert_eq!(mempool.next_nonce(&sender(1).1, 5), 6);
+    }
+
+    #[test]
+    fn same_nonce_is_only_replaced_by_a_higher_fee() {
+        let mut mempool = Mempool::default();
+        let original = mempool.insert(call(1, 0, 10, 0), 0).unwrap();
+        assert!(mempool.insert(call(1, 0, 10, 1), 0).is_err());
+        assert!(mempool.insert(call(1, 0, 9, 1), 0).is_err());
+        let replacement = mempool.insert(call(1, 0, 11, 0), 0).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&original).is_none());
+        assert!(mempool.get(&replacement).is_some());
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_lowest_fee() {
+        let mut mempool = Mempool::new(2);
+        let low = mempool.insert(call(1, 0, 1, 0), 0).unwrap();
+        let high = mempool.insert(call(2, 0, 5, 0), 0).unwrap();
+        assert!(mempool.insert(call(3, 0, 1, 0), 0).is_err());
+        let newer = mempool.insert(call(3, 0, 2, 0), 0).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.get(&low).is_none());
+        assert!(mempool.get(&high).is_some());
+        assert!(mempool.get(&newer).is_some());
+    }
+
+    #[test]
+    fn full_mempool_evicts_future_nonces_before_runnable_ones() {
+        let mut mempool = Mempool::new(3);
+        // Sender 1 never sends nonce 0, so its high fees buy it nothing.
+        let future = mempool.insert(call(1, 1, 100, 0), 0).unwrap();
+        let later = mempool.insert(call(1, 2, 100, 0), 0).unwrap();
+        let runnable = mempool.insert(call(2, 0, 1, 0), 0).unwrap();
+        let newer = mempool.insert(call(3, 0, 2, 0), 0).unwrap();
+        assert!(mempool.get(&later).is_none());
+        assert!(mempool.get(&future).is_some());
+        assert!(mempool.get(&runnable).is_some());
+        assert!(mempool.get(&newer).is_some());
+
+        // Once only runnable transactions are left, no fee gets a future one in.
+        mempool.insert(call(4, 0, 3, 0), 0).unwrap();
+        assert!(mempool.get(&future).is_none());
+        assert!(mempool.insert(call(5, 1, 1_000, 0), 0).is_err());
+        assert!(mempool.insert(call(5, 0, 4, 0), 0).is_ok());
+    }
+
+    #[test]
+    fn full_mempool_evicts_a_senders_last_nonce() {
+        let mut mempool = Mempool::new(3);
+        let first = mempool.insert(call(1, 0, 50, 0), 0).unwrap();
+        let cheap = mempool.insert(call(1, 1, 1, 0), 0).unwrap();
+        let last = mempool.insert(call(1, 2, 50, 0), 0).unwrap();
+        // Dropping the cheap middle nonce would strand the last one.
+        assert!(mempool.insert(call(2, 0, 10, 0), 0).is_err());
+        let newer = mempool.insert(call(2, 0, 60, 0), 0).unwrap();
+        assert!(mempool.get(&last).is_none());
+        assert!(mempool.get(&first).is_some() && mempool.get(&cheap).is_some() && mempool.get(&newer).is_some());
+    }
+
+    #[test]
+    fn select_keeps_nonce_order_and_the_gas_limit() {
+        let mut mempool = Mempool::default();
+        // The later nonce pays more but still has to wait for the earlier one.
+        mempool.insert(call(1, 1, 50, 10), 0).unwrap();
+        mempool.insert(call(1, 0, 1, 10), 0).unwrap();
+        mempool.insert(call(2, 0, 20, 30), 0).unwrap();
+        // Sender 3 starts at nonce 2 on chain, so nonce 3 must wait for nonce 2.
+        mempool.insert(call(3, 3, 100, 0), 2).unwrap();
+
+        let selected = mempool.select(10, 40, &HashMap::from([(sender(3).1, 2)]));
+        let picked: Vec<(PublicKey, u64)> = selected.iter().map(|tx| (tx.sender.0, tx.nonce)).collect();
+        // Sender 2 takes 30 gas first; sender 1's nonce 0 fits in the last 10
+        // and its nonce 1 no longer does.
+        assert_eq!(picked, vec![(sender(2).1, 0), (sender(1).1, 0)]);
+
+        let selected = mempool.select(10, 100, &HashMap::new());
+        let picked: Vec<(PublicKey, u64)> = selected.iter().map(|tx| (tx.sender.0, tx.nonce)).collect();
+        assert_eq!(picked, vec![(sender(2).1, 0), (sender(1).1, 0), (sender(1).1, 1)]);
+        assert_eq!(mempool.select(1, 100, &HashMap::new()).len(), 1);
+    }
+
+    #[test]
+    fn remove_included_drops_included_and_stale_transactions() {
+        let mut mempool = Mempool::default();
+        let included = call(1, 0, 1, 0);
+        mempool.insert(included.clone(), 0).unwrap();
+        let stale = mempool.insert(call(2, 0, 1, 0), 0).unwrap();
+        let pending = mempool.insert(call(2, 1, 1, 0), 0).unwrap();
+
+        mempool.remove_included(&[included], &HashMap::from([(sender(1).1, 1), (sender(2).1, 1)]));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&stale).is_none());
+        assert!(mempool.get(&pending).is_some());
+    }
+}
@@ -0,0 +1,184 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::block::BlockHash;
+
+// Leaves and inner nodes are hashed with different prefixes so an inner node
+// can never be passed off as a transaction hash.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub hash: BlockHash,
+    // True when the sibling sits on the left of the node being proven.
+    pub is_left: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    // Walks the levels `merkle_proof` builds for `leaf_count` leaves, so the
+    // side of each sibling follows from `leaf_index` and the proof only holds
+    // for the position it claims. A carried-up odd node has no step.
+    pub fn verify(&self, leaf: &BlockHash, root: &BlockHash) -> bool {
+        if self.leaf_index >= self.leaf_count {
+            return false;
+        }
+        let mut steps = self.steps.iter();
+        let mut computed = hash_leaf(leaf);
+        let mut position = self.leaf_index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            let sibling = position ^ 1;
+            if sibling < width {
+                let step = match steps.next() {
+                    Some(step) if step.is_left == (sibling < position) => step,
+                    _ => return false,
+                };
+                computed = if step.is_left {
+                    hash_nodes(&step.hash, &computed)
+                } else {
+                    hash_nodes(&computed, &step.hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        steps.next().is_none() && computed == *root
+    }
+}
+
+// Root of an empty list is the zero hash. An unpaired node at the end of a
+// level is carried up unchanged rather than duplicated.
+pub fn merkle_root(leaves: &[BlockHash]) -> BlockHash {
+    if leaves.is_empty() {
+        return BlockHash::ZERO;
+    }
+    let mut level: Vec<BlockHash> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+pub fn merkle_proof(leaves: &[BlockHash], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level: Vec<BlockHash> = leaves.iter().map(hash_leaf).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            steps.push(ProofStep { hash: level[sibling], is_left: sibling < position });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof { leaf_index: index, leaf_count: leaves.len(), steps })
+}
+
+fn next_level(level: &[BlockHash]) -> Vec<BlockHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_nodes(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn hash_leaf(leaf: &BlockHash) -> BlockHash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf.as_bytes());
+    BlockHash(hasher.finalize().into())
+}
+
+fn hash_nodes(left: &BlockHash, right: &BlockHash) -> BlockHash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    BlockHash(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<BlockHash> {
+        (0..count).map(|i| BlockHash(Sha256::digest(&[i as u8]).into())).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for count in [1, 2, 3, 5, 8] {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert!(proof.verify(leaf, &root), "leaf {} of {}", index, count);
+            }
+            assert!(merkle_proof(&leaves, count).is_none());
+        }
+    }
+
+    #[test]
+    fn proof_only_holds_for_its_own_index() {
+        for count in [2, 3, 5, 8] {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for index in 0..count {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                for other in (0..count + 1).filter(|other| *other != index) {
+                    let moved = MerkleProof { leaf_index: other, ..proof.clone() };
+                    assert!(!moved.verify(&leaves[index], &root), "leaf {} of {} passed as {}", index, count, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_root_or_leaf_is_rejected() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        assert!(!proof.verify(&leaves[2], &merkle_root(&leaves[..4])));
+        assert!(!proof.verify(&leaves[3], &root));
+    }
+
+    #[test]
+    fn tampered_steps_are_rejected() {
+        let leaves = leaves(8);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 5).unwrap();
+
+        let mut hash = proof.clone();
+        hash.steps[1].hash = leaves[0];
+        assert!(!hash.verify(&leaves[5], &root));
+
+        let mut side = proof.clone();
+        side.steps[0].is_left = !side.steps[0].is_left;
+        assert!(!side.verify(&leaves[5], &root));
+
+        let mut short = proof.clone();
+        short.steps.pop();
+        assert!(!short.verify(&leaves[5], &root));
+
+        let mut long = proof.clone();
+        long.steps.push(ProofStep { hash: root, is_left: false });
+        assert!(!long.verify(&leaves[5], &root));
+
+        let mut count = proof;
+        count.leaf_count = 16;
+        assert!(!count.verify(&leaves[5], &root));
+    }
+}
@@ -1,82 +1,278 @@
 use crate::block::Block;
-use crate::Blockchain;
+use crate::finality::Precommit;
+use crate::gossip::{Gossip, Session};
+use crate::protocol::{Codec, InventoryItem, Message, MAX_HEADERS_PER_REQUEST};
+use crate::handshake::{self, LocalChain, NodeIdentity, PeerInfo};
+use crate::peer_manager::{Offence, PeerManager};
+use crate::snapshot::{StateSnapshot, MAX_SNAPSHOT_SIZE, SNAPSHOT_CHUNK_SIZE};
+use crate::sync::{self, ChainSync};
+use crate::transaction::Transaction;
+use crate::transport::SecureStream;
+use crate::blockchain::Blockchain;
+use std::future::Future;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
-use serde_json::to_string;
 use tokio::sync::Mutex;
 use std::sync::Arc;
-use secp256k1::{Secp256k1, SecretKey, PublicKey, All};
-
-pub async fn broadcast_new_block(block: &Block, peer_addresses: Vec<String>) {
-    for peer_address in peer_addresses {
-        if let Ok(mut stream) = TcpStream::connect(peer_address).await {
-            if let Ok(block_data) = to_string(block) {
-                let _ = stream.write_all(block_data.as_bytes()).await;
-                // You might want to handle errors here
+
+// How long a peer gets to accept a connection or answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the peer manager tops up outbound connections.
+const PEER_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+// How long a short-lived command stays connected so peers can fetch what it
+// announced.
+const ANNOUNCE_LINGER: Duration = Duration::from_secs(3);
+
+// The state every peer connection works with. Locks are always taken in the
+// order blockchain, peers, gossip, sync.
+#[derive(Clone)]
+pub struct Network {
+    pub identity: NodeIdentity,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub peers: Arc<Mutex<PeerManager>>,
+    pub gossip: Arc<Mutex<Gossip>>,
+    pub sync: Arc<Mutex<ChainSync>>,
+}
+
+impl Network {
+    pub fn new(identity: NodeIdentity, blockchain: Arc<Mutex<Blockchain>>, peers: Arc<Mutex<PeerManager>>) -> Self {
+        Network {
+            identity,
+            blockchain,
+            peers,
+            gossip: Arc::new(Mutex::new(Gossip::new())),
+            sync: Arc::new(Mutex::new(ChainSync::new())),
+        }
+    }
+
+    pub async fn announce_block(&self, block: &Block) {
+        self.gossip.lock().await.announce(InventoryItem::Block(block.hash), None);
+    }
+
+    pub async fn announce_transaction(&self, tx: &Transaction) {
+        self.gossip.lock().await.announce(InventoryItem::Transaction(tx.hash()), None);
+    }
+
+    pub async fn broadcast_precommit(&self, precommit: &Precommit) {
+        self.gossip.lock().await.relay_precommit(precommit, None);
+    }
+
+    // Gives connected peers time to request what we just announced before a
+    // one-shot command exits.
+    pub async fn linger(&self) {
+        if self.gossip.lock().await.connection_count() > 0 {
+            tokio::time::sleep(ANNOUNCE_LINGER).await;
+        }
+    }
+}
+
+// Why a session with a peer ended early; decides how the peer is scored.
+enum SessionError {
+    Unreachable(String),
+    Timeout(String),
+    Misbehaved(Offence, String),
+}
+
+impl SessionError {
+    fn message(&self) -> &str {
+        match self {
+            SessionError::Unreachable(e) | SessionError::Timeout(e) | SessionError::Misbehaved(_, e) => e,
+        }
+    }
+}
+
+async fn with_timeout<T>(what: &str, future: impl Future<Output = Result<T, String>>) -> Result<T, SessionError> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, future).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(SessionError::Unreachable(format!("{}: {}", what, e))),
+        Err(_) => Err(SessionError::Timeout(format!("{} timed out", what))),
+    }
+}
+
+// Connects and authenticates, dropping addresses that turn out to be us.
+async fn open_session(address: &str, network: &Network) -> Result<(SecureStream<TcpStream>, PeerInfo), SessionError> {
+    let codec = Codec::default();
+    let local = LocalChain::of(&*network.blockchain.lock().await);
+    let stream = with_timeout("Connect", async { TcpStream::connect(address).await.map_err(|e| e.to_string()) }).await?;
+    let (stream, peer) = with_timeout("Handshake", handshake::initiate(stream, &codec, &network.identity, &local)).await?;
+    if peer.public_key == network.identity.public_key {
+        network.peers.lock().await.mark_own_address(address);
+        return Err(SessionError::Unreachable("Connected to ourselves".to_string()));
+    }
+    Ok((stream, peer))
+}
+
+// Updates the peer's record with how a session ended.
+async fn record_outcome<T>(peers: &Arc<Mutex<PeerManager>>, address: &str, node_id: Option<&str>, result: &Result<T, SessionError>) {
+    let mut peers = peers.lock().await;
+    match result {
+        Ok(_) => {
+            if let Some(node_id) = node_id {
+                peers.record_success(address, node_id);
             }
         }
+        Err(SessionError::Unreachable(_)) => peers.record_failure(address),
+        Err(SessionError::Timeout(_)) => {
+            peers.record_failure(address);
+            peers.penalize(address, Offence::Timeout);
+        }
+        Err(SessionError::Misbehaved(offence, _)) => peers.penalize(address, *offence),
     }
 }
 
-pub async fn try_connect_and_sync(peer_address: &str, blockchain: &Arc<Mutex<Blockchain>>) -> Result<Blockchain, String> {
-    let retry_limit = 3;
-    for attempt in 1..=retry_limit {
-        log::info!("Attempting to connect to peer at {}, attempt {}", peer_address, attempt);
-        match TcpStream::connect(peer_address).await {
-            Ok(mut stream) => {
-                log::info!("Connected to peer at {}", peer_address);
-                // Simulate sending a blockchain request
-                if let Err(e) = stream.write_all(b"request_blockchain").await {
-                    log::warn!("Failed to send request to {}: {}", peer_address, e);
-                    continue; // Try connecting again
+// Connects to a peer and asks it for more peer addresses, then keeps the
+// connection open for gossip and sync.
+pub async fn connect_peer(address: &str, network: &Network) -> Result<(), String> {
+    if network.gossip.lock().await.is_connected(address) {
+        return Err(format!("Already connected to {}", address));
+    }
+    if !network.peers.lock().await.try_connect_outbound(address) {
+        return Err(format!("No outbound slot for {}", address));
+    }
+    let result = match open_session(address, network).await {
+        Ok((mut stream, peer)) => exchange_peers(&mut stream, &network.peers).await.map(|()| (stream, peer)),
+        Err(e) => Err(e),
+    };
+    let node_id = result.as_ref().ok().map(|(_, peer)| peer.node_id.as_str());
+    record_outcome(&network.peers, address, node_id, &result).await;
+
+    let opened = match result {
+        Ok((stream, peer)) => Session::open(network, peer, address.to_string()).await.map(|session| (stream, session)),
+        Err(e) => Err(e.message().to_string()),
+    };
+    match opened {
+        Ok((stream, session)) => {
+            let (address, network) = (address.to_string(), network.clone());
+            tokio::spawn(async move {
+                if let Err(e) = session.run(stream, network.clone()).await {
+                    log::info!("Session with {} ended: {}", address, e);
                 }
-                // Assume receiving and processing blockchain data
-                return Ok(Blockchain::new_empty()); // Placeholder for actual received blockchain
-            },
-            Err(e) => {
-                log::error!("Connection attempt {} failed: {}", attempt, e);
-                if attempt == retry_limit {
-                    return Err(format!("Failed to connect to peer at {} after {} attempts", peer_address, retry_limit));
+                network.peers.lock().await.disconnected_outbound(&address);
+            });
+            Ok(())
+        }
+        Err(e) => {
+            network.peers.lock().await.disconnected_outbound(address);
+            Err(e)
+        }
+    }
+}
+
+async fn exchange_peers(stream: &mut SecureStream<TcpStream>, peers: &Arc<Mutex<PeerManager>>) -> Result<(), SessionError> {
+    let codec = Codec::default();
+    match with_timeout("Peer request", codec.request(stream, &Message::GetPeers)).await? {
+        Message::Peers(addresses) => {
+            let mut peers = peers.lock().await;
+            let added = addresses.iter().filter(|address| peers.add_address(address)).count();
+            if added > 0 {
+                log::info!("Learned {} new peer addresses", added);
+            }
+            Ok(())
+        }
+        other => Err(SessionError::Misbehaved(Offence::ProtocolViolation, format!("Expected peers, got {:?}", other))),
+    }
+}
+
+// Fetches a peer's state snapshot when it is at least a snapshot interval
+// ahead of us, so a new node does not have to replay the whole chain. The
+// snapshot is checked against the headers leading up to it, see
+// `Blockchain::verify_snapshot`; the blocks after it sync as usual.
+async fn download_snapshot(address: &str, network: &Network) -> Result<bool, SessionError> {
+    let codec = Codec::default();
+    let (mut stream, _) = open_session(address, network).await?;
+    let (tip, interval) = {
+        let blockchain = network.blockchain.lock().await;
+        (sync::best_height(&blockchain), blockchain.snapshot_interval.max(1))
+    };
+    let misbehaved = |e: String| SessionError::Misbehaved(Offence::ProtocolViolation, e);
+
+    let manifest = match with_timeout("Snapshot request", codec.request(&mut stream, &Message::GetSnapshotInfo)).await? {
+        Message::SnapshotInfo(Some(manifest)) if manifest.height >= tip + interval => manifest,
+        Message::SnapshotInfo(_) => return Ok(false),
+        other => return Err(misbehaved(format!("Expected snapshot info, got {:?}", other))),
+    };
+    let expected_chunks = (manifest.size as usize).div_ceil(SNAPSHOT_CHUNK_SIZE) as u32;
+    if manifest.size > MAX_SNAPSHOT_SIZE || manifest.chunk_count != expected_chunks {
+        return Err(misbehaved(format!("Invalid snapshot manifest {:?}", manifest)));
+    }
+    log::info!("Downloading state snapshot at height {} ({} bytes) from {}", manifest.height, manifest.size, address);
+
+    // Headers up to the block after the snapshot, which commits to its state.
+    let mut headers = Vec::new();
+    let mut from_height = tip + 1;
+    while from_height <= manifest.height + 1 {
+        let max = (manifest.height + 2 - from_height).min(MAX_HEADERS_PER_REQUEST as u64) as u32;
+        match with_timeout("Header request", codec.request(&mut stream, &Message::GetHeaders { from_height, max })).await? {
+            Message::Headers(batch) if !batch.is_empty() && batch.len() <= max as usize => {
+                from_height += batch.len() as u64;
+                headers.extend(batch);
+            }
+            other => return Err(misbehaved(format!("Expected up to {} headers, got {:?}", max, other))),
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(manifest.size as usize);
+    for index in 0..manifest.chunk_count {
+        let request = Message::GetSnapshotChunk { height: manifest.height, index };
+        match with_timeout("Snapshot chunk request", codec.request(&mut stream, &request)).await? {
+            Message::SnapshotChunk { height, index: got, data } if height == manifest.height && got == index => {
+                if data.is_empty() {
+                    return Err(SessionError::Unreachable("Snapshot is no longer available".to_string()));
+                }
+                bytes.extend(data);
+                if bytes.len() as u64 > manifest.size {
+                    return Err(misbehaved("Snapshot is larger than announced".to_string()));
                 }
             }
+            other => return Err(misbehaved(format!("Expected snapshot chunk {}, got {:?}", index, other))),
         }
     }
-    Err(format!("Failed to connect to peer at {}", peer_address))
+    if bytes.len() as u64 != manifest.size {
+        return Err(misbehaved("Snapshot is smaller than announced".to_string()));
+    }
+    let snapshot = StateSnapshot::from_bytes(&bytes).map_err(misbehaved)?;
+
+    let mut blockchain = network.blockchain.lock().await;
+    blockchain.verify_snapshot(&snapshot, &headers)
+        .map_err(|e| SessionError::Misbehaved(Offence::InvalidBlock, e))?;
+    // The block after the snapshot is synced with its body.
+    headers.pop();
+    blockchain.install_snapshot(snapshot, headers).map_err(SessionError::Unreachable)?;
+    log::info!("Restored state snapshot at height {}", manifest.height);
+    Ok(true)
 }
 
-pub async fn synchronize_with_peers(
-    node_id: &str,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    peer_addresses: &[String],
-) -> Result<(), String> {
-    for peer_address in peer_addresses {
-        match try_connect_and_sync(peer_address, blockchain).await {
-            Ok(peer_blockchain) => {
-                let mut blockchain = blockchain.lock().await;
-                blockchain.synchronize_from_peer(peer_blockchain);
-                log::info!("Synchronized with peer at {}", peer_address);
-            },
-            Err(e) => return Err(e),
+// Tries peers until one provides a snapshot ahead of us.
+async fn bootstrap_from_snapshot(network: &Network) {
+    if network.blockchain.lock().await.blocks.is_empty() {
+        return;
+    }
+    let candidates = network.peers.lock().await.candidates();
+    for address in candidates {
+        let result = download_snapshot(&address, network).await;
+        record_outcome(&network.peers, &address, None, &result).await;
+        match result {
+            Ok(true) => return,
+            Ok(false) => {}
+            // Connecting below reports peers that are down.
+            Err(SessionError::Unreachable(e)) => log::debug!("Snapshot download from {} failed: {}", address, e),
+            Err(e) => log::warn!("Snapshot download from {} failed: {}", address, e.message()),
         }
     }
-    Ok(())
 }
 
-pub async fn synchronize_or_initialize(
-    node_id: &str, 
-    blockchain: &Arc<Mutex<Blockchain>>, 
-    peer_addresses: &[String], 
-    secret_key: &SecretKey, 
-    public_key: PublicKey
-) -> Result<(), String> {
-    // Attempt to synchronize with each peer address provided.
-    let mut any_successful = false; // Flag to track if any synchronization was successful
-
-    for address in peer_addresses {
-        match try_connect_and_sync(address, blockchain).await {
-            Ok(_) => {
-                println!("Synchronized with node at {}", address);
-                any_successful = true; // Mark as successful if any synchronization succeeds
+pub async fn synchronize_or_initialize(network: &Network) -> Result<(), String> {
+    // Attempt to connect to each peer the address book offers.
+    let mut any_successful = false; // Flag to track if any connection was successful
+    let start_height = sync::best_height(&*network.blockchain.lock().await);
+    bootstrap_from_snapshot(network).await;
+
+    let candidates = network.peers.lock().await.candidates();
+    for address in candidates {
+        match connect_peer(&address, network).await {
+            Ok(()) => {
+                println!("Connected to node at {}", address);
+                any_successful = true; // Mark as successful if any connection succeeds
             },
             Err(e) => {
                 println!("Failed to connect or sync with node at {}: {}", address, e);
@@ -87,11 +283,34 @@ pub async fn synchronize_or_initialize(
     // Check if any synchronization was successful
     if !any_successful {
         // If no synchronization was successful and the blockchain is still empty, log the condition
-        if blockchain.lock().await.blocks.is_empty() {
+        if network.blockchain.lock().await.blocks.is_empty() {
             log::info!("No successful synchronization and blockchain is empty.");
         }
         return Err("Failed to synchronize with any peers.".to_string());
     }
 
+    sync::catch_up(network).await;
+    let imported = sync::best_height(&*network.blockchain.lock().await).saturating_sub(start_height);
+    println!("Synchronized with peers, imported {} blocks", imported);
     Ok(())
 }
+
+// Periodically dials the best candidates from the address book, up to the
+// outbound limit. Failed peers come back once their backoff has passed.
+pub async fn run_peer_manager(network: Network) {
+    loop {
+        tokio::time::sleep(PEER_MAINTENANCE_INTERVAL).await;
+        let candidates = network.peers.lock().await.candidates();
+        for address in candidates {
+            if network.gossip.lock().await.is_connected(&address) {
+                continue;
+            }
+            let network = network.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connect_peer(&address, &network).await {
+                    log::debug!("Connecting to {} failed: {}", address, e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use crate::poa::now_millis;
+use crate::protocol::Message;
+use crate::storage::ChainStore;
+
+pub const DEFAULT_MAX_INBOUND: usize = 32;
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+// Addresses handed out per peer-list request, and kept in the book at most.
+pub const MAX_SHARED_ADDRESSES: usize = 100;
+const MAX_ADDRESS_BOOK: usize = 1000;
+const INITIAL_BACKOFF_MS: u128 = 1_000;
+const MAX_BACKOFF_MS: u128 = 5 * 60 * 1000;
+const MAX_SCORE: i32 = 100;
+// A peer whose score drops to this is banned for `BAN_DURATION_MS`.
+const BAN_THRESHOLD: i32 = -100;
+const BAN_DURATION_MS: u128 = 60 * 60 * 1000;
+
+// Something a peer did that costs it score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    // A block that failed validation.
+    InvalidBlock,
+    // A transaction or vote we could not accept.
+    InvalidMessage,
+    // A message that breaks the protocol, like an unsolicited response.
+    ProtocolViolation,
+    // No answer within the request timeout.
+    Timeout,
+}
+
+impl Offence {
+    // The offence a rejected message counts as.
+    pub fn for_message(message: &Message) -> Self {
+        match message {
+            Message::NewBlock(_) | Message::Headers(_) | Message::Blocks(_) => Offence::InvalidBlock,
+            Message::NewTransaction(_) | Message::Precommit(_) => Offence::InvalidMessage,
+            _ => Offence::ProtocolViolation,
+        }
+    }
+
+    fn penalty(self) -> i32 {
+        match self {
+            Offence::InvalidBlock => 25,
+            Offence::InvalidMessage => 5,
+            Offence::ProtocolViolation => 50,
+            Offence::Timeout => 10,
+        }
+    }
+}
+
+// What we know about one peer address. Persisted across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerEntry {
+    pub address: String,
+    pub node_id: Option<String>,
+    pub score: i32,
+    // Consecutive failed connection attempts, driving the backoff.
+    pub failures: u32,
+    pub next_attempt_ms: u128,
+    pub banned_until_ms: u128,
+    pub last_seen_ms: u128,
+}
+
+impl PeerEntry {
+    fn new(address: String) -> Self {
+        PeerEntry {
+            address,
+            node_id: None,
+            score: 0,
+            failures: 0,
+            next_attempt_ms: 0,
+            banned_until_ms: 0,
+            last_seen_ms: 0,
+        }
+    }
+
+    fn is_banned(&self, now: u128) -> bool {
+        self.banned_until_ms > now
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// A peer entry as reported by the API.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerStatus {
+    #[serde(flatten)]
+    pub entry: PeerEntry,
+    pub connected: Option<Direction>,
+    pub banned: bool,
+}
+
+// Keeps the address book, the set of open connections and every peer's
+// score. Addresses come from `PEER_ADDRESSES`, from peers announcing their
+// listening port in the handshake and from peer-list exchanges.
+#[derive(Debug)]
+pub struct PeerManager {
+    entries: HashMap<String, PeerEntry>,
+    inbound: HashMap<SocketAddr, String>,
+    outbound: HashSet<String>,
+    // Addresses that turned out to be this node; never dialled again.
+    own_addresses: HashSet<String>,
+    max_inbound: usize,
+    max_outbound: usize,
+    store: Arc<dyn ChainStore>,
+}
+
+impl PeerManager {
+    // Loads the address book from `store`, which keeps every change to it.
+    pub fn new(store: Arc<dyn ChainStore>) -> Self {
+        let entries = match store.peers() {
+            Ok(peers) => peers.into_iter().map(|entry| (entry.address.clone(), entry)).collect(),
+            Err(e) => {
+                log::warn!("Failed to load the address book: {}", e);
+                HashMap::new()
+            }
+        };
+        PeerManager {
+            entries,
+            inbound: HashMap::new(),
+            outbound: HashSet::new(),
+            own_addresses: HashSet::new(),
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            store,
+        }
+    }
+
+    pub fn with_limits(mut self, max_inbound: usize, max_outbound: usize) -> Self {
+        self.max_inbound = max_inbound;
+        self.max_outbound = max_outbound;
+        self
+    }
+
+    fn save(&self, address: &str) {
+        if let Some(entry) = self.entries.get(address) {
+            if let Err(e) = self.store.put_peer(entry) {
+                log::warn!("Failed to save peer {}: {}", address, e);
+            }
+        }
+    }
+
+    fn entry(&mut self, address: &str) -> &mut PeerEntry {
+        self.entries.entry(address.to_string()).or_insert_with(|| PeerEntry::new(address.to_string()))
+    }
+
+    // Adds an address to the book unless it is malformed, already known or
+    // the book is full. Returns whether it was added.
+    pub fn add_address(&mut self, address: &str) -> bool {
+        if address.parse::<SocketAddr>().is_err()
+            || self.entries.contains_key(address)
+            || self.own_addresses.contains(address)
+            || self.entries.len() >= MAX_ADDRESS_BOOK {
+            return false;
+        }
+        self.entry(address);
+        self.save(address);
+        true
+    }
+
+    // Drops an address that turned out to be ourselves and ignores it when
+    // peers share it again.
+    pub fn mark_own_address(&mut self, address: &str) {
+        self.own_addresses.insert(address.to_string());
+        if self.entries.remove(address).is_some() {
+            if let Err(e) = self.store.remove_peer(address) {
+                log::warn!("Failed to remove peer {}: {}", address, e);
+            }
+        }
+    }
+
+    // Addresses worth dialling now, best-scored first, limited to the free
+    // outbound slots.
+    pub fn candidates(&self) -> Vec<String> {
+        let now = now_millis();
+        let free = self.max_outbound.saturating_sub(self.outbound.len());
+        let mut candidates: Vec<&PeerEntry> = self.entries.values()
+            .filter(|entry| !entry.is_banned(now) && entry.next_attempt_ms <= now && !self.outbound.contains(&entry.address))
+            .collect();
+        candidates.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.address.cmp(&b.address)));
+        candidates.into_iter().take(free).map(|entry| entry.address.clone()).collect()
+    }
+
+    // Addresses we have reached before and that are not banned; what we share
+    // with other peers.
+    pub fn known_good(&self) -> Vec<String> {
+        let now = now_millis();
+        let mut entries: Vec<&PeerEntry> = self.entries.values()
+            .filter(|entry| entry.last_seen_ms > 0 && !entry.is_banned(now))
+            .collect();
+        entries.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.address.cmp(&b.address)));
+        entries.into_iter().take(MAX_SHARED_ADDRESSES).map(|entry| entry.address.clone()).collect()
+    }
+
+    // Reserves an outbound slot for `address`.
+    pub fn try_connect_outbound(&mut self, address: &str) -> bool {
+        if self.outbound.len() >= self.max_outbound || self.is_banned(address) {
+            return false;
+        }
+        self.outbound.insert(address.to_string())
+    }
+
+    pub fn disconnected_outbound(&mut self, address: &str) {
+        self.outbound.remove(address);
+    }
+
+    // Admits an inbound connection unless its IP is banned or we are full.
+    pub fn try_accept_inbound(&mut self, remote: SocketAddr) -> Result<(), String> {
+        if self.is_ip_banned(remote.ip()) {
+            return Err(format!("{} is banned", remote.ip()));
+        }
+        if self.inbound.len() >= self.max_inbound {
+            return Err(format!("Inbound connection limit of {} reached", self.max_inbound));
+        }
+        self.inbound.insert(remote, remote.to_string());
+        Ok(())
+    }
+
+    // Ties an inbound connection to the address its peer listens on, which
+    // is what scores and bans are recorded against.
+    pub fn identify_inbound(&mut self, remote: SocketAddr, listen_port: Option<u16>) -> String {
+        let address = match listen_port {
+            Some(port) => {
+                let address = SocketAddr::new(remote.ip(), port).to_string();
+                self.add_address(&address);
+                address
+            }
+            None => remote.to_string(),
+        };
+        self.inbound.insert(remote, address.clone());
+        address
+    }
+
+    pub fn disconnected_inbound(&mut self, remote: SocketAddr) {
+        self.inbound.remove(&remote);
+    }
+
+    pub fn record_success(&mut self, address: &str, node_id: &str) {
+        let entry = self.entry(address);
+        entry.node_id = Some(node_id.to_string());
+        entry.failures = 0;
+        entry.next_attempt_ms = 0;
+        entry.last_seen_ms = now_millis();
+        entry.score = (entry.score + 1).min(MAX_SCORE);
+        self.save(address);
+    }
+
+    // Backs off exponentially from the last attempt.
+    pub fn record_failure(&mut self, address: &str) {
+        if self.own_addresses.contains(address) {
+            return;
+        }
+        let entry = self.entry(address);
+        entry.failures = entry.failures.saturating_add(1);
+        let backoff = INITIAL_BACKOFF_MS.saturating_mul(1u128 << (entry.failures - 1).min(20)).min(MAX_BACKOFF_MS);
+        entry.next_attempt_ms = now_millis() + backoff;
+        self.save(address);
+    }
+
+    pub fn penalize(&mut self, address: &str, offence: Offence) {
+        if self.own_addresses.contains(address) {
+            return;
+        }
+        let entry = self.entry(address);
+        entry.score -= offence.penalty();
+        if entry.score <= BAN_THRESHOLD {
+            entry.banned_until_ms = now_millis() + BAN_DURATION_MS;
+            entry.score = 0;
+            log::warn!("Banned peer {} for {:?} until {}", address, offence, entry.banned_until_ms);
+        } else {
+            log::info!("Peer {} penalized for {:?}, score {}", address, offence, entry.score);
+        }
+        self.save(address);
+    }
+
+    pub fn is_banned(&self, address: &str) -> bool {
+        self.entries.get(address).is_some_and(|entry| entry.is_banned(now_millis()))
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        let now = now_millis();
+        self.entries.values().any(|entry| {
+            entry.is_banned(now) && entry.address.parse::<SocketAddr>().is_ok_and(|address| address.ip() == ip)
+        })
+    }
+
+    pub fn connection_counts(&self) -> (usize, usize) {
+        (self.inbound.len(), self.outbound.len())
+    }
+
+    pub fn statuses(&self) -> Vec<PeerStatus> {
+        let now = now_millis();
+        let inbound: HashSet<&String> = self.inbound.values().collect();
+        let mut statuses: Vec<PeerStatus> = self.entries.values()
+            .map(|entry| PeerStatus {
+                entry: entry.clone(),
+                connected: if self.outbound.contains(&entry.address) {
+                    Some(Direction::Outbound)
+                } else if inbound.contains(&entry.address) {
+                    Some(Direction::Inbound)
+                } else {
+                    None
+                },
+                banned: entry.is_banned(now),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.entry.address.cmp(&b.entry.address));
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::poa::use_simulated_clock;
+    use crate::storage::{MemoryStore, SledStore};
+
+    const EPOCH_MS: u128 = 1_700_000_000_000;
+
+    fn manager() -> PeerManager {
+        use_simulated_clock(EPOCH_MS);
+        PeerManager::new(Arc::new(MemoryStore::new()))
+    }
+
+    async fn advance(ms: u128) {
+        tokio::time::advance(Duration::from_millis(ms as u64)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn offences_lower_the_score_until_the_peer_is_banned() {
+        let mut peers = manager();
+        peers.add_address("10.0.0.1:9000");
+        for _ in 0..3 {
+            peers.record_success("10.0.0.1:9000", "node1");
+        }
+        peers.penalize("10.0.0.1:9000", Offence::InvalidMessage);
+        assert_eq!(peers.entries["10.0.0.1:9000"].score, 3 - 5);
+        peers.penalize("10.0.0.1:9000", Offence::ProtocolViolation);
+        assert!(!peers.is_banned("10.0.0.1:9000"));
+        peers.penalize("10.0.0.1:9000", Offence::ProtocolViolation);
+
+        assert!(peers.is_banned("10.0.0.1:9000"));
+        assert_eq!(peers.entries["10.0.0.1:9000"].score, 0);
+        assert!(peers.is_ip_banned("10.0.0.1".parse().unwrap()));
+        assert!(peers.try_accept_inbound("10.0.0.1:40000".parse().unwrap()).is_err());
+        assert!(!peers.try_connect_outbound("10.0.0.1:9000"));
+        assert!(peers.candidates().is_empty());
+        assert!(peers.known_good().is_empty());
+
+        advance(BAN_DURATION_MS).await;
+        assert!(!peers.is_banned("10.0.0.1:9000"));
+        assert!(peers.try_accept_inbound("10.0.0.1:40000".parse().unwrap()).is_ok());
+        assert_eq!(peers.candidates(), vec!["10.0.0.1:9000".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn score_is_capped_and_orders_candidates() {
+        let mut peers = manager();
+        peers.add_address("10.0.0.1:9000");
+        peers.add_address("10.0.0.2:9000");
+        for _ in 0..MAX_SCORE + 10 {
+            peers.record_success("10.0.0.2:9000", "node2");
+        }
+        assert_eq!(peers.entries["10.0.0.2:9000"].score, MAX_SCORE);
+        assert_eq!(peers.candidates(), vec!["10.0.0.2:9000".to_string(), "10.0.0.1:9000".to_string()]);
+        assert_eq!(peers.known_good(), vec!["10.0.0.2:9000".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_addresses_back_off_exponentially() {
+        let mut peers = manager();
+        peers.add_address("10.0.0.1:9000");
+        let mut expected = Vec::new();
+        let mut delays = Vec::new();
+        for failures in 1..=12u32 {
+            peers.record_failure("10.0.0.1:9000");
+            delays.push(peers.entries["10.0.0.1:9000"].next_attempt_ms - EPOCH_MS);
+            expected.push((INITIAL_BACKOFF_MS << (failures - 1)).min(MAX_BACKOFF_MS));
+        }
+        assert_eq!(delays, expected);
+        assert_eq!(delays.last(), Some(&MAX_BACKOFF_MS));
+
+        assert!(peers.candidates().is_empty());
+        advance(MAX_BACKOFF_MS - 1).await;
+        assert!(peers.candidates().is_empty());
+        advance(1).await;
+        assert_eq!(peers.candidates(), vec!["10.0.0.1:9000".to_string()]);
+
+        // A successful connection starts the backoff over.
+        peers.record_success("10.0.0.1:9000", "node1");
+        peers.record_failure("10.0.0.1:9000");
+        assert_eq!(peers.entries["10.0.0.1:9000"].next_attempt_ms, now_millis() + INITIAL_BACKOFF_MS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_stay_within_the_limits() {
+        let mut peers = manager().with_limits(2, 1);
+        peers.add_address("10.0.0.1:9000");
+        peers.add_address("10.0.0.2:9000");
+        assert_eq!(peers.candidates().len(), 1);
+        assert!(peers.try_connect_outbound("10.0.0.1:9000"));
+        assert!(!peers.try_connect_outbound("10.0.0.2:9000"));
+        assert!(peers.candidates().is_empty());
+        peers.disconnected_outbound("10.0.0.1:9000");
+        assert!(peers.try_connect_outbound("10.0.0.2:9000"));
+
+        let remotes: Vec<SocketAddr> = (1..=3).map(|i| format!("10.0.1.{}:40000", i).parse().unwrap()).collect();
+        assert!(peers.try_accept_inbound(remotes[0]).is_ok());
+        assert!(peers.try_accept_inbound(remotes[1]).is_ok());
+        assert!(peers.try_accept_inbound(remotes[2]).is_err());
+        peers.disconnected_inbound(remotes[0]);
+        assert!(peers.try_accept_inbound(remotes[2]).is_ok());
+        assert_eq!(peers.connection_counts(), (2, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_book_takes_valid_new_addresses_up_to_its_limit() {
+        let mut peers = manager();
+        assert!(!peers.add_address("not an address"));
+        assert!(peers.add_address("10.0.0.1:9000"));
+        assert!(!peers.add_address("10.0.0.1:9000"));
+        peers.mark_own_address("10.0.0.1:9000");
+        assert!(!peers.add_address("10.0.0.1:9000"));
+
+        for i in 0..MAX_ADDRESS_BOOK {
+            assert!(peers.add_address(&format!("10.1.{}.{}:9000", i / 256, i % 256)));
+        }
+        assert!(!peers.add_address("10.2.0.1:9000"));
+        assert_eq!(peers.statuses().len(), MAX_ADDRESS_BOOK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_book_survives_a_restart() {
+        use_simulated_clock(EPOCH_MS);
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store: Arc<dyn ChainStore> = Arc::new(SledStore::open(db.clone()).unwrap());
+        let mut peers = PeerManager::new(store);
+        peers.add_address("10.0.0.1:9000");
+        peers.add_address("10.0.0.2:9000");
+        peers.add_address("10.0.0.3:9000");
+        peers.record_success("10.0.0.1:9000", "node1");
+        for _ in 0..4 {
+            peers.penalize("10.0.0.2:9000", Offence::ProtocolViolation);
+        }
+        peers.mark_own_address("10.0.0.3:9000");
+
+        let restarted = PeerManager::new(Arc::new(SledStore::open(db).unwrap()));
+        let statuses = restarted.statuses();
+        let addresses: Vec<&str> = statuses.iter().map(|status| status.entry.address.as_str()).collect();
+        assert_eq!(addresses, vec!["10.0.0.1:9000", "10.0.0.2:9000"]);
+        assert_eq!(statuses[0].entry.node_id.as_deref(), Some("node1"));
+        assert_eq!(statuses[0].entry.score, 1);
+        assert!(restarted.is_banned("10.0.0.2:9000"));
+    }
+}
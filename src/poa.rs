@@ -1,19 +1,170 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::block::BlockHeader;
 use crate::node::Node;
 
+pub const DEFAULT_SLOT_DURATION_MS: u64 = 5000;
+// How far ahead of the local clock a block timestamp may be before it is rejected.
+pub const MAX_CLOCK_DRIFT_MS: u128 = 1000;
+
+// Round-robin block production schedule. The proposer for height `h` is entry
+// `h % n` of the authority list sorted by node ID, and a block must fall in a
+// later slot than its parent so each slot holds at most one block.
+#[derive(Debug, Clone)]
 pub struct PoA {
-    pub authorities: Vec<Node>,
+    pub slot_duration_ms: u64,
 }
 
 impl PoA {
-    pub fn new() -> Self {
-        PoA { authorities: vec![] }
+    pub fn new(slot_duration_ms: u64) -> Self {
+        PoA { slot_duration_ms: slot_duration_ms.max(1) }
+    }
+
+    pub fn sorted_authorities(authorities: &[Node]) -> Vec<&Node> {
+        let mut sorted: Vec<&Node> = authorities.iter().filter(|node| node.is_authority).collect();
+        sorted.sort_by(|a, b| a.id.cmp(&b.id));
+        sorted
+    }
+
+    pub fn is_authority(authorities: &[Node], node_id: &str) -> bool {
+        authorities.iter().any(|node| node.id == node_id && node.is_authority)
+    }
+
+    pub fn expected_proposer<'a>(&self, authorities: &'a [Node], height: u64) -> Option<&'a Node> {
+        let sorted = Self::sorted_authorities(authorities);
+        if sorted.is_empty() {
+            return None;
+        }
+        Some(sorted[(height % sorted.len() as u64) as usize])
+    }
+
+    pub fn is_turn(&self, authorities: &[Node], height: u64, node_id: &str) -> bool {
+        self.expected_proposer(authorities, height).map_or(false, |node| node.id == node_id)
+    }
+
+    pub fn slot_at(&self, timestamp_ms: u128) -> u64 {
+        (timestamp_ms / self.slot_duration_ms as u128) as u64
+    }
+
+    pub fn time_until_next_slot(&self, now_ms: u128) -> Duration {
+        let slot_ms = self.slot_duration_ms as u128;
+        let next_slot_start = (now_ms / slot_ms + 1) * slot_ms;
+        Duration::from_millis((next_slot_start - now_ms) as u64)
+    }
+
+    pub fn validate_block(&self, authorities: &[Node], block: &BlockHeader, parent: &BlockHeader, now_ms: u128) -> Result<(), String> {
+        match self.expected_proposer(authorities, block.index) {
+            Some(expected) if expected.id == block.node_id => {}
+            Some(expected) => {
+                return Err(format!("Block {}: expected proposer {}, got {}", block.index, expected.id, block.node_id));
+            }
+            None => return Err(format!("Block {}: no authorities to propose it", block.index)),
+        }
+
+        let slot = self.slot_at(block.timestamp);
+        let parent_slot = self.slot_at(parent.timestamp);
+        if slot <= parent_slot {
+            return Err(format!("Block {}: slot {} is not after parent slot {}", block.index, slot, parent_slot));
+        }
+        if block.timestamp > now_ms + MAX_CLOCK_DRIFT_MS {
+            return Err(format!("Block {}: timestamp {} is in the future", block.index, block.timestamp));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PoA {
+    fn default() -> Self {
+        PoA::new(DEFAULT_SLOT_DURATION_MS)
+    }
+}
+
+pub fn now_millis() -> u128 {
+    #[cfg(test)]
+    if let Some(now) = simulated_now() {
+        return now;
+    }
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+thread_local! {
+    // Start of the simulated clock as (wall-clock ms, tokio instant); see `simulator`.
+    static SIMULATED_CLOCK: std::cell::Cell<Option<(u128, tokio::time::Instant)>> = const { std::cell::Cell::new(None) };
+}
+
+// Makes `now_millis` on this thread start at `epoch_ms` and advance with
+// tokio's clock, which tests pause so time only moves when every task waits.
+#[cfg(test)]
+pub fn use_simulated_clock(epoch_ms: u128) {
+    SIMULATED_CLOCK.with(|clock| clock.set(Some((epoch_ms, tokio::time::Instant::now()))));
+}
+
+#[cfg(test)]
+fn simulated_now() -> Option<u128> {
+    SIMULATED_CLOCK.with(|clock| clock.get()).map(|(epoch_ms, start)| epoch_ms + start.elapsed().as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Secp256k1, SecretKey, PublicKey};
+    use crate::block::BlockHash;
+
+    fn authorities(ids: &[&str]) -> Vec<Node> {
+        ids.iter().enumerate().map(|(i, id)| {
+            let secret_key = SecretKey::from_slice(&[i as u8 + 1; 32]).unwrap();
+            Node { id: id.to_string(), is_authority: true, public_key: PublicKey::from_secret_key(&Secp256k1::new(), &secret_key) }
+        }).collect()
+    }
+
+    fn header(index: u64, timestamp: u128, node_id: &str) -> BlockHeader {
+        BlockHeader {
+            version: 0,
+            index,
+            timestamp,
+            previous_hash: BlockHash::ZERO,
+            hash: BlockHash::ZERO,
+            nonce: 0,
+            data: String::new(),
+            merkle_root: BlockHash::ZERO,
+            state_root: BlockHash::ZERO,
+            signature: String::new(),
+            node_id: node_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn proposers_rotate_by_sorted_node_id() {
+        let poa = PoA::new(1000);
+        let mut authorities = authorities(&["c", "a", "b"]);
+        // Nodes that are not authorities never take a turn.
+        authorities.push(Node { id: "aa".to_string(), is_authority: false, ..authorities[0].clone() });
+        let proposers: Vec<&str> = (0..4).map(|h| poa.expected_proposer(&authorities, h).unwrap().id.as_str()).collect();
+        assert_eq!(proposers, vec!["a", "b", "c", "a"]);
+        assert!(poa.expected_proposer(&[], 1).is_none());
     }
 
-    pub fn add_authority(&mut self, node: Node) {
-        self.authorities.push(node);
+    #[test]
+    fn block_from_the_wrong_proposer_is_rejected() {
+        let poa = PoA::new(1000);
+        let authorities = authorities(&["a", "b", "c"]);
+        let parent = header(0, 1_000, "a");
+        assert!(poa.validate_block(&authorities, &header(1, 2_000, "b"), &parent, 2_000).is_ok());
+        assert!(poa.validate_block(&authorities, &header(1, 2_000, "c"), &parent, 2_000).is_err());
+        assert!(poa.validate_block(&authorities, &header(1, 2_000, "d"), &parent, 2_000).is_err());
+        assert!(poa.validate_block(&[], &header(1, 2_000, "b"), &parent, 2_000).is_err());
     }
 
-    pub fn is_authority(&self, node_id: &str) -> bool {
-        self.authorities.iter().any(|node| node.id == node_id && node.is_authority)
+    #[test]
+    fn block_must_fall_in_a_later_slot_and_not_in_the_future() {
+        let poa = PoA::new(1000);
+        let authorities = authorities(&["a", "b"]);
+        let parent = header(0, 5_100, "a");
+        // Same slot as the parent, even though the timestamp is later.
+        assert!(poa.validate_block(&authorities, &header(1, 5_900, "b"), &parent, 10_000).is_err());
+        assert!(poa.validate_block(&authorities, &header(1, 4_000, "b"), &parent, 10_000).is_err());
+        assert!(poa.validate_block(&authorities, &header(1, 6_000, "b"), &parent, 10_000).is_ok());
+        assert!(poa.validate_block(&authorities, &header(1, 6_000, "b"), &parent, 6_000 - MAX_CLOCK_DRIFT_MS).is_ok());
+        assert!(poa.validate_block(&authorities, &header(1, 6_000, "b"), &parent, 6_000 - MAX_CLOCK_DRIFT_MS - 1).is_err());
     }
 }
@@ -0,0 +1,58 @@
+use crate::block::BlockHash;
+use crate::finality::Precommit;
+use crate::network::Network;
+use crate::poa::now_millis;
+
+// Wakes at every slot boundary, precommits to the current tip if this node is
+// an authority for it, and seals the pending transactions into a block when
+// this node is the scheduled proposer for the next height.
+pub async fn run_block_producer(network: Network) {
+    let blockchain = network.blockchain.clone();
+    let (node_id, secret_key) = (network.identity.node_id.clone(), network.identity.secret_key);
+    log::info!("Block producer started for {}", node_id);
+    let mut last_precommitted: Option<BlockHash> = None;
+    loop {
+        let wait = blockchain.lock().await.poa.time_until_next_slot(now_millis());
+        tokio::time::sleep(wait).await;
+
+        let precommit = {
+            let mut blockchain = blockchain.lock().await;
+            match blockchain.blocks.last().cloned() {
+                Some(tip) if Some(tip.hash) != last_precommitted
+                    && tip.index > blockchain.finality.finalized_height
+                    && blockchain.authorities_at(tip.index).iter().any(|node| node.id == node_id) => {
+                    let precommit = Precommit::new(tip.hash, tip.index, node_id.clone(), &secret_key);
+                    if let Err(e) = blockchain.add_precommit(precommit.clone()) {
+                        log::warn!("Failed to record own precommit: {}", e);
+                    }
+                    last_precommitted = Some(tip.hash);
+                    Some(precommit)
+                }
+                _ => None,
+            }
+        };
+        if let Some(precommit) = precommit {
+            network.broadcast_precommit(&precommit).await;
+        }
+
+        let sealed = {
+            let mut blockchain = blockchain.lock().await;
+            if blockchain.blocks.is_empty() || !blockchain.is_turn(&node_id) {
+                continue;
+            }
+            let transactions = blockchain.pending_transactions();
+            if transactions.is_empty() {
+                continue;
+            }
+            blockchain.add_block(String::new(), transactions, node_id.clone(), &secret_key)
+        };
+
+        match sealed {
+            Ok(block) => {
+                log::info!("Sealed block {} with {} transactions", block.index, block.transactions.len());
+                network.announce_block(&block).await;
+            }
+            Err(e) => log::warn!("Failed to seal block: {}", e),
+        }
+    }
+}
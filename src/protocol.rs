@@ -0,0 +1,209 @@
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::block::{Block, BlockHash, BlockHeader};
+use crate::finality::Precommit;
+use crate::snapshot::SnapshotManifest;
+use crate::public_key_serde::SerializablePublicKey;
+use crate::transaction::{Transaction, TxHash};
+
+// Bumped whenever the frame layout or `Message` changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 6;
+// Separates independent networks so their nodes never exchange messages.
+pub const DEFAULT_NETWORK_ID: u32 = 0x434f_474e;
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_HEADERS_PER_REQUEST: u32 = 2000;
+pub const MAX_BLOCKS_PER_REQUEST: u32 = 100;
+pub const MAX_INVENTORY_ITEMS: usize = 1000;
+const FRAME_HEADER_LEN: usize = 10;
+
+// Something a peer can announce by hash and be asked for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InventoryItem {
+    Block(BlockHash),
+    Transaction(TxHash),
+}
+
+// Every message exchanged between peers. Requests are answered on the same
+// connection; announcements get no response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    // First frame on every connection, always in plaintext: an ephemeral key
+    // to encrypt the rest of the connection, or `None` for plaintext. See
+    // `transport`.
+    Transport { ephemeral_key: Option<SerializablePublicKey> },
+    // Opens the identity handshake; the peer answers with its own `Hello`. See `handshake`.
+    Hello {
+        node_id: String,
+        public_key: SerializablePublicKey,
+        protocol_version: u16,
+        genesis_hash: BlockHash,
+        best_height: u64,
+        best_hash: BlockHash,
+        // Port the sender accepts connections on, if any.
+        listen_port: Option<u16>,
+        challenge: [u8; 32],
+    },
+    // Signature over the other side's challenge, proving ownership of its key.
+    HelloProof { signature: String },
+    GetStatus,
+    Status {
+        best_height: u64,
+        best_hash: BlockHash,
+        finalized_height: u64,
+    },
+    GetHeaders { from_height: u64, max: u32 },
+    Headers(Vec<BlockHeader>),
+    GetBlocks { from_height: u64, max: u32 },
+    Blocks(Vec<Block>),
+    // State snapshots for bootstrapping, sent in chunks. A chunk is empty
+    // when the snapshot is no longer available. See `snapshot`.
+    GetSnapshotInfo,
+    SnapshotInfo(Option<SnapshotManifest>),
+    GetSnapshotChunk { height: u64, index: u32 },
+    SnapshotChunk { height: u64, index: u32, data: Vec<u8> },
+    GetPeers,
+    Peers(Vec<String>),
+    // Gossip: hashes of new blocks and transactions, and a request for the
+    // ones the receiver lacks, answered with `NewBlock` and `NewTransaction`.
+    Inventory(Vec<InventoryItem>),
+    GetData(Vec<InventoryItem>),
+    NewBlock(Block),
+    NewTransaction(Transaction),
+    Precommit(Precommit),
+}
+
+// Frames messages as network id (u32), protocol version (u16) and payload
+// length (u32), all big-endian, followed by the bincode-encoded message.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub network_id: u32,
+}
+
+impl Codec {
+    pub fn new(network_id: u32) -> Self {
+        Codec { network_id }
+    }
+
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>, String> {
+        let payload = bincode::serialize(message).map_err(|e| e.to_string())?;
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(format!("Message of {} bytes exceeds the frame limit", payload.len()));
+        }
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&self.network_id.to_be_bytes());
+        frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    // Checks a frame header and returns the payload length.
+    fn payload_len(&self, header: &[u8; FRAME_HEADER_LEN]) -> Result<usize, String> {
+        let network_id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        if network_id != self.network_id {
+            return Err(format!("Peer is on network {:#x}, expected {:#x}", network_id, self.network_id));
+        }
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        if version != PROTOCOL_VERSION {
+            return Err(format!("Peer speaks protocol version {}, expected {}", version, PROTOCOL_VERSION));
+        }
+        let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(format!("Frame of {} bytes exceeds the limit", len));
+        }
+        Ok(len)
+    }
+
+    // Reads the next message, or `None` once the peer has closed the connection.
+    pub async fn read<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Option<Message>, String> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        }
+        let len = self.payload_len(&header)?;
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await.map_err(|e| e.to_string())?;
+        bincode::deserialize(&payload).map(Some).map_err(|e| format!("Malformed message: {}", e))
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W, message: &Message) -> Result<(), String> {
+        let frame = self.encode(message)?;
+        writer.write_all(&frame).await.map_err(|e| e.to_string())?;
+        writer.flush().await.map_err(|e| e.to_string())
+    }
+
+    // Sends a request and waits for the peer's response.
+    pub async fn request<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S, message: &Message) -> Result<Message, String> {
+        self.write(stream, message).await?;
+        self.read(stream).await?.ok_or_else(|| "Peer closed the connection".to_string())
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new(DEFAULT_NETWORK_ID)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(network_id: u32, version: u16, len: u32) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&network_id.to_be_bytes());
+        frame.extend_from_slice(&version.to_be_bytes());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_until_the_stream_ends() {
+        let codec = Codec::default();
+        let messages = [Message::GetStatus, Message::Peers(vec!["127.0.0.1:8000".to_string()]), Message::GetHeaders { from_height: 5, max: 10 }];
+        let mut bytes = Vec::new();
+        for message in &messages {
+            bytes.extend(codec.encode(message).unwrap());
+        }
+        let mut reader = bytes.as_slice();
+        for message in messages {
+            assert_eq!(codec.read(&mut reader).await, Ok(Some(message)));
+        }
+        assert_eq!(codec.read(&mut reader).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_are_refused() {
+        let codec = Codec::default();
+        let oversize = Message::SnapshotChunk { height: 1, index: 0, data: vec![0; MAX_FRAME_SIZE] };
+        assert!(codec.encode(&oversize).is_err());
+
+        // Refused from the header alone, before reading the payload.
+        let frame = header(DEFAULT_NETWORK_ID, PROTOCOL_VERSION, MAX_FRAME_SIZE as u32 + 1);
+        assert!(codec.read(&mut frame.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_from_another_network_or_version_are_refused() {
+        let frame = Codec::new(1).encode(&Message::GetStatus).unwrap();
+        assert!(Codec::new(2).read(&mut frame.as_slice()).await.is_err());
+        assert_eq!(Codec::new(1).read(&mut frame.as_slice()).await, Ok(Some(Message::GetStatus)));
+
+        let mut frame = Codec::default().encode(&Message::GetStatus).unwrap();
+        frame[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        assert!(Codec::default().read(&mut frame.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_or_malformed_payloads_are_errors() {
+        let codec = Codec::default();
+        let frame = codec.encode(&Message::Peers(vec!["peer".to_string()])).unwrap();
+        assert!(codec.read(&mut &frame[..frame.len() - 1]).await.is_err());
+
+        let mut frame = header(DEFAULT_NETWORK_ID, PROTOCOL_VERSION, 4);
+        frame.extend_from_slice(&[0xff; 4]);
+        assert!(codec.read(&mut frame.as_slice()).await.is_err());
+    }
+}
@@ -6,7 +6,7 @@ use serde::de::{self, Deserializer};
 use serde::ser::Serializer;
 use std::str::FromStr;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SerializablePublicKey(pub PublicKey);
 
 impl Serialize for SerializablePublicKey {
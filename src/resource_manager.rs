@@ -1,27 +1,59 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use crate::smart_contract::GPUResourceContract;
 use crate::smart_contract::GPURequirements;
+use crate::storage::WriteBatch;
 
 #[derive(Debug, Clone)]
 pub struct ResourceManager {
     gpu_resources: HashMap<String, GPUResourceContract>,
+    // Registrations changed or removed since the last `take_changes`.
+    changed: BTreeSet<String>,
 }
 
 impl ResourceManager {
     pub fn new() -> Self {
         ResourceManager {
             gpu_resources: HashMap::new(),
+            changed: BTreeSet::new(),
         }
     }
 
+    // Drops all registrations before they are rebuilt from the chain.
+    pub fn clear(&mut self) {
+        self.changed.extend(self.gpu_resources.drain().map(|(node_id, _)| node_id));
+    }
+
+    // Replaces all registrations, e.g. with those from a state snapshot.
+    pub fn restore(&mut self, resources: BTreeMap<String, GPUResourceContract>) {
+        self.clear();
+        self.changed.extend(resources.keys().cloned());
+        self.gpu_resources.extend(resources);
+    }
+
+    pub fn registrations(&self) -> impl Iterator<Item = (&String, &GPUResourceContract)> {
+        self.gpu_resources.iter()
+    }
+
     pub fn register_gpu(&mut self, node_id: String, contract: GPUResourceContract) {
+        self.changed.insert(node_id.clone());
         self.gpu_resources.insert(node_id, contract);
     }
 
+    // Adds the pending registration changes to `batch`.
+    pub fn take_changes(&mut self, batch: &mut WriteBatch) {
+        for node_id in std::mem::take(&mut self.changed) {
+            match self.gpu_resources.get(&node_id) {
+                Some(gpu) => batch.put_resource(node_id, gpu.clone()),
+                None => batch.remove_resource(node_id),
+            }
+        }
+    }
+
     pub fn allocate_gpu(&mut self, task_requirements: &GPURequirements) -> Result<String, String> {
         for (node_id, gpu) in &mut self.gpu_resources {
             if gpu.available && gpu.meets_requirements(task_requirements) {
                 gpu.available = false;
+                self.changed.insert(node_id.clone());
                 return Ok(node_id.clone());
             }
         }
@@ -33,6 +65,7 @@ impl ResourceManager {
             if !gpu.available {
                 gpu.available = true;
                 gpu.current_task = None;
+                self.changed.insert(node_id.to_string());
                 Ok(())
             } else {
                 Err("GPU is already available".to_string())
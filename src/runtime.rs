@@ -0,0 +1,192 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use crate::api;
+use crate::blockchain::{Blockchain, DEFAULT_CHAIN_ID};
+use crate::handshake::NodeIdentity;
+use crate::network::{run_peer_manager, synchronize_or_initialize, Network};
+use crate::peer_manager::{PeerManager, DEFAULT_MAX_INBOUND, DEFAULT_MAX_OUTBOUND};
+use crate::poa::{PoA, DEFAULT_SLOT_DURATION_MS};
+use crate::producer::run_block_producer;
+use crate::server;
+use crate::snapshot::DEFAULT_SNAPSHOT_INTERVAL;
+use crate::storage::{ChainStore, SledStore};
+use crate::sync::run_sync;
+use crate::transport::Encryption;
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:6397";
+pub const DEFAULT_API_ADDRESS: &str = "127.0.0.1:3030";
+pub const DEFAULT_DB_PATH: &str = "blockchain_db";
+
+// Everything a node needs to know before it starts, read from the environment.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub node_id: String,
+    // Contract transactions must name this chain.
+    pub chain_id: String,
+    pub secret_key: SecretKey,
+    // Where the P2P listener binds, and the port peers are told to dial back.
+    pub listen_address: SocketAddr,
+    pub api_address: SocketAddr,
+    pub db_path: String,
+    pub peer_addresses: Vec<String>,
+    pub encryption: Encryption,
+    pub slot_duration_ms: u64,
+    // Zero turns snapshots off.
+    pub snapshot_interval: u64,
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl NodeConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let secret_key = env::var("SECRET_KEY").map_err(|_| "SECRET_KEY must be set".to_string())?;
+        let secret_key = hex::decode(secret_key).ok()
+            .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+            .ok_or("SECRET_KEY is not a valid hex-encoded secret key")?;
+        let encryption = Encryption::parse(&env::var("PEER_ENCRYPTION").unwrap_or_else(|_| "on".to_string()))
+            .map_err(|e| format!("Invalid PEER_ENCRYPTION: {}", e))?;
+        let peer_addresses = env::var("PEER_ADDRESSES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        Ok(NodeConfig {
+            node_id: env::var("NODE_ID").unwrap_or_else(|_| "node1".to_string()),
+            chain_id: env::var("CHAIN_ID").unwrap_or_else(|_| DEFAULT_CHAIN_ID.to_string()),
+            secret_key,
+            listen_address: address_var("NODE_IP", DEFAULT_LISTEN_ADDRESS)?,
+            api_address: address_var("API_ADDRESS", DEFAULT_API_ADDRESS)?,
+            db_path: env::var("DB_PATH").unwrap_or_else(|_| DEFAULT_DB_PATH.to_string()),
+            peer_addresses,
+            encryption,
+            slot_duration_ms: number_var("SLOT_DURATION_MS", DEFAULT_SLOT_DURATION_MS)?,
+            snapshot_interval: number_var("SNAPSHOT_INTERVAL", DEFAULT_SNAPSHOT_INTERVAL)?,
+            max_inbound: number_var("MAX_INBOUND_PEERS", DEFAULT_MAX_INBOUND)?,
+            max_outbound: number_var("MAX_OUTBOUND_PEERS", DEFAULT_MAX_OUTBOUND)?,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &self.secret_key)
+    }
+}
+
+fn address_var(name: &str, default: &str) -> Result<SocketAddr, String> {
+    let value = env::var(name).unwrap_or_else(|_| default.to_string());
+    value.parse().map_err(|_| format!("Invalid {}: {} is not an ip:port address", name, value))
+}
+
+fn number_var<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|_| format!("Invalid {}: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+// One running node: the chain with its contract and resource state, and
+// once started the P2P listener, API server, block producer, sync and peer
+// maintenance.
+#[derive(Clone)]
+pub struct NodeRuntime {
+    pub config: NodeConfig,
+    pub network: Network,
+    db: sled::Db,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl NodeRuntime {
+    // Opens the database and brings the chain up to date with peers, or
+    // creates the genesis block when there is no one to sync from.
+    pub async fn open(config: NodeConfig) -> Result<Self, String> {
+        let db = sled::open(&config.db_path).map_err(|e| format!("Failed to open database: {}", e))?;
+        let store: Arc<dyn ChainStore> = Arc::new(SledStore::open(db.clone()).map_err(|e| format!("Failed to open blockchain storage: {}", e))?);
+        let blockchain = Blockchain::new(store.clone(), config.chain_id.clone(), PoA::new(config.slot_duration_ms), config.snapshot_interval)
+            .map_err(|e| format!("Failed to load blockchain: {}", e))?;
+
+        // PEER_ADDRESSES seeds the persistent address book.
+        let mut peer_manager = PeerManager::new(store).with_limits(config.max_inbound, config.max_outbound);
+        for address in &config.peer_addresses {
+            if address.parse::<SocketAddr>().is_err() {
+                log::warn!("Ignoring invalid peer address {}", address);
+                continue;
+            }
+            peer_manager.add_address(address);
+        }
+
+        let identity = NodeIdentity::new(config.node_id.clone(), config.secret_key)
+            .with_encryption(config.encryption)
+            .with_listen_address(config.listen_address.to_string());
+        let network = Network::new(identity, Arc::new(Mutex::new(blockchain)), Arc::new(Mutex::new(peer_manager)));
+
+        if let Err(e) = synchronize_or_initialize(&network).await {
+            println!("Failed to synchronize with peers: {}", e);
+            let mut blockchain = network.blockchain.lock().await;
+            if blockchain.blocks.is_empty() {
+                println!("No blocks present after failed synchronization; initializing genesis block.");
+                blockchain.initialize_genesis(&config.node_id, &config.secret_key, config.public_key()).await;
+            }
+        }
+
+        Ok(NodeRuntime {
+            config,
+            network,
+            db,
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    // Binds the P2P listener and the API server, then starts the background
+    // services. Fails without starting anything if either address is taken.
+    pub async fn start(&self) -> Result<(), String> {
+        let listener = TcpListener::bind(self.config.listen_address).await
+            .map_err(|e| format!("Cannot listen on {}: {}", self.config.listen_address, e))?;
+        let api = api::start_api(self.network.clone(), self.config.api_address)?;
+        println!("Node server running on {}", self.config.listen_address);
+        println!("API server running on {}", self.config.api_address);
+
+        let mut tasks = self.tasks.lock().await;
+        tasks.push(tokio::spawn(server::serve(listener, self.network.clone())));
+        tasks.push(tokio::spawn(api));
+        tasks.push(tokio::spawn(run_block_producer(self.network.clone())));
+        tasks.push(tokio::spawn(run_peer_manager(self.network.clone())));
+        tasks.push(tokio::spawn(run_sync(self.network.clone())));
+        Ok(())
+    }
+
+    // Stops the services and flushes the database. Holding the chain lock
+    // while flushing means no block commit is left half written.
+    pub async fn shutdown(&self) {
+        for task in self.tasks.lock().await.drain(..) {
+            task.abort();
+        }
+        let _blockchain = self.network.blockchain.lock().await;
+        match self.db.flush_async().await {
+            Ok(_) => log::info!("Database flushed"),
+            Err(e) => eprintln!("Failed to flush database: {}", e),
+        }
+    }
+}
+
+// Resolves on the first SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1,31 +1,82 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::net::SocketAddr;
 use crate::blockchain::Blockchain;
-use serde_json;
+use crate::block::{Block, BlockHash};
+use crate::gossip::Session;
+use crate::handshake::{self, LocalChain};
+use crate::network::Network;
+use crate::peer_manager::PeerManager;
+use crate::protocol::{Codec, Message, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST};
 
-pub async fn start_node_server(node_ip: String, blockchain: Arc<Mutex<Blockchain>>) {
-    let listener = TcpListener::bind(&node_ip).await.unwrap();
-    println!("Node server running on {}", node_ip);
-
-    while let Ok((mut socket, _)) = listener.accept().await {
-        let blockchain = blockchain.clone();
+// Accepts peers on the node's P2P listener until the runtime stops it.
+pub async fn serve(listener: TcpListener, network: Network) {
+    while let Ok((socket, address)) = listener.accept().await {
+        if let Err(e) = network.peers.lock().await.try_accept_inbound(address) {
+            log::info!("Refused connection from {}: {}", address, e);
+            continue;
+        }
+        let network = network.clone();
         tokio::spawn(async move {
-            let mut buffer = [0; 1024];
-            while let Ok(n) = socket.read(&mut buffer).await {
-                if n == 0 {
-                    break;
-                }
-                let received_data = String::from_utf8_lossy(&buffer[..n]);
-                if received_data == "request_blockchain" {
-                    let blockchain_data = {
-                        let blockchain = blockchain.lock().await;
-                        serde_json::to_string(&*blockchain).unwrap()
-                    };
-                    socket.write_all(blockchain_data.as_bytes()).await.unwrap();
-                }
+            if let Err(e) = serve_peer(socket, address, &network).await {
+                log::warn!("Connection from {} closed: {}", address, e);
             }
+            network.peers.lock().await.disconnected_inbound(address);
         });
     }
 }
+
+async fn serve_peer(socket: TcpStream, remote: SocketAddr, network: &Network) -> Result<(), String> {
+    let codec = Codec::default();
+    let local = LocalChain::of(&*network.blockchain.lock().await);
+    let (socket, peer) = handshake::respond(socket, &codec, &network.identity, &local).await?;
+    if peer.public_key == network.identity.public_key {
+        return Err("Connected to ourselves".to_string());
+    }
+    let address = network.peers.lock().await.identify_inbound(remote, peer.listen_port);
+    log::info!("Peer {} connected from {} at height {} on genesis {} (encrypted: {})", peer.node_id, address, peer.best_height, peer.genesis_hash, peer.encrypted);
+    Session::open(network, peer, address).await?.run(socket, network.clone()).await
+}
+
+// Answers a request from an authenticated peer. An error names what the
+// peer sent that we had to reject.
+pub fn handle_message(blockchain: &mut Blockchain, peers: &PeerManager, message: Message) -> Result<Option<Message>, String> {
+    let response = match message {
+        Message::Transport { .. } | Message::Hello { .. } | Message::HelloProof { .. } => {
+            return Err("Handshake message after the handshake".to_string());
+        }
+        Message::GetStatus => Some(Message::Status {
+            best_height: blockchain.blocks.len().saturating_sub(1) as u64,
+            best_hash: blockchain.blocks.last().map_or(BlockHash::ZERO, |block| block.hash),
+            finalized_height: blockchain.finality.finalized_height,
+        }),
+        Message::GetHeaders { from_height, max } => Some(Message::Headers(
+            blockchain.blocks.iter()
+                .skip(from_height as usize)
+                .take(max.min(MAX_HEADERS_PER_REQUEST) as usize)
+                .map(Block::header)
+                .collect(),
+        )),
+        // Blocks restored from a snapshot have no bodies to serve.
+        Message::GetBlocks { from_height, .. } if blockchain.snapshot_base > 0 && from_height <= blockchain.snapshot_base => {
+            Some(Message::Blocks(Vec::new()))
+        }
+        Message::GetBlocks { from_height, max } => Some(Message::Blocks(
+            blockchain.blocks.iter()
+                .skip(from_height as usize)
+                .take(max.min(MAX_BLOCKS_PER_REQUEST) as usize)
+                .cloned()
+                .collect(),
+        )),
+        Message::GetSnapshotInfo => Some(Message::SnapshotInfo(blockchain.snapshot_manifest())),
+        Message::GetSnapshotChunk { height, index } => Some(Message::SnapshotChunk {
+            height,
+            index,
+            data: blockchain.snapshot_chunk(height, index).unwrap_or_default(),
+        }),
+        Message::GetPeers => Some(Message::Peers(peers.known_good())),
+        // Gossip, sync answers and status polls are handled by the session
+        // before anything reaches here; other responses were never asked for.
+        _ => return Err("Unsolicited message".to_string()),
+    };
+    Ok(response)
+}
@@ -0,0 +1,477 @@
+// Runs several nodes in one process for tests. Nodes are wired together with
+// the real handshake, gossip and sync code over in-memory links that add
+// latency, drop messages and can be partitioned. Time is tokio's paused
+// clock, which `now_millis` follows, so a minute of simulated block
+// production takes as long as the work it involves. Each link draws its
+// delays and drops from its own RNG seeded from the simulation seed.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use crate::authority::AuthoritySchedule;
+use crate::block::{Block, BlockHash};
+use crate::blockchain::{Blockchain, DEFAULT_CHAIN_ID};
+use crate::gossip::Session;
+use crate::handshake::{self, LocalChain, NodeIdentity};
+use crate::network::Network;
+use crate::node::Node;
+use crate::peer_manager::PeerManager;
+use crate::poa::{self, PoA};
+use crate::producer::run_block_producer;
+use crate::protocol::{Codec, Message};
+use crate::snapshot::DEFAULT_SNAPSHOT_INTERVAL;
+use crate::storage::{ChainStore, MemoryStore, WriteBatch};
+use crate::sync::run_sync;
+use crate::smart_contract::{ContractAction, ContractRequest, ContractType};
+use crate::transaction::{Transaction, TransactionPayload, TxHash};
+use crate::transport::Encryption;
+
+// Where the simulated clock starts.
+const SIMULATION_EPOCH_MS: u128 = 1_700_000_000_000;
+const LINK_BUFFER: usize = 64 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONVERGENCE_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub nodes: usize,
+    // The first `authorities` nodes form the genesis authority set.
+    pub authorities: usize,
+    pub slot_duration_ms: u64,
+    pub latency: Duration,
+    pub jitter: Duration,
+    // Chance that a message is lost.
+    pub loss: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 1,
+            nodes: 4,
+            authorities: 3,
+            slot_duration_ms: 1000,
+            latency: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        }
+    }
+}
+
+// Conditions every link is subject to, changeable while the simulation runs.
+struct Links {
+    seed: u64,
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+    // Partition group of each node; nodes in different groups cannot talk.
+    groups: Vec<usize>,
+    rngs: HashMap<(usize, usize), StdRng>,
+}
+
+impl Links {
+    // How long a message from `from` to `to` takes, or `None` if it is lost.
+    fn delay(&mut self, from: usize, to: usize) -> Option<Duration> {
+        if self.groups[from] != self.groups[to] {
+            return None;
+        }
+        let seed = self.seed ^ (((from as u64) << 32) | to as u64);
+        let rng = self.rngs.entry((from, to)).or_insert_with(|| StdRng::seed_from_u64(seed));
+        if self.loss > 0.0 && rng.gen_bool(self.loss) {
+            return None;
+        }
+        let jitter = rng.gen_range(0..=self.jitter.as_millis() as u64);
+        Some(self.latency + Duration::from_millis(jitter))
+    }
+}
+
+pub struct Simulation {
+    pub nodes: Vec<Network>,
+    links: Arc<StdMutex<Links>>,
+}
+
+// Address a node is known by to its peers.
+fn address(node: usize) -> String {
+    format!("10.0.0.{}:6397", node + 1)
+}
+
+impl Simulation {
+    // Creates the nodes on a shared genesis block, not yet connected or running.
+    pub async fn new(config: SimConfig) -> Self {
+        poa::use_simulated_clock(SIMULATION_EPOCH_MS);
+        let secp = Secp256k1::new();
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let keys: Vec<(String, SecretKey)> = (0..config.nodes)
+            .map(|i| {
+                let secret_key = loop {
+                    if let Ok(key) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+                        break key;
+                    }
+                };
+                (format!("node{}", i), secret_key)
+            })
+            .collect();
+
+        let authorities = keys.iter().take(config.authorities)
+            .map(|(id, secret_key)| Node { id: id.clone(), is_authority: true, public_key: PublicKey::from_secret_key(&secp, secret_key) })
+            .collect();
+        let schedule = AuthoritySchedule::new(authorities);
+        let genesis = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "Genesis Block".to_string(), Vec::new(), keys[0].0.clone());
+
+        let nodes = keys.into_iter()
+            .map(|(id, secret_key)| {
+                let store = Arc::new(MemoryStore::new());
+                let mut batch = WriteBatch::default();
+                batch.put_block(genesis.clone());
+                batch.put_authority_schedule(schedule.clone());
+                batch.set_head(0, genesis.hash);
+                store.write(batch).expect("Memory store accepts genesis");
+                let blockchain = Blockchain::new(store.clone(), DEFAULT_CHAIN_ID.to_string(), PoA::new(config.slot_duration_ms), DEFAULT_SNAPSHOT_INTERVAL)
+                    .expect("Genesis loads");
+                let identity = NodeIdentity::new(id, secret_key).with_encryption(Encryption::Disabled);
+                Network::new(identity, Arc::new(Mutex::new(blockchain)), Arc::new(Mutex::new(PeerManager::new(store))))
+            })
+            .collect();
+
+        let links = Links {
+            seed: config.seed,
+            latency: config.latency,
+            jitter: config.jitter,
+            loss: config.loss,
+            groups: vec![0; config.nodes],
+            rngs: HashMap::new(),
+        };
+        Simulation { nodes, links: Arc::new(StdMutex::new(links)) }
+    }
+
+    // Starts block production and sync on every node.
+    pub fn start(&self) {
+        for network in &self.nodes {
+            tokio::spawn(run_block_producer(network.clone()));
+            tokio::spawn(run_sync(network.clone()));
+        }
+    }
+
+    // Opens a session between two nodes as if `a` had dialled `b`.
+    pub async fn connect(&self, a: usize, b: usize) -> Result<(), String> {
+        let (a_end, a_link) = tokio::io::duplex(LINK_BUFFER);
+        let (b_end, b_link) = tokio::io::duplex(LINK_BUFFER);
+        let (a_read, a_write) = tokio::io::split(a_link);
+        let (b_read, b_write) = tokio::io::split(b_link);
+        tokio::spawn(relay(a_read, b_write, self.links.clone(), a, b));
+        tokio::spawn(relay(b_read, a_write, self.links.clone(), b, a));
+
+        let codec = Codec::default();
+        let (network_a, network_b) = (&self.nodes[a], &self.nodes[b]);
+        let local_a = LocalChain::of(&*network_a.blockchain.lock().await);
+        let local_b = LocalChain::of(&*network_b.blockchain.lock().await);
+        let handshakes = async {
+            tokio::join!(
+                handshake::initiate(a_end, &codec, &network_a.identity, &local_a),
+                handshake::respond(b_end, &codec, &network_b.identity, &local_b),
+            )
+        };
+        let (a_side, b_side) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshakes).await
+            .map_err(|_| format!("Handshake between {} and {} timed out", a, b))?;
+        let (a_stream, b_info) = a_side?;
+        let (b_stream, a_info) = b_side?;
+
+        let a_session = Session::open(network_a, b_info, address(b)).await?;
+        let b_session = Session::open(network_b, a_info, address(a)).await?;
+        for (session, stream, network) in [(a_session, a_stream, network_a), (b_session, b_stream, network_b)] {
+            let network = network.clone();
+            tokio::spawn(async move {
+                if let Err(e) = session.run(stream, network.clone()).await {
+                    log::info!("Simulated session of {} ended: {}", network.identity.node_id, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn connect_all(&self) -> Result<(), String> {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Splits the nodes into groups that cannot reach each other; nodes not
+    // listed form one more group.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut links = self.links.lock().unwrap();
+        links.groups = vec![groups.len(); self.nodes.len()];
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in *nodes {
+                links.groups[*node] = group;
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.partition(&[]);
+    }
+
+    pub fn set_loss(&self, loss: f64) {
+        self.links.lock().unwrap().loss = loss;
+    }
+
+    pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+        let mut links = self.links.lock().unwrap();
+        links.latency = latency;
+        links.jitter = jitter;
+    }
+
+    // Submits a GPU registration signed by `node` to its mempool and
+    // announces it, giving the proposers something to seal.
+    pub async fn submit_transaction(&self, node: usize) -> Result<TxHash, String> {
+        let node_id = self.nodes[node].identity.node_id.clone();
+        self.submit_payload(node, |nonce| TransactionPayload::GpuRegistration {
+            node_id: format!("{}-gpu-{}", node_id, nonce),
+            gpu_type: "simulated".to_string(),
+            vram_capacity: 16.0,
+            cuda_cores: 4096,
+        }).await
+    }
+
+    // Submits and announces a transaction from `node` with the payload built
+    // for its next nonce.
+    pub async fn submit_payload(&self, node: usize, payload: impl FnOnce(u64) -> TransactionPayload) -> Result<TxHash, String> {
+        let network = &self.nodes[node];
+        let identity = &network.identity;
+        let tx = {
+            let mut blockchain = network.blockchain.lock().await;
+            let nonce = blockchain.pending_nonce(&identity.public_key);
+            let mut tx = Transaction::new(identity.public_key, nonce, 0, payload(nonce));
+            tx.sign(&identity.secret_key);
+            blockchain.submit_transaction(tx.clone())?;
+            tx
+        };
+        network.announce_transaction(&tx).await;
+        Ok(tx.hash())
+    }
+
+    // Lets simulated time pass.
+    pub async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    pub async fn heights(&self) -> Vec<u64> {
+        let mut heights = Vec::new();
+        for network in &self.nodes {
+            heights.push(network.blockchain.lock().await.blocks.len() as u64 - 1);
+        }
+        heights
+    }
+
+    pub async fn tips(&self) -> Vec<BlockHash> {
+        let mut tips = Vec::new();
+        for network in &self.nodes {
+            tips.push(network.blockchain.lock().await.blocks.last().expect("Genesis exists").hash);
+        }
+        tips
+    }
+
+    // Waits until every node has the same tip at `min_height` or above and
+    // returns that height.
+    pub async fn wait_for_convergence(&self, min_height: u64, timeout: Duration) -> Result<u64, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (heights, tips) = (self.heights().await, self.tips().await);
+            if heights[0] >= min_height && tips.iter().all(|tip| *tip == tips[0]) {
+                return Ok(heights[0]);
+            }
+            if Instant::now() >= deadline {
+                let nodes: Vec<String> = heights.iter().zip(&tips).enumerate()
+                    .map(|(i, (height, tip))| format!("node{} at {} ({})", i, height, tip))
+                    .collect();
+                return Err(format!("No convergence on height {} after {:?}: {}", min_height, timeout, nodes.join(", ")));
+            }
+            tokio::time::sleep(CONVERGENCE_POLL).await;
+        }
+    }
+
+    pub async fn assert_converged(&self, min_height: u64, timeout: Duration) -> u64 {
+        self.wait_for_convergence(min_height, timeout).await.unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+// Carries messages one way between two nodes under the current link
+// conditions. Delivery stays in order, like a TCP connection with delay.
+async fn relay<R, W>(mut from: R, mut to: W, links: Arc<StdMutex<Links>>, sender: usize, receiver: usize)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let codec = Codec::default();
+    let (queue, mut deliveries) = mpsc::unbounded_channel::<(Instant, Message)>();
+    let writer = tokio::spawn(async move {
+        while let Some((deliver_at, message)) = deliveries.recv().await {
+            tokio::time::sleep_until(deliver_at).await;
+            if codec.write(&mut to, &message).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut last_delivery = Instant::now();
+    while let Ok(Some(message)) = codec.read(&mut from).await {
+        let delay = match links.lock().unwrap().delay(sender, receiver) {
+            Some(delay) => delay,
+            None => continue,
+        };
+        last_delivery = last_delivery.max(Instant::now() + delay);
+        if queue.send((last_delivery, message)).is_err() {
+            break;
+        }
+    }
+    drop(queue);
+    let _ = writer.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT: Duration = Duration::from_millis(1000);
+
+    // Keeps the proposers busy with one transaction per slot from rotating nodes.
+    async fn produce(sim: &Simulation, slots: usize, from: &[usize]) {
+        for slot in 0..slots {
+            sim.submit_transaction(from[slot % from.len()]).await.expect("Transaction accepted");
+            sim.run_for(SLOT).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn authorities_take_turns_and_nodes_converge() {
+        let sim = Simulation::new(SimConfig::default()).await;
+        sim.connect_all().await.unwrap();
+        sim.start();
+        produce(&sim, 12, &[0, 1, 2, 3]).await;
+        let height = sim.assert_converged(6, Duration::from_secs(30)).await;
+
+        let blockchain = sim.nodes[3].blockchain.lock().await;
+        let authorities = blockchain.authorities_at(height).to_vec();
+        for block in &blockchain.blocks[1..] {
+            let expected = blockchain.poa.expected_proposer(&authorities, block.index).unwrap();
+            assert_eq!(block.node_id, expected.id, "block {} sealed out of turn", block.index);
+        }
+        let sealed_by: std::collections::HashSet<&str> = blockchain.blocks[1..].iter().map(|block| block.node_id.as_str()).collect();
+        assert_eq!(sealed_by.len(), 3, "every authority sealed blocks");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_node_syncs_the_chain() {
+        let sim = Simulation::new(SimConfig::default()).await;
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            sim.connect(a, b).await.unwrap();
+        }
+        sim.start();
+        produce(&sim, 20, &[0, 1, 2]).await;
+        let height = sim.heights().await[0];
+        assert!(height >= 10, "chain grew to {}", height);
+        assert_eq!(sim.heights().await[3], 0);
+
+        sim.connect(3, 0).await.unwrap();
+        sim.assert_converged(height, Duration::from_secs(30)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partitioned_node_catches_up_after_heal() {
+        let sim = Simulation::new(SimConfig::default()).await;
+        sim.connect_all().await.unwrap();
+        sim.start();
+        produce(&sim, 3, &[0]).await;
+        sim.assert_converged(1, Duration::from_secs(10)).await;
+
+        sim.partition(&[&[0, 1, 2], &[3]]);
+        produce(&sim, 15, &[0, 1, 2]).await;
+        let heights = sim.heights().await;
+        assert!(heights[0] > heights[3] + 5, "majority advanced while node 3 was cut off: {:?}", heights);
+
+        sim.heal();
+        produce(&sim, 3, &[1]).await;
+        sim.assert_converged(heights[0], Duration::from_secs(60)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_slow_links_still_converge() {
+        let sim = Simulation::new(SimConfig { seed: 7, ..SimConfig::default() }).await;
+        sim.connect_all().await.unwrap();
+        sim.set_latency(Duration::from_millis(150), Duration::from_millis(100));
+        sim.set_loss(0.1);
+        sim.start();
+        produce(&sim, 20, &[0, 1, 2, 3]).await;
+
+        // Proposers that fall behind skip their slot, so fewer blocks carry
+        // the same transactions; what matters is that none went missing.
+        sim.set_loss(0.0);
+        produce(&sim, 2, &[0]).await;
+        // A full round, so whoever holds the last transaction gets a turn.
+        sim.run_for(SLOT * 3).await;
+        sim.assert_converged(3, Duration::from_secs(120)).await;
+        let blockchain = sim.nodes[0].blockchain.lock().await;
+        let included: usize = blockchain.blocks.iter().map(|block| block.transactions.len()).sum();
+        assert_eq!(included, 22);
+    }
+
+    fn contract_request(chain_id: &str, action: ContractAction) -> TransactionPayload {
+        TransactionPayload::Contract(ContractRequest {
+            chain_id: chain_id.to_string(),
+            contract_id: "registry".to_string(),
+            action,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn contract_state_is_the_same_everywhere_and_replays_from_genesis() {
+        let sim = Simulation::new(SimConfig::default()).await;
+        sim.connect_all().await.unwrap();
+        sim.start();
+
+        let deploy = ContractAction::Deploy {
+            code: Vec::new(),
+            contract_type: ContractType::MinerRegistration { gpu_type: "simulated".to_string(), ram_capacity: 16.0 },
+        };
+        sim.submit_payload(0, |_| contract_request(DEFAULT_CHAIN_ID, deploy)).await.expect("Deploy accepted");
+        sim.run_for(SLOT).await;
+        for slot in 0..8 {
+            let node = slot % 4;
+            sim.submit_payload(node, |nonce| contract_request(DEFAULT_CHAIN_ID, ContractAction::Execute {
+                input: format!("set node{} {}", node, nonce),
+                gas_limit: 100_000,
+            })).await.expect("Call accepted");
+            sim.run_for(SLOT).await;
+        }
+        let wrong_chain = contract_request("other-chain", ContractAction::Execute { input: "set node0 0".to_string(), gas_limit: 100_000 });
+        assert!(sim.submit_payload(0, |_| wrong_chain).await.is_err());
+        sim.run_for(SLOT * 3).await;
+        sim.assert_converged(3, Duration::from_secs(60)).await;
+
+        let (store, state, state_root) = {
+            let blockchain = sim.nodes[0].blockchain.lock().await;
+            let state = blockchain.contract_manager.contracts["registry"].state.clone();
+            (blockchain.store.clone(), state, blockchain.state_root())
+        };
+        assert_eq!(state.len(), 4, "every node's calls applied: {:?}", state);
+        for network in &sim.nodes[1..] {
+            let blockchain = network.blockchain.lock().await;
+            assert_eq!(blockchain.contract_manager.contracts["registry"].state, state);
+            assert_eq!(blockchain.state_root(), state_root);
+        }
+
+        // Loading the stored blocks again replays every transaction from genesis.
+        let replayed = Blockchain::new(store, DEFAULT_CHAIN_ID.to_string(), PoA::new(SimConfig::default().slot_duration_ms), DEFAULT_SNAPSHOT_INTERVAL)
+            .expect("Chain reloads");
+        assert_eq!(replayed.contract_manager.contracts["registry"].state, state);
+        assert_eq!(replayed.state_root(), state_root);
+    }
+}
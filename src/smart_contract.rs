@@ -1,12 +1,13 @@
 use secp256k1::{Secp256k1, Message, Signature, PublicKey, SecretKey, ecdsa};
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use sled::Db;
-use bincode::{self, serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use crate::public_key_serde::SerializablePublicKey;
+use std::sync::Arc;
+use crate::storage::{ChainStore, WriteBatch};
+use crate::wasm::{self, CallContext, Execution, GAS, OUT_OF_GAS};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum AIModel {
     ImageClassification,
     NaturalLanguageProcessing,
@@ -26,7 +27,7 @@ pub enum AITask {
     },
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct AIInferenceContract {
     pub owner: SerializablePublicKey,
     pub model: AIModel,
@@ -64,7 +65,7 @@ impl AIInferenceContract {
 
 
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SmartContract {
     pub owner: SerializablePublicKey,
     pub code: Vec<u8>,
@@ -82,18 +83,34 @@ impl SmartContract {
         }
     }
 
-    pub fn execute(&mut self, input: &str) -> String {
+    // Contracts with code run it as a WASM module; those without understand
+    // `set key value` and `get key`, charged like the matching host calls.
+    pub fn execute(&mut self, input: &str, context: CallContext, gas_limit: u64) -> Execution {
+        if !self.code.is_empty() {
+            return wasm::execute(&self.code, &mut self.state, input.as_bytes(), context, gas_limit);
+        }
         let parts: Vec<&str> = input.split_whitespace().collect();
-        match parts.as_slice() {
+        let gas = match parts.as_slice() {
+            ["set", key, value] => GAS.call + GAS.storage_write + GAS.storage_per_byte * (key.len() + value.len()) as u64,
+            ["get", key] => GAS.call + GAS.storage_read + GAS.per_byte * key.len() as u64,
+            _ => GAS.call,
+        };
+        if gas > gas_limit {
+            return Execution::failed(gas_limit, OUT_OF_GAS.to_string());
+        }
+        let mut written = Vec::new();
+        let output = match parts.as_slice() {
             ["set", key, value] => {
                 self.state.insert(key.to_string(), value.to_string());
+                written.push(key.to_string());
                 "Set operation completed".to_string()
             },
             ["get", key] => {
                 self.state.get(*key).cloned().unwrap_or_else(|| "Key not found".to_string())
             },
-            _ => "Invalid operation".to_string(),
-        }
+            _ => return Execution::failed(gas, "Invalid operation".to_string()),
+        };
+        Execution { gas_used: gas, result: Ok(output.into_bytes()), events: Vec::new(), written }
     }
 
     pub fn execute_ai_task(&mut self, task: AITask) -> Result<String, String> {
@@ -104,7 +121,7 @@ impl SmartContract {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ContractType {
     MinerRegistration {
         gpu_type: String,
@@ -114,41 +131,143 @@ pub enum ContractType {
     // Add other contract types here as needed
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ContractAction {
+    Deploy {
+        code: Vec<u8>,
+        contract_type: ContractType,
+    },
+    Execute {
+        input: String,
+        gas_limit: u64,
+    },
+}
+
+// A contract deploy or call as the caller signs it in a transaction. The
+// chain ID keeps the transaction from being replayed on another chain; the
+// transaction nonce keeps it from being replayed on this one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractRequest {
+    pub chain_id: String,
+    pub contract_id: String,
+    pub action: ContractAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractEvent {
+    pub contract_id: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContractOutput {
+    pub gas_used: u64,
+    // The call's output, or why it failed and changed nothing.
+    pub result: Result<String, String>,
+    pub events: Vec<ContractEvent>,
+}
+
 #[derive(Clone)]
 pub struct ContractManager {
     pub contracts: HashMap<String, SmartContract>,
-    pub db: Option<Db>,
+    pub store: Arc<dyn ChainStore>,
+    // Contracts deployed, replaced or removed since the last `take_changes`.
+    changed: BTreeSet<String>,
+    // Storage entries written by calls since then, by contract ID and key.
+    changed_entries: BTreeSet<(String, String)>,
 }
 
 impl ContractManager {
-    pub fn new(db: Option<Db>) -> Self {
+    pub fn new(store: Arc<dyn ChainStore>) -> Self {
         ContractManager {
             contracts: HashMap::new(),
-            db,
+            store,
+            changed: BTreeSet::new(),
+            changed_entries: BTreeSet::new(),
         }
     }
 
+    // Drops in-memory contract state before it is rebuilt from the chain.
+    pub fn clear(&mut self) {
+        self.changed.extend(self.contracts.drain().map(|(id, _)| id));
+    }
+
+    // Adds the pending contract changes to `batch`; the caller writes them
+    // together with the block that caused them. A call only rewrites the
+    // storage entries it wrote, not the whole contract. Calls cannot delete
+    // keys, so entries are only ever put; whole contracts are removed.
+    pub fn take_changes(&mut self, batch: &mut WriteBatch) {
+        let changed = std::mem::take(&mut self.changed);
+        for (id, key) in std::mem::take(&mut self.changed_entries) {
+            if changed.contains(&id) {
+                continue;
+            }
+            if let Some(value) = self.contracts.get(&id).and_then(|contract| contract.state.get(&key)) {
+                batch.put_contract_entry(id, key, value.clone());
+            }
+        }
+        for id in changed {
+            match self.contracts.get(&id) {
+                Some(contract) => batch.put_contract(id, contract.clone()),
+                None => batch.remove_contract(id),
+            }
+        }
+    }
+
+    // Replaces the pending changes with what it takes to bring `stored` up
+    // to date, so a restart that replays the chain only rewrites contracts
+    // whose stored state is missing or differs from the chain.
+    pub fn keep_stored(&mut self, stored: &BTreeMap<String, SmartContract>) {
+        self.changed_entries.clear();
+        self.changed = self.contracts.keys().chain(stored.keys())
+            .filter(|id| self.contracts.get(*id) != stored.get(*id))
+            .cloned()
+            .collect();
+        for id in self.changed.iter().filter(|id| stored.contains_key(*id)) {
+            log::warn!("Stored state of contract {} does not match the chain; rewriting it", id);
+        }
+    }
+
+    // Replaces all contracts, e.g. with those from a state snapshot.
+    pub fn restore(&mut self, contracts: BTreeMap<String, SmartContract>) {
+        self.clear();
+        self.changed.extend(contracts.keys().cloned());
+        self.contracts.extend(contracts);
+    }
+
     pub fn deploy_contract(&mut self, id: String, owner: PublicKey, code: Vec<u8>, contract_type: ContractType) -> Result<SmartContract, String> {
         if self.contracts.contains_key(&id) {
             return Err("Contract with this ID already exists".to_string());
         }
+        if !code.is_empty() {
+            wasm::validate(&code)?;
+        }
 
         let contract = SmartContract::new(owner, code, contract_type);
         self.contracts.insert(id.clone(), contract.clone());
-
-        if let Some(db) = &self.db {
-            let serialized_contract = serialize(&contract).map_err(|e| e.to_string())?;
-            db.insert(id.as_bytes(), serialized_contract).map_err(|e| e.to_string())?;
-        }
+        self.changed.insert(id);
 
         Ok(contract)  // Return the contract for details extraction
     }
 
-    pub fn execute_contract(&mut self, id: &str, input: &str) -> String {
-        if let Some(contract) = self.contracts.get_mut(id) {
-            contract.execute(input)
-        } else {
-            "Contract not found".to_string()
+    pub fn execute_contract(&mut self, id: &str, input: &str, context: CallContext, gas_limit: u64) -> ContractOutput {
+        let contract = match self.contracts.get_mut(id) {
+            Some(contract) => contract,
+            None => return ContractOutput { gas_used: 0, result: Err("Contract not found".to_string()), events: Vec::new() },
+        };
+        let execution = contract.execute(input, context, gas_limit);
+        if execution.result.is_ok() {
+            self.changed_entries.extend(execution.written.iter().map(|key| (id.to_string(), key.clone())));
+        }
+        for event in &execution.events {
+            log::info!("Contract {} emitted event {}", id, hex::encode(event));
+        }
+        ContractOutput {
+            gas_used: execution.gas_used,
+            result: execution.result.map(|output| String::from_utf8_lossy(&output).into_owned()),
+            events: execution.events.into_iter()
+                .map(|data| ContractEvent { contract_id: id.to_string(), data })
+                .collect(),
         }
     }
 
@@ -161,13 +280,11 @@ impl ContractManager {
             .map_err(|_| "Verification failed".to_string())
     }
 
-    pub fn check_contract_exists(&self, id: &str) -> bool {
-        if self.contracts.contains_key(id) {
-            true
-        } else if let Some(db) = &self.db {
-            db.contains_key(id.as_bytes()).unwrap_or(false)
-        } else {
-            false
+    // Contracts not loaded into memory are read from the store.
+    pub fn contract(&self, id: &str) -> Result<Option<SmartContract>, String> {
+        match self.contracts.get(id) {
+            Some(contract) => Ok(Some(contract.clone())),
+            None => self.store.contract(id),
         }
     }
 }
@@ -232,3 +349,61 @@ impl GPUResourceContract {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{SledStore, StoreOp};
+
+    #[test]
+    fn call_rewrites_only_the_entries_it_wrote() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store: Arc<dyn ChainStore> = Arc::new(SledStore::open(db.clone()).unwrap());
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let context = CallContext { caller: Some(owner), block_height: 1 };
+        let mut manager = ContractManager::new(store.clone());
+        manager.deploy_contract("kv".to_string(), owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 }).unwrap();
+        for key in ["a", "b", "c"] {
+            assert!(manager.execute_contract("kv", &format!("set {} 1", key), context, 1_000_000).result.is_ok());
+        }
+        let mut batch = WriteBatch::default();
+        manager.take_changes(&mut batch);
+        store.write(batch).unwrap();
+
+        // Stands in for an entry the next call must not touch.
+        let mut batch = WriteBatch::default();
+        batch.put_contract_entry("kv".to_string(), "a".to_string(), "untouched".to_string());
+        store.write(batch).unwrap();
+
+        assert!(manager.execute_contract("kv", "set b 2", context, 1_000_000).result.is_ok());
+        let mut batch = WriteBatch::default();
+        manager.take_changes(&mut batch);
+        assert_eq!(batch.ops.len(), 1);
+        assert!(matches!(&batch.ops[0], StoreOp::PutContractEntry(id, key, value) if id == "kv" && key == "b" && value == "2"));
+        store.write(batch).unwrap();
+
+        let reopened = SledStore::open(db).unwrap();
+        let state = &reopened.contracts().unwrap()["kv"].state;
+        assert_eq!(state, &HashMap::from([
+            ("a".to_string(), "untouched".to_string()),
+            ("b".to_string(), "2".to_string()),
+            ("c".to_string(), "1".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn contracts_missing_from_memory_are_read_from_the_store() {
+        let store: Arc<dyn ChainStore> = Arc::new(SledStore::open(sled::Config::new().temporary(true).open().unwrap()).unwrap());
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let mut contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        contract.state.insert("a".to_string(), "1".to_string());
+        let mut batch = WriteBatch::default();
+        batch.put_contract("kv".to_string(), contract);
+        store.write(batch).unwrap();
+
+        let manager = ContractManager::new(store);
+        assert!(manager.contracts.is_empty());
+        assert_eq!(manager.contract("kv").unwrap().unwrap().state["a"], "1");
+        assert!(manager.contract("missing").unwrap().is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, BTreeSet};
+use secp256k1::PublicKey;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::authority::AuthoritySchedule;
+use crate::block::{write_bytes, BlockHash};
+use crate::merkle::merkle_root;
+use crate::public_key_serde::SerializablePublicKey;
+use crate::smart_contract::{GPUResourceContract, SmartContract};
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 1000;
+// Snapshots kept on disk; older ones are dropped as new ones are taken.
+pub const SNAPSHOTS_KEPT: usize = 2;
+pub const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+pub const MAX_SNAPSHOT_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Leaf kinds, so entries of different kinds can never collide.
+const CONTRACT_LEAF: u8 = 0;
+const RESOURCE_LEAF: u8 = 1;
+const NONCE_LEAF: u8 = 2;
+const AUTHORITY_LEAF: u8 = 3;
+
+// The chain state after the block at `height`: everything blocks change
+// besides the chain itself. Block `height + 1` commits to it with its state
+// root, which is what a snapshot fetched from a peer is checked against.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateSnapshot {
+    pub height: u64,
+    pub block_hash: BlockHash,
+    pub contracts: BTreeMap<String, SmartContract>,
+    pub resources: BTreeMap<String, GPUResourceContract>,
+    pub authority_schedule: AuthoritySchedule,
+    pub nonces: Vec<(SerializablePublicKey, u64)>,
+}
+
+// What a peer tells us about the snapshot it serves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub height: u64,
+    pub block_hash: BlockHash,
+    pub size: u64,
+    pub chunk_count: u32,
+}
+
+impl StateSnapshot {
+    pub fn state_root(&self) -> BlockHash {
+        state_root(
+            self.contracts.iter(),
+            self.resources.iter(),
+            &self.authority_schedule,
+            self.nonces.iter().map(|(key, nonce)| (&key.0, nonce)),
+        )
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| e.to_string())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(bytes).map_err(|e| format!("Cannot decode snapshot: {}", e))
+    }
+
+}
+
+// A snapshot serialized once for serving, so each chunk request only slices
+// the bytes instead of encoding the whole state again.
+#[derive(Debug, Clone)]
+pub struct SerializedSnapshot {
+    pub height: u64,
+    pub block_hash: BlockHash,
+    bytes: Vec<u8>,
+}
+
+impl SerializedSnapshot {
+    pub fn new(snapshot: &StateSnapshot) -> Result<Self, String> {
+        Ok(SerializedSnapshot { height: snapshot.height, block_hash: snapshot.block_hash, bytes: snapshot.to_bytes()? })
+    }
+
+    pub fn manifest(&self) -> SnapshotManifest {
+        SnapshotManifest {
+            height: self.height,
+            block_hash: self.block_hash,
+            size: self.bytes.len() as u64,
+            chunk_count: self.bytes.len().div_ceil(SNAPSHOT_CHUNK_SIZE) as u32,
+        }
+    }
+
+    pub fn chunk(&self, index: u32) -> Option<Vec<u8>> {
+        self.bytes.chunks(SNAPSHOT_CHUNK_SIZE).nth(index as usize).map(<[u8]>::to_vec)
+    }
+}
+
+fn leaf(kind: u8, key: &[u8], value: &[u8]) -> BlockHash {
+    let mut buf = vec![kind];
+    write_bytes(&mut buf, key);
+    write_bytes(&mut buf, value);
+    BlockHash(Sha256::digest(&buf).into())
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("State serializes")
+}
+
+// Merkle root over every piece of chain state. Entries are sorted and maps
+// inside them encoded in key order, so nodes holding the same state agree on
+// the root however their hash maps happen to be laid out.
+pub fn state_root<'a>(
+    contracts: impl Iterator<Item = (&'a String, &'a SmartContract)>,
+    resources: impl Iterator<Item = (&'a String, &'a GPUResourceContract)>,
+    schedule: &AuthoritySchedule,
+    nonces: impl Iterator<Item = (&'a PublicKey, &'a u64)>,
+) -> BlockHash {
+    let mut leaves = Vec::new();
+
+    let contracts: BTreeMap<&String, &SmartContract> = contracts.collect();
+    for (id, contract) in contracts {
+        let state: BTreeMap<&String, &String> = contract.state.iter().collect();
+        let value = encode(&(&contract.owner, &contract.code, &contract.contract_type, state));
+        leaves.push(leaf(CONTRACT_LEAF, id.as_bytes(), &value));
+    }
+
+    let resources: BTreeMap<&String, &GPUResourceContract> = resources.collect();
+    for (node_id, resource) in resources {
+        leaves.push(leaf(RESOURCE_LEAF, node_id.as_bytes(), &encode(resource)));
+    }
+
+    let nonces: BTreeMap<[u8; 33], u64> = nonces.map(|(key, nonce)| (key.serialize(), *nonce)).collect();
+    for (key, nonce) in nonces {
+        leaves.push(leaf(NONCE_LEAF, &key, &nonce.to_be_bytes()));
+    }
+
+    let votes: BTreeMap<Vec<u8>, &BTreeSet<String>> = schedule.votes()
+        .map(|(proposal, voters)| (encode(proposal), voters))
+        .collect();
+    leaves.push(leaf(AUTHORITY_LEAF, &[], &encode(&(&schedule.history, votes))));
+
+    merkle_root(&leaves)
+}
@@ -0,0 +1,1041 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+#[cfg(test)]
+use std::sync::Mutex;
+use sled::{Db, Tree};
+use sled::transaction::{TransactionResult, Transactional};
+use crate::authority::AuthoritySchedule;
+use crate::block::{write_bytes, Block, BlockHash};
+use crate::finality::Justification;
+use crate::peer_manager::PeerEntry;
+use crate::smart_contract::{GPUResourceContract, SmartContract};
+use crate::snapshot::StateSnapshot;
+use crate::transaction::Receipt;
+
+// Version 0 is the original layout with everything in the default tree.
+pub const SCHEMA_VERSION: u32 = 4;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const FINALIZED_KEY: &str = "finalized";
+const HEAD_KEY: &str = "head";
+const AUTHORITY_SCHEDULE_KEY: &str = "schedule";
+const SNAPSHOT_BASE_KEY: &str = "snapshot_base";
+
+// Layout of the version 0 default tree.
+const LEGACY_BLOCK_FORMAT_KEY: &str = "block_format";
+const LEGACY_BLOCK_FORMAT: u32 = 2;
+const LEGACY_AUTHORITY_SCHEDULE_KEY: &str = "authority_schedule";
+const LEGACY_AUTHORITIES_KEY: &str = "authorities";
+const LEGACY_JUSTIFICATION_PREFIX: &str = "justification_";
+
+// Before version 4 each contract's whole state was one record in this tree.
+const LEGACY_CONTRACT_STATE_TREE: &str = "contract_state";
+
+// Everything the chain and the contract manager persist. Reads go through the
+// getters; writes are collected in a `WriteBatch` and applied with `write`.
+pub trait ChainStore: Send + Sync + fmt::Debug {
+    // Canonical block at `height`.
+    fn block_at(&self, height: u64) -> Result<Option<Block>, String>;
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, String>;
+    fn authority_schedule(&self) -> Result<Option<AuthoritySchedule>, String>;
+    fn contract(&self, id: &str) -> Result<Option<SmartContract>, String>;
+    // Every stored contract with its state, e.g. to load them on start.
+    fn contracts(&self) -> Result<BTreeMap<String, SmartContract>, String>;
+    fn justification(&self, height: u64) -> Result<Option<Justification>, String>;
+    fn finalized(&self) -> Result<Option<(u64, BlockHash)>, String>;
+    // Tip of the last fully committed block. Canonical blocks above it are
+    // leftovers of an interrupted commit.
+    fn head(&self) -> Result<Option<(u64, BlockHash)>, String>;
+    fn snapshot(&self, height: u64) -> Result<Option<StateSnapshot>, String>;
+    // Heights of the stored snapshots, lowest first.
+    fn snapshot_heights(&self) -> Result<Vec<u64>, String>;
+    // Canonical blocks up to this height were restored from a snapshot and
+    // have no transactions.
+    fn snapshot_base(&self) -> Result<u64, String>;
+    // Receipts of a block's transactions, kept by block hash so every branch
+    // has its own.
+    fn receipts(&self, block_hash: &BlockHash) -> Result<Option<Vec<Receipt>>, String>;
+    // The peer address book. It is not part of the chain and survives resyncs.
+    fn peers(&self) -> Result<Vec<PeerEntry>, String>;
+    // Applies the whole batch or none of it.
+    fn write(&self, batch: WriteBatch) -> Result<(), String>;
+    fn flush(&self) -> Result<(), String>;
+
+    fn contains_contract(&self, id: &str) -> Result<bool, String> {
+        self.contract(id).map(|contract| contract.is_some())
+    }
+
+    // Stores the block and makes it the canonical block at its height.
+    fn put_block(&self, block: &Block) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        batch.put_block(block.clone());
+        self.write(batch)
+    }
+
+    fn put_authority_schedule(&self, schedule: &AuthoritySchedule) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        batch.put_authority_schedule(schedule.clone());
+        self.write(batch)
+    }
+
+    fn put_contract(&self, id: &str, contract: &SmartContract) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        batch.put_contract(id.to_string(), contract.clone());
+        self.write(batch)
+    }
+
+    fn put_peer(&self, entry: &PeerEntry) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        batch.put_peer(entry.clone());
+        self.write(batch)
+    }
+
+    fn remove_peer(&self, address: &str) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        batch.remove_peer(address.to_string());
+        self.write(batch)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StoreOp {
+    PutBlock(Block),
+    // Drops canonical blocks stored before the batch from this height upwards,
+    // e.g. after a reorg to a shorter branch. Their bodies stay available by hash.
+    TruncateBlocks(u64),
+    PutAuthoritySchedule(AuthoritySchedule),
+    // Stores the contract and replaces all of its storage entries.
+    PutContract(String, SmartContract),
+    // Sets one storage entry of a stored contract: contract ID, key, value.
+    // The batch is rejected if the contract is not stored or put before it.
+    PutContractEntry(String, String, String),
+    RemoveContract(String),
+    PutResource(String, GPUResourceContract),
+    RemoveResource(String),
+    PutJustification(Justification),
+    PutFinalized(u64, BlockHash),
+    SetHead(u64, BlockHash),
+    PutSnapshot(StateSnapshot),
+    RemoveSnapshot(u64),
+    SetSnapshotBase(u64),
+    PutReceipts(BlockHash, Vec<Receipt>),
+    PutPeer(PeerEntry),
+    RemovePeer(String),
+}
+
+// Writes applied together, in order, by `ChainStore::write`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub ops: Vec<StoreOp>,
+}
+
+impl WriteBatch {
+    pub fn put_block(&mut self, block: Block) {
+        self.ops.push(StoreOp::PutBlock(block));
+    }
+
+    pub fn truncate_blocks(&mut self, height: u64) {
+        self.ops.push(StoreOp::TruncateBlocks(height));
+    }
+
+    pub fn put_authority_schedule(&mut self, schedule: AuthoritySchedule) {
+        self.ops.push(StoreOp::PutAuthoritySchedule(schedule));
+    }
+
+    pub fn put_contract(&mut self, id: String, contract: SmartContract) {
+        self.ops.push(StoreOp::PutContract(id, contract));
+    }
+
+    pub fn put_contract_entry(&mut self, id: String, key: String, value: String) {
+        self.ops.push(StoreOp::PutContractEntry(id, key, value));
+    }
+
+    pub fn remove_contract(&mut self, id: String) {
+        self.ops.push(StoreOp::RemoveContract(id));
+    }
+
+    pub fn put_resource(&mut self, node_id: String, resource: GPUResourceContract) {
+        self.ops.push(StoreOp::PutResource(node_id, resource));
+    }
+
+    pub fn remove_resource(&mut self, node_id: String) {
+        self.ops.push(StoreOp::RemoveResource(node_id));
+    }
+
+    pub fn put_justification(&mut self, justification: Justification) {
+        self.ops.push(StoreOp::PutJustification(justification));
+    }
+
+    pub fn put_finalized(&mut self, height: u64, hash: BlockHash) {
+        self.ops.push(StoreOp::PutFinalized(height, hash));
+    }
+
+    pub fn set_head(&mut self, height: u64, hash: BlockHash) {
+        self.ops.push(StoreOp::SetHead(height, hash));
+    }
+
+    pub fn put_snapshot(&mut self, snapshot: StateSnapshot) {
+        self.ops.push(StoreOp::PutSnapshot(snapshot));
+    }
+
+    pub fn remove_snapshot(&mut self, height: u64) {
+        self.ops.push(StoreOp::RemoveSnapshot(height));
+    }
+
+    pub fn set_snapshot_base(&mut self, height: u64) {
+        self.ops.push(StoreOp::SetSnapshotBase(height));
+    }
+
+    pub fn put_receipts(&mut self, block_hash: BlockHash, receipts: Vec<Receipt>) {
+        self.ops.push(StoreOp::PutReceipts(block_hash, receipts));
+    }
+
+    pub fn put_peer(&mut self, entry: PeerEntry) {
+        self.ops.push(StoreOp::PutPeer(entry));
+    }
+
+    pub fn remove_peer(&mut self, address: String) {
+        self.ops.push(StoreOp::RemovePeer(address));
+    }
+}
+
+// The on-disk store. Each kind of record lives in its own sled tree, and
+// numeric keys are big-endian so they iterate in order.
+#[derive(Clone, Debug)]
+pub struct SledStore {
+    db: Db,
+    // height -> block hash of the canonical chain
+    blocks_by_height: Tree,
+    // block hash -> block, including blocks that are no longer canonical
+    blocks_by_hash: Tree,
+    authorities: Tree,
+    // contract id -> contract without its state
+    contracts: Tree,
+    // contract id and storage key -> value, see `contract_entry_key`
+    contract_storage: Tree,
+    // node id -> registered GPU
+    resources: Tree,
+    // height -> justification
+    justifications: Tree,
+    // height -> state snapshot taken after that block
+    snapshots: Tree,
+    // block hash -> receipts of its transactions
+    receipts: Tree,
+    // address -> address book entry
+    peers: Tree,
+    meta: Tree,
+}
+
+// Trees written by `SledStore::write`, in the order of the transaction view.
+#[derive(Clone, Copy)]
+enum Table {
+    BlocksByHeight,
+    BlocksByHash,
+    Authorities,
+    Contracts,
+    ContractStorage,
+    Resources,
+    Justifications,
+    Snapshots,
+    Receipts,
+    Peers,
+    Meta,
+}
+
+// A raw insert, or a removal when there is no value, for `SledStore::write`.
+type Change = (Table, Vec<u8>, Option<Vec<u8>>);
+
+const TABLES: [Table; 11] = [
+    Table::BlocksByHeight,
+    Table::BlocksByHash,
+    Table::Authorities,
+    Table::Contracts,
+    Table::ContractStorage,
+    Table::Resources,
+    Table::Justifications,
+    Table::Snapshots,
+    Table::Receipts,
+    Table::Peers,
+    Table::Meta,
+];
+
+pub fn height_key(height: u64) -> [u8; 8] {
+    height.to_be_bytes()
+}
+
+// The length-prefixed contract ID, so one contract's entries share a prefix
+// no other contract's entries start with.
+fn contract_prefix(id: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + id.len());
+    write_bytes(&mut prefix, id.as_bytes());
+    prefix
+}
+
+fn contract_entry_key(id: &str, key: &str) -> Vec<u8> {
+    let mut entry_key = contract_prefix(id);
+    entry_key.extend_from_slice(key.as_bytes());
+    entry_key
+}
+
+fn decode<T: serde::de::DeserializeOwned>(what: &str, bytes: &[u8]) -> Result<T, String> {
+    bincode::deserialize(bytes).map_err(|e| format!("Cannot decode {}: {}", what, e))
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    bincode::serialize(value).map_err(|e| e.to_string())
+}
+
+impl SledStore {
+    // Opens the trees and brings the database up to the current schema version.
+    pub fn open(db: Db) -> Result<Self, String> {
+        let tree = |name: &str| db.open_tree(name).map_err(|e| e.to_string());
+        let store = SledStore {
+            blocks_by_height: tree("blocks_by_height")?,
+            blocks_by_hash: tree("blocks_by_hash")?,
+            authorities: tree("authorities")?,
+            contracts: tree("contracts")?,
+            contract_storage: tree("contract_storage")?,
+            resources: tree("resources")?,
+            justifications: tree("justifications")?,
+            snapshots: tree("snapshots")?,
+            receipts: tree("receipts")?,
+            peers: tree("peers")?,
+            meta: tree("meta")?,
+            db,
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    pub fn schema_version(&self) -> Result<u32, String> {
+        match self.meta.get(SCHEMA_VERSION_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => {
+                let bytes: [u8; 4] = bytes.as_ref().try_into().map_err(|_| "Invalid schema version".to_string())?;
+                Ok(u32::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn migrate(&self) -> Result<(), String> {
+        let mut version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(format!("Database schema version {} is newer than supported version {}", version, SCHEMA_VERSION));
+        }
+        while version < SCHEMA_VERSION {
+            match version {
+                0 => self.migrate_from_default_tree()?,
+                1 => self.migrate_head_record()?,
+                2 => self.migrate_block_state_root()?,
+                3 => self.migrate_contract_storage()?,
+                _ => unreachable!("Every older schema version has a migration"),
+            }
+            version += 1;
+            self.meta.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes()).map_err(|e| e.to_string())?;
+            self.flush()?;
+            log::info!("Migrated database to schema version {}", version);
+        }
+        Ok(())
+    }
+
+    // Moves blocks, authorities, finality records and contracts out of the
+    // default tree. Old keys are only removed once everything is copied, so an
+    // interrupted migration simply runs again.
+    fn migrate_from_default_tree(&self) -> Result<(), String> {
+        let versioned_blocks = match self.db.get(LEGACY_BLOCK_FORMAT_KEY).map_err(|e| e.to_string())? {
+            Some(format) if format.as_ref() == LEGACY_BLOCK_FORMAT.to_be_bytes() => true,
+            Some(format) => return Err(format!("Unsupported block format {:?}", format.as_ref())),
+            None => false,
+        };
+
+        let mut migrated = Vec::new();
+        let mut legacy_authorities = None;
+        for entry in self.db.iter() {
+            let (key, value) = entry.map_err(|e| e.to_string())?;
+            let name = String::from_utf8_lossy(&key).to_string();
+            if let Ok(height) = name.parse::<u64>() {
+                // Blocks written before the versioned block format keep version 0
+                // so their hashes and signatures still verify.
+                let block = if versioned_blocks {
+                    Block::from_bytes_without_state_root(&value)
+                } else {
+                    Block::from_legacy_bytes(&value)
+                }.map_err(|e| format!("Block {}: {}", height, e))?;
+                self.put_block(&block)?;
+            } else if let Some(height) = name.strip_prefix(LEGACY_JUSTIFICATION_PREFIX).and_then(|h| h.parse::<u64>().ok()) {
+                self.justifications.insert(height_key(height), value).map_err(|e| e.to_string())?;
+            } else if name == LEGACY_AUTHORITY_SCHEDULE_KEY {
+                self.authorities.insert(AUTHORITY_SCHEDULE_KEY, value).map_err(|e| e.to_string())?;
+            } else if name == LEGACY_AUTHORITIES_KEY {
+                legacy_authorities = Some(decode("authorities", &value)?);
+            } else if name == FINALIZED_KEY {
+                self.meta.insert(FINALIZED_KEY, value).map_err(|e| e.to_string())?;
+            } else if name == LEGACY_BLOCK_FORMAT_KEY {
+                // Implied by the schema version from now on.
+            } else {
+                match decode::<SmartContract>("contract", &value) {
+                    Ok(contract) => self.put_contract(&name, &contract)?,
+                    Err(e) => {
+                        log::warn!("Leaving unknown key {} in the default tree: {}", name, e);
+                        continue;
+                    }
+                }
+            }
+            migrated.push(key);
+        }
+
+        // The flat authority list predates the schedule and seeds its genesis set.
+        if let Some(authorities) = legacy_authorities {
+            if self.authority_schedule()?.is_none() {
+                self.put_authority_schedule(&AuthoritySchedule::new(authorities))?;
+            }
+        }
+        self.flush()?;
+        for key in migrated {
+            self.db.remove(key).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Version 1 had no head record; the canonical blocks from genesis up to
+    // the first gap become the head. Only the index is read, as blocks are
+    // still in the layout without a state root.
+    fn migrate_head_record(&self) -> Result<(), String> {
+        let mut head = None;
+        let mut height = 0;
+        while let Some(hash) = self.blocks_by_height.get(height_key(height)).map_err(|e| e.to_string())? {
+            if !self.blocks_by_hash.contains_key(&hash).map_err(|e| e.to_string())? {
+                break;
+            }
+            head = Some((height, BlockHash::from_slice(&hash)?));
+            height += 1;
+        }
+        if let Some((height, hash)) = head {
+            let mut batch = WriteBatch::default();
+            batch.set_head(height, hash);
+            self.write(batch)?;
+        }
+        Ok(())
+    }
+
+    // Version 3 added the state root to blocks. Blocks already in the new
+    // layout, like those a version 0 migration just wrote, are recognised by
+    // their hash and left alone.
+    fn migrate_block_state_root(&self) -> Result<(), String> {
+        for entry in self.blocks_by_hash.iter() {
+            let (key, value) = entry.map_err(|e| e.to_string())?;
+            let block = match Block::from_bytes_without_state_root(&value) {
+                Ok(block) if block.hash == block.calculate_hash() => block,
+                _ => continue,
+            };
+            self.blocks_by_hash.insert(key, encode(&block)?).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Version 4 keeps every storage entry of a contract as its own record.
+    // The old tree is only dropped once all entries are copied.
+    fn migrate_contract_storage(&self) -> Result<(), String> {
+        let legacy = self.db.open_tree(LEGACY_CONTRACT_STATE_TREE).map_err(|e| e.to_string())?;
+        for entry in legacy.iter() {
+            let (id, value) = entry.map_err(|e| e.to_string())?;
+            let id = String::from_utf8(id.to_vec()).map_err(|_| "Contract ID is not UTF-8".to_string())?;
+            let state: HashMap<String, String> = decode("contract state", &value)?;
+            for (key, value) in state {
+                self.contract_storage.insert(contract_entry_key(&id, &key), value.as_bytes()).map_err(|e| e.to_string())?;
+            }
+        }
+        self.flush()?;
+        self.db.drop_tree(LEGACY_CONTRACT_STATE_TREE).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    // A stored contract's storage entries.
+    fn contract_state(&self, id: &str) -> Result<HashMap<String, String>, String> {
+        let prefix = contract_prefix(id);
+        self.contract_storage.scan_prefix(&prefix)
+            .map(|entry| {
+                let (key, value) = entry.map_err(|e| e.to_string())?;
+                let key = String::from_utf8(key[prefix.len()..].to_vec());
+                let value = String::from_utf8(value.to_vec());
+                key.ok().zip(value.ok()).ok_or_else(|| format!("Contract {} has a storage entry that is not UTF-8", id))
+            })
+            .collect()
+    }
+
+    // Removals of every storage entry of a contract, for `write`.
+    fn contract_state_removals(&self, id: &str, changes: &mut Vec<Change>) -> Result<(), String> {
+        for key in self.contract_storage.scan_prefix(contract_prefix(id)).keys() {
+            changes.push((Table::ContractStorage, key.map_err(|e| e.to_string())?.to_vec(), None));
+        }
+        Ok(())
+    }
+
+    fn table(&self, table: Table) -> &Tree {
+        match table {
+            Table::BlocksByHeight => &self.blocks_by_height,
+            Table::BlocksByHash => &self.blocks_by_hash,
+            Table::Authorities => &self.authorities,
+            Table::Contracts => &self.contracts,
+            Table::ContractStorage => &self.contract_storage,
+            Table::Resources => &self.resources,
+            Table::Justifications => &self.justifications,
+            Table::Snapshots => &self.snapshots,
+            Table::Receipts => &self.receipts,
+            Table::Peers => &self.peers,
+            Table::Meta => &self.meta,
+        }
+    }
+}
+
+impl ChainStore for SledStore {
+    fn block_at(&self, height: u64) -> Result<Option<Block>, String> {
+        match self.blocks_by_height.get(height_key(height)).map_err(|e| e.to_string())? {
+            Some(hash) => {
+                let hash = BlockHash::from_slice(&hash)?;
+                self.block(&hash)?
+                    .map(Some)
+                    .ok_or_else(|| format!("Block {} at height {} is indexed but missing", hash, height))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, String> {
+        match self.blocks_by_hash.get(hash.as_bytes()).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("block", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn authority_schedule(&self) -> Result<Option<AuthoritySchedule>, String> {
+        match self.authorities.get(AUTHORITY_SCHEDULE_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("authority schedule", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn contract(&self, id: &str) -> Result<Option<SmartContract>, String> {
+        let bytes = match self.contracts.get(id.as_bytes()).map_err(|e| e.to_string())? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let mut contract: SmartContract = decode("contract", &bytes)?;
+        contract.state = self.contract_state(id)?;
+        Ok(Some(contract))
+    }
+
+    fn contracts(&self) -> Result<BTreeMap<String, SmartContract>, String> {
+        let mut contracts = BTreeMap::new();
+        for entry in self.contracts.iter() {
+            let (id, bytes) = entry.map_err(|e| e.to_string())?;
+            let id = String::from_utf8(id.to_vec()).map_err(|_| "Contract ID is not UTF-8".to_string())?;
+            let mut contract: SmartContract = decode("contract", &bytes)?;
+            contract.state = self.contract_state(&id)?;
+            contracts.insert(id, contract);
+        }
+        Ok(contracts)
+    }
+
+    fn contains_contract(&self, id: &str) -> Result<bool, String> {
+        self.contracts.contains_key(id.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn justification(&self, height: u64) -> Result<Option<Justification>, String> {
+        match self.justifications.get(height_key(height)).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("justification", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn finalized(&self) -> Result<Option<(u64, BlockHash)>, String> {
+        match self.meta.get(FINALIZED_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("finalized block", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn head(&self) -> Result<Option<(u64, BlockHash)>, String> {
+        match self.meta.get(HEAD_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("head", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn snapshot(&self, height: u64) -> Result<Option<StateSnapshot>, String> {
+        match self.snapshots.get(height_key(height)).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("snapshot", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn snapshot_heights(&self) -> Result<Vec<u64>, String> {
+        self.snapshots.iter()
+            .keys()
+            .map(|key| {
+                let key = key.map_err(|e| e.to_string())?;
+                let bytes: [u8; 8] = key.as_ref().try_into().map_err(|_| "Invalid snapshot key".to_string())?;
+                Ok(u64::from_be_bytes(bytes))
+            })
+            .collect()
+    }
+
+    fn snapshot_base(&self) -> Result<u64, String> {
+        match self.meta.get(SNAPSHOT_BASE_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("snapshot base", &bytes),
+            None => Ok(0),
+        }
+    }
+
+    fn receipts(&self, block_hash: &BlockHash) -> Result<Option<Vec<Receipt>>, String> {
+        match self.receipts.get(block_hash.as_bytes()).map_err(|e| e.to_string())? {
+            Some(bytes) => decode("receipts", &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // An unreadable entry only costs us that address, so it is skipped.
+    fn peers(&self) -> Result<Vec<PeerEntry>, String> {
+        let mut peers = Vec::new();
+        for entry in self.peers.iter() {
+            let (address, bytes) = entry.map_err(|e| e.to_string())?;
+            match decode::<PeerEntry>("address book entry", &bytes) {
+                Ok(peer) => peers.push(peer),
+                Err(e) => log::warn!("Skipping address book entry {}: {}", String::from_utf8_lossy(&address), e),
+            }
+        }
+        Ok(peers)
+    }
+
+    // Everything is encoded up front so the sled transaction only has to
+    // insert and remove raw keys across the trees.
+    fn write(&self, batch: WriteBatch) -> Result<(), String> {
+        let mut changes: Vec<Change> = Vec::new();
+        // Whether each contract put or removed so far in the batch is stored.
+        let mut batch_contracts: HashMap<String, bool> = HashMap::new();
+        for op in batch.ops {
+            match op {
+                StoreOp::PutBlock(block) => {
+                    changes.push((Table::BlocksByHash, block.hash.as_bytes().to_vec(), Some(encode(&block)?)));
+                    changes.push((Table::BlocksByHeight, height_key(block.index).to_vec(), Some(block.hash.as_bytes().to_vec())));
+                }
+                StoreOp::TruncateBlocks(height) => {
+                    for key in self.blocks_by_height.range(height_key(height)..).keys() {
+                        changes.push((Table::BlocksByHeight, key.map_err(|e| e.to_string())?.to_vec(), None));
+                    }
+                }
+                StoreOp::PutAuthoritySchedule(schedule) => {
+                    changes.push((Table::Authorities, AUTHORITY_SCHEDULE_KEY.as_bytes().to_vec(), Some(encode(&schedule)?)));
+                }
+                StoreOp::PutContract(id, mut contract) => {
+                    let state: HashMap<String, String> = std::mem::take(&mut contract.state);
+                    changes.push((Table::Contracts, id.as_bytes().to_vec(), Some(encode(&contract)?)));
+                    self.contract_state_removals(&id, &mut changes)?;
+                    for (key, value) in state {
+                        changes.push((Table::ContractStorage, contract_entry_key(&id, &key), Some(value.into_bytes())));
+                    }
+                    batch_contracts.insert(id, true);
+                }
+                StoreOp::PutContractEntry(id, key, value) => {
+                    let stored = match batch_contracts.get(&id) {
+                        Some(stored) => *stored,
+                        None => self.contains_contract(&id)?,
+                    };
+                    if !stored {
+                        return Err(format!("Cannot set entry {} of contract {}: the contract is not stored", key, id));
+                    }
+                    changes.push((Table::ContractStorage, contract_entry_key(&id, &key), Some(value.into_bytes())));
+                }
+                StoreOp::RemoveContract(id) => {
+                    changes.push((Table::Contracts, id.as_bytes().to_vec(), None));
+                    self.contract_state_removals(&id, &mut changes)?;
+                    batch_contracts.insert(id, false);
+                }
+                StoreOp::PutResource(node_id, resource) => {
+                    changes.push((Table::Resources, node_id.into_bytes(), Some(encode(&resource)?)));
+                }
+                StoreOp::RemoveResource(node_id) => {
+                    changes.push((Table::Resources, node_id.into_bytes(), None));
+                }
+                StoreOp::PutJustification(justification) => {
+                    changes.push((Table::Justifications, height_key(justification.height).to_vec(), Some(encode(&justification)?)));
+                }
+                StoreOp::PutFinalized(height, hash) => {
+                    changes.push((Table::Meta, FINALIZED_KEY.as_bytes().to_vec(), Some(encode(&(height, hash))?)));
+                }
+                StoreOp::SetHead(height, hash) => {
+                    changes.push((Table::Meta, HEAD_KEY.as_bytes().to_vec(), Some(encode(&(height, hash))?)));
+                }
+                StoreOp::PutSnapshot(snapshot) => {
+                    changes.push((Table::Snapshots, height_key(snapshot.height).to_vec(), Some(encode(&snapshot)?)));
+                }
+                StoreOp::RemoveSnapshot(height) => {
+                    changes.push((Table::Snapshots, height_key(height).to_vec(), None));
+                }
+                StoreOp::SetSnapshotBase(height) => {
+                    changes.push((Table::Meta, SNAPSHOT_BASE_KEY.as_bytes().to_vec(), Some(encode(&height)?)));
+                }
+                StoreOp::PutReceipts(block_hash, receipts) => {
+                    changes.push((Table::Receipts, block_hash.as_bytes().to_vec(), Some(encode(&receipts)?)));
+                }
+                StoreOp::PutPeer(entry) => {
+                    changes.push((Table::Peers, entry.address.as_bytes().to_vec(), Some(encode(&entry)?)));
+                }
+                StoreOp::RemovePeer(address) => {
+                    changes.push((Table::Peers, address.into_bytes(), None));
+                }
+            }
+        }
+
+        let trees: Vec<&Tree> = TABLES.iter().map(|table| self.table(*table)).collect();
+        let result: TransactionResult<(), String> = trees.as_slice().transaction(|views| {
+            for (table, key, value) in &changes {
+                let view = &views[*table as usize];
+                match value {
+                    Some(value) => view.insert(key.as_slice(), value.as_slice())?,
+                    None => view.remove(key.as_slice())?,
+                };
+            }
+            Ok(())
+        });
+        result.map_err(|e| e.to_string())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+// Keeps everything in memory, for tests and simulated nodes that should not
+// touch the disk.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+struct MemoryState {
+    blocks_by_height: BTreeMap<u64, BlockHash>,
+    blocks_by_hash: HashMap<BlockHash, Block>,
+    authority_schedule: Option<AuthoritySchedule>,
+    contracts: BTreeMap<String, SmartContract>,
+    resources: BTreeMap<String, GPUResourceContract>,
+    justifications: BTreeMap<u64, Justification>,
+    finalized: Option<(u64, BlockHash)>,
+    head: Option<(u64, BlockHash)>,
+    snapshots: BTreeMap<u64, StateSnapshot>,
+    snapshot_base: u64,
+    receipts: HashMap<BlockHash, Vec<Receipt>>,
+    peers: BTreeMap<String, PeerEntry>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("Memory store lock poisoned")
+    }
+}
+
+#[cfg(test)]
+impl ChainStore for MemoryStore {
+    fn block_at(&self, height: u64) -> Result<Option<Block>, String> {
+        let state = self.state();
+        Ok(state.blocks_by_height.get(&height).and_then(|hash| state.blocks_by_hash.get(hash)).cloned())
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, String> {
+        Ok(self.state().blocks_by_hash.get(hash).cloned())
+    }
+
+    fn authority_schedule(&self) -> Result<Option<AuthoritySchedule>, String> {
+        Ok(self.state().authority_schedule.clone())
+    }
+
+    fn contract(&self, id: &str) -> Result<Option<SmartContract>, String> {
+        Ok(self.state().contracts.get(id).cloned())
+    }
+
+    fn contracts(&self) -> Result<BTreeMap<String, SmartContract>, String> {
+        Ok(self.state().contracts.clone())
+    }
+
+    fn justification(&self, height: u64) -> Result<Option<Justification>, String> {
+        Ok(self.state().justifications.get(&height).cloned())
+    }
+
+    fn finalized(&self) -> Result<Option<(u64, BlockHash)>, String> {
+        Ok(self.state().finalized)
+    }
+
+    fn head(&self) -> Result<Option<(u64, BlockHash)>, String> {
+        Ok(self.state().head)
+    }
+
+    fn snapshot(&self, height: u64) -> Result<Option<StateSnapshot>, String> {
+        Ok(self.state().snapshots.get(&height).cloned())
+    }
+
+    fn snapshot_heights(&self) -> Result<Vec<u64>, String> {
+        Ok(self.state().snapshots.keys().copied().collect())
+    }
+
+    fn snapshot_base(&self) -> Result<u64, String> {
+        Ok(self.state().snapshot_base)
+    }
+
+    fn receipts(&self, block_hash: &BlockHash) -> Result<Option<Vec<Receipt>>, String> {
+        Ok(self.state().receipts.get(block_hash).cloned())
+    }
+
+    fn peers(&self) -> Result<Vec<PeerEntry>, String> {
+        Ok(self.state().peers.values().cloned().collect())
+    }
+
+    // Holding the lock for the whole batch makes it atomic for readers, and
+    // applying it to a copy leaves nothing behind when it is rejected.
+    fn write(&self, batch: WriteBatch) -> Result<(), String> {
+        let mut guard = self.state();
+        let mut state = guard.clone();
+        for op in batch.ops {
+            match op {
+                StoreOp::PutBlock(block) => {
+                    state.blocks_by_height.insert(block.index, block.hash);
+                    state.blocks_by_hash.insert(block.hash, block);
+                }
+                StoreOp::TruncateBlocks(height) => {
+                    state.blocks_by_height.split_off(&height);
+                }
+                StoreOp::PutAuthoritySchedule(schedule) => state.authority_schedule = Some(schedule),
+                StoreOp::PutContract(id, contract) => {
+                    state.contracts.insert(id, contract);
+                }
+                StoreOp::PutContractEntry(id, key, value) => match state.contracts.get_mut(&id) {
+                    Some(contract) => {
+                        contract.state.insert(key, value);
+                    }
+                    None => return Err(format!("Cannot set entry {} of contract {}: the contract is not stored", key, id)),
+                },
+                StoreOp::RemoveContract(id) => {
+                    state.contracts.remove(&id);
+                }
+                StoreOp::PutResource(node_id, resource) => {
+                    state.resources.insert(node_id, resource);
+                }
+                StoreOp::RemoveResource(node_id) => {
+                    state.resources.remove(&node_id);
+                }
+                StoreOp::PutJustification(justification) => {
+                    state.justifications.insert(justification.height, justification);
+                }
+                StoreOp::PutFinalized(height, hash) => state.finalized = Some((height, hash)),
+                StoreOp::SetHead(height, hash) => state.head = Some((height, hash)),
+                StoreOp::PutSnapshot(snapshot) => {
+                    state.snapshots.insert(snapshot.height, snapshot);
+                }
+                StoreOp::RemoveSnapshot(height) => {
+                    state.snapshots.remove(&height);
+                }
+                StoreOp::SetSnapshotBase(height) => state.snapshot_base = height,
+                StoreOp::PutReceipts(block_hash, receipts) => {
+                    state.receipts.insert(block_hash, receipts);
+                }
+                StoreOp::PutPeer(entry) => {
+                    state.peers.insert(entry.address.clone(), entry);
+                }
+                StoreOp::RemovePeer(address) => {
+                    state.peers.remove(&address);
+                }
+            }
+        }
+        *guard = state;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use crate::node::Node;
+    use crate::smart_contract::ContractType;
+
+    fn temporary_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    // A block as the original schema stored it, keyed by its height.
+    fn legacy_block(index: u64, previous_hash: &str, hash: BlockHash) -> Vec<u8> {
+        let fields = (index, 1_000 + index as u128, previous_hash.to_string(), hash.to_hex(), 0u64, format!("block {}", index), String::new(), "node1".to_string());
+        bincode::serialize(&fields).unwrap()
+    }
+
+    #[test]
+    fn version_0_database_is_migrated_with_its_blocks_and_contracts() {
+        let db = temporary_db();
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let genesis = legacy_block(0, "0", BlockHash([1; 32]));
+        let second = legacy_block(1, &BlockHash([1; 32]).to_hex(), BlockHash([2; 32]));
+        let mut contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        contract.state.insert("count".to_string(), "2".to_string());
+        contract.state.insert("owner".to_string(), "node1".to_string());
+        let authorities = vec![Node { id: "node1".to_string(), is_authority: true, public_key: owner }];
+
+        db.insert("0", genesis.clone()).unwrap();
+        db.insert("1", second.clone()).unwrap();
+        db.insert("counter", bincode::serialize(&contract).unwrap()).unwrap();
+        db.insert(LEGACY_AUTHORITIES_KEY, bincode::serialize(&authorities).unwrap()).unwrap();
+        db.insert(FINALIZED_KEY, bincode::serialize(&(1u64, BlockHash([2; 32]))).unwrap()).unwrap();
+
+        let store = SledStore::open(db.clone()).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(store.block_at(0).unwrap(), Some(Block::from_legacy_bytes(&genesis).unwrap()));
+        assert_eq!(store.block_at(1).unwrap(), Some(Block::from_legacy_bytes(&second).unwrap()));
+        assert_eq!(store.head().unwrap(), Some((1, BlockHash([2; 32]))));
+        assert_eq!(store.finalized().unwrap(), Some((1, BlockHash([2; 32]))));
+        assert_eq!(store.authority_schedule().unwrap().unwrap().history, AuthoritySchedule::new(authorities).history);
+        assert_eq!(store.contract("counter").unwrap(), Some(contract.clone()));
+        assert_eq!(store.contracts().unwrap(), BTreeMap::from([("counter".to_string(), contract.clone())]));
+        assert!(db.is_empty());
+
+        // Opening the migrated database again changes nothing.
+        let reopened = SledStore::open(db).unwrap();
+        assert_eq!(reopened.contracts().unwrap(), BTreeMap::from([("counter".to_string(), contract)]));
+        assert_eq!(reopened.head().unwrap(), Some((1, BlockHash([2; 32]))));
+    }
+
+    #[test]
+    fn version_3_contract_state_is_split_into_entries() {
+        let db = temporary_db();
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let mut contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        contract.state.insert("count".to_string(), "2".to_string());
+        let state = std::mem::take(&mut contract.state);
+
+        db.open_tree("meta").unwrap().insert(SCHEMA_VERSION_KEY, &3u32.to_be_bytes()).unwrap();
+        db.open_tree("contracts").unwrap().insert("counter", bincode::serialize(&contract).unwrap()).unwrap();
+        db.open_tree(LEGACY_CONTRACT_STATE_TREE).unwrap().insert("counter", bincode::serialize(&state).unwrap()).unwrap();
+
+        let store = SledStore::open(db.clone()).unwrap();
+        contract.state = state;
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(store.contract("counter").unwrap(), Some(contract));
+        assert!(!db.tree_names().iter().any(|name| name.as_ref() == LEGACY_CONTRACT_STATE_TREE.as_bytes()));
+    }
+
+    // One batch changes every table a block commit touches.
+    fn batch_applies_to_every_table(store: &dyn ChainStore) {
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        let first = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "first".to_string(), Vec::new(), "node1".to_string());
+        let replacement = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "replacement".to_string(), Vec::new(), "node1".to_string());
+
+        let mut batch = WriteBatch::default();
+        batch.put_block(first.clone());
+        batch.put_contract("kv".to_string(), contract);
+        batch.put_receipts(first.hash, Vec::new());
+        batch.set_head(0, first.hash);
+        store.write(batch).unwrap();
+
+        let mut batch = WriteBatch::default();
+        batch.truncate_blocks(0);
+        batch.put_block(replacement.clone());
+        batch.remove_contract("kv".to_string());
+        batch.put_receipts(replacement.hash, Vec::new());
+        batch.set_head(0, replacement.hash);
+        store.write(batch).unwrap();
+
+        assert_eq!(store.block_at(0).unwrap(), Some(replacement.clone()));
+        assert_eq!(store.contract("kv").unwrap(), None);
+        assert_eq!(store.receipts(&replacement.hash).unwrap(), Some(Vec::new()));
+        assert_eq!(store.head().unwrap(), Some((0, replacement.hash)));
+    }
+
+    #[test]
+    fn both_stores_apply_a_batch_to_every_table() {
+        let db = temporary_db();
+        batch_applies_to_every_table(&SledStore::open(db.clone()).unwrap());
+        let reopened = SledStore::open(db).unwrap();
+        assert_eq!(reopened.block_at(0).unwrap().unwrap().data, "replacement");
+        batch_applies_to_every_table(&MemoryStore::new());
+    }
+
+    fn rejects_entries_of_contracts_that_are_not_stored(store: &dyn ChainStore) {
+        let mut batch = WriteBatch::default();
+        batch.set_head(7, BlockHash([7; 32]));
+        batch.put_contract_entry("missing".to_string(), "key".to_string(), "value".to_string());
+        assert!(store.write(batch).is_err());
+        assert_eq!(store.head().unwrap(), None);
+        assert!(store.contracts().unwrap().is_empty());
+
+        // A contract put earlier in the same batch is stored by then.
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        let mut batch = WriteBatch::default();
+        batch.put_contract("kv".to_string(), contract.clone());
+        batch.put_contract_entry("kv".to_string(), "key".to_string(), "value".to_string());
+        store.write(batch).unwrap();
+        assert_eq!(store.contract("kv").unwrap().unwrap().state["key"], "value");
+
+        let mut batch = WriteBatch::default();
+        batch.remove_contract("kv".to_string());
+        batch.put_contract_entry("kv".to_string(), "key".to_string(), "again".to_string());
+        assert!(store.write(batch).is_err());
+        assert_eq!(store.contract("kv").unwrap().unwrap().state["key"], "value");
+    }
+
+    #[test]
+    fn both_stores_reject_entries_of_contracts_that_are_not_stored() {
+        rejects_entries_of_contracts_that_are_not_stored(&SledStore::open(temporary_db()).unwrap());
+        rejects_entries_of_contracts_that_are_not_stored(&MemoryStore::new());
+    }
+
+    // A rejected batch leaves every table as the batch before it left them.
+    fn batch_is_all_or_nothing(store: &dyn ChainStore) {
+        let owner = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let contract = SmartContract::new(owner, Vec::new(), ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 });
+        let first = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "first".to_string(), Vec::new(), "node1".to_string());
+        let replacement = Block::new(0, BlockHash::ZERO, BlockHash::ZERO, "replacement".to_string(), Vec::new(), "node1".to_string());
+
+        let mut batch = WriteBatch::default();
+        batch.put_block(first.clone());
+        batch.put_contract("kv".to_string(), contract.clone());
+        batch.put_receipts(first.hash, Vec::new());
+        batch.set_head(0, first.hash);
+        store.write(batch).unwrap();
+
+        let mut batch = WriteBatch::default();
+        batch.truncate_blocks(0);
+        batch.put_block(replacement.clone());
+        batch.remove_contract("kv".to_string());
+        batch.put_receipts(replacement.hash, Vec::new());
+        batch.set_head(0, replacement.hash);
+        batch.put_contract_entry("kv".to_string(), "key".to_string(), "value".to_string());
+        assert!(store.write(batch).is_err());
+
+        assert_eq!(store.block_at(0).unwrap(), Some(first.clone()));
+        assert_eq!(store.block(&replacement.hash).unwrap(), None);
+        assert_eq!(store.contract("kv").unwrap(), Some(contract));
+        assert_eq!(store.receipts(&replacement.hash).unwrap(), None);
+        assert_eq!(store.head().unwrap(), Some((0, first.hash)));
+    }
+
+    #[test]
+    fn both_stores_apply_a_batch_entirely_or_not_at_all() {
+        let db = temporary_db();
+        batch_is_all_or_nothing(&SledStore::open(db.clone()).unwrap());
+        // Nothing of the rejected batch reaches the disk either.
+        let reopened = SledStore::open(db).unwrap();
+        assert_eq!(reopened.block_at(0).unwrap().unwrap().data, "first");
+        batch_is_all_or_nothing(&MemoryStore::new());
+    }
+}
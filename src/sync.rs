@@ -0,0 +1,378 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use crate::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::network::Network;
+use crate::peer_manager::Offence;
+use crate::poa::now_millis;
+use crate::protocol::{InventoryItem, Message, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST, MAX_INVENTORY_ITEMS};
+
+// Blocks requested from one peer at a time.
+const BODY_BATCH: u64 = 32;
+// How long a peer gets to answer a sync request before it goes to another.
+const SYNC_REQUEST_TIMEOUT_MS: u128 = 10_000;
+// First step back from our tip when a peer's headers do not connect to it.
+const INITIAL_LOOKBACK: u64 = 8;
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+// How often peers are asked for their tip and pending transactions are
+// announced again, in case an announcement was lost.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+// How long a one-shot command waits for sync to make progress.
+const SYNC_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct SyncPeer {
+    best_height: u64,
+    // Set when the peer had nothing we could use, until it shows a new block.
+    exhausted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestKind {
+    Headers,
+    Bodies,
+}
+
+#[derive(Debug)]
+struct Request {
+    kind: RequestKind,
+    from_height: u64,
+    count: u64,
+    sent_ms: u128,
+}
+
+// Headers-first chain sync. Headers above our tip come from the peer with
+// the best chain and are checked for linkage and authority signatures before
+// any body is fetched. Bodies are then requested in batches from every peer
+// that has them and imported in height order. Everything imported is
+// committed, so a restart resumes from the new tip.
+#[derive(Debug, Default)]
+pub struct ChainSync {
+    peers: HashMap<String, SyncPeer>,
+    // At most one outstanding request per peer.
+    requests: HashMap<String, Request>,
+    // Checked headers of blocks we do not hold yet, contiguous by height.
+    headers: BTreeMap<u64, BlockHeader>,
+    bodies: BTreeMap<u64, Block>,
+    // How far below our tip header requests start while looking for where
+    // a peer's chain leaves ours.
+    lookback: u64,
+}
+
+impl ChainSync {
+    pub fn new() -> Self {
+        ChainSync::default()
+    }
+
+    // `has_tip` says we already hold the peer's best block.
+    pub fn add_peer(&mut self, address: &str, best_height: u64, has_tip: bool) {
+        self.peers.insert(address.to_string(), SyncPeer { best_height, exhausted: has_tip });
+    }
+
+    pub fn remove_peer(&mut self, address: &str) {
+        self.peers.remove(address);
+        self.requests.remove(address);
+    }
+
+    // A peer showed us a block at `height`.
+    pub fn peer_advanced(&mut self, address: &str, height: u64) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            if height > peer.best_height {
+                peer.best_height = height;
+                peer.exhausted = false;
+            }
+        }
+    }
+
+    // Nothing left to download from the peers we know.
+    pub fn is_idle(&self, our_height: u64) -> bool {
+        self.headers.is_empty() && !self.peers.values().any(|peer| !peer.exhausted && peer.best_height > our_height)
+    }
+
+    fn reset(&mut self) {
+        self.headers.clear();
+        self.bodies.clear();
+        self.lookback = 0;
+    }
+
+    fn exhaust(&mut self, address: &str) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.exhausted = true;
+        }
+    }
+
+    fn take_request(&mut self, address: &str, kind: RequestKind) -> Result<Request, String> {
+        match self.requests.get(address) {
+            Some(request) if request.kind == kind => Ok(self.requests.remove(address).expect("Request exists")),
+            _ => Err(format!("Unsolicited {:?} response", kind)),
+        }
+    }
+
+    // Checks headers a peer sent for our request and queues the ones we
+    // lack for download. An error means the peer sent something invalid.
+    pub fn on_headers(&mut self, address: &str, headers: Vec<BlockHeader>, blockchain: &Blockchain) -> Result<(), String> {
+        let request = self.take_request(address, RequestKind::Headers)?;
+        if headers.len() as u64 > request.count {
+            return Err(format!("{} headers for a request of {}", headers.len(), request.count));
+        }
+        let first = match headers.first() {
+            Some(first) => first,
+            None => {
+                self.exhaust(address);
+                return Ok(());
+            }
+        };
+        if first.index != request.from_height {
+            return Err(format!("Headers start at height {} instead of {}", first.index, request.from_height));
+        }
+
+        let mut parent = match self.headers.last_key_value() {
+            Some((_, last)) if last.hash == first.previous_hash => last.clone(),
+            Some(_) => {
+                // The chain we were following changed under us; start over.
+                self.reset();
+                return Ok(());
+            }
+            None => match blockchain.tree.get(&first.previous_hash) {
+                Some(block) => block.header(),
+                None if request.from_height <= blockchain.finality.finalized_height + 1 => {
+                    log::info!("Peer {} is not on our finalized chain", address);
+                    self.exhaust(address);
+                    self.lookback = 0;
+                    return Ok(());
+                }
+                None => {
+                    self.lookback = (self.lookback * 2).max(INITIAL_LOOKBACK);
+                    return Ok(());
+                }
+            },
+        };
+        self.lookback = 0;
+
+        let limit = blockchain.verifiable_height();
+        let last_height = headers.last().map_or(0, |header| header.index);
+        for header in headers {
+            if header.index > limit {
+                break;
+            }
+            blockchain.check_header(&header, &parent)?;
+            if !blockchain.tree.contains(&header.hash) {
+                self.headers.insert(header.index, header.clone());
+            }
+            parent = header;
+        }
+        // A short answer means the peer has nothing beyond it.
+        if last_height < request.from_height + request.count - 1 {
+            if let Some(peer) = self.peers.get_mut(address) {
+                peer.best_height = last_height;
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the blocks a peer sent for our request that match their checked
+    // headers. An error means the peer sent something invalid.
+    pub fn on_blocks(&mut self, address: &str, blocks: Vec<Block>) -> Result<(), String> {
+        let request = self.take_request(address, RequestKind::Bodies)?;
+        if blocks.len() as u64 > request.count {
+            return Err(format!("{} blocks for a request of {}", blocks.len(), request.count));
+        }
+        let received = blocks.len() as u64;
+        for (height, block) in (request.from_height..).zip(blocks) {
+            if block.index != height {
+                return Err(format!("Expected block at height {}, got {}", height, block.index));
+            }
+            match self.headers.get(&height) {
+                Some(header) if *header == block.header() => {
+                    if block.merkle_root != block.calculate_merkle_root() {
+                        return Err(format!("Block {}: Merkle root does not match transactions", height));
+                    }
+                    self.bodies.insert(height, block);
+                }
+                // The peer follows another branch than the headers we have.
+                Some(_) => {
+                    self.exhaust(address);
+                    return Ok(());
+                }
+                // Headers were reset, or the block arrived another way.
+                None => {}
+            }
+        }
+        if received < request.count {
+            if let Some(peer) = self.peers.get_mut(address) {
+                peer.best_height = peer.best_height.min(request.from_height + received).saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    // Imports downloaded blocks in height order as far as they are
+    // contiguous and returns their hashes.
+    pub fn import_ready(&mut self, blockchain: &mut Blockchain) -> Vec<BlockHash> {
+        let mut imported = Vec::new();
+        while let Some((&height, header)) = self.headers.first_key_value() {
+            if blockchain.tree.contains(&header.hash) {
+                self.headers.remove(&height);
+                self.bodies.remove(&height);
+                continue;
+            }
+            let block = match self.bodies.remove(&height) {
+                Some(block) => block,
+                None => break,
+            };
+            self.headers.remove(&height);
+            let hash = block.hash;
+            if let Err(e) = blockchain.import_block(block) {
+                log::warn!("Dropping {} synced headers: block {} at height {} failed: {}", self.headers.len(), hash, height, e);
+                self.reset();
+                break;
+            }
+            imported.push(hash);
+        }
+        imported
+    }
+
+    // Expires overdue requests and picks the next ones to send. Returns the
+    // peers that missed their deadline and the requests by peer.
+    pub fn next_requests(&mut self, blockchain: &Blockchain) -> (Vec<String>, Vec<(String, Message)>) {
+        let now = now_millis();
+        let mut timed_out = Vec::new();
+        self.requests.retain(|address, request| {
+            let live = now < request.sent_ms + SYNC_REQUEST_TIMEOUT_MS;
+            if !live {
+                timed_out.push(address.clone());
+            }
+            live
+        });
+        let mut messages = Vec::new();
+        if blockchain.blocks.is_empty() {
+            return (timed_out, messages);
+        }
+
+        if !self.requests.values().any(|request| request.kind == RequestKind::Headers) {
+            let tip = blockchain.blocks.len() as u64 - 1;
+            let from_height = match self.headers.last_key_value() {
+                Some((&height, _)) => height + 1,
+                None => (tip + 1).saturating_sub(self.lookback).max(blockchain.finality.finalized_height + 1),
+            };
+            let limit = blockchain.verifiable_height();
+            let source = self.peers.iter()
+                .filter(|(address, peer)| !peer.exhausted && peer.best_height >= from_height && !self.requests.contains_key(*address))
+                .max_by_key(|(address, peer)| (peer.best_height, Reverse(*address)))
+                .map(|(address, _)| address.clone());
+            if let (Some(address), true) = (source, from_height <= limit) {
+                let count = (limit - from_height + 1).min(MAX_HEADERS_PER_REQUEST as u64);
+                self.requests.insert(address.clone(), Request { kind: RequestKind::Headers, from_height, count, sent_ms: now });
+                messages.push((address, Message::GetHeaders { from_height, max: count as u32 }));
+            }
+        }
+
+        // Hand out the lowest missing bodies first, one batch per idle peer.
+        let requested: Vec<(u64, u64)> = self.requests.values()
+            .filter(|request| request.kind == RequestKind::Bodies)
+            .map(|request| (request.from_height, request.from_height + request.count))
+            .collect();
+        let missing = |height: u64| {
+            self.headers.contains_key(&height)
+                && !self.bodies.contains_key(&height)
+                && !requested.iter().any(|&(from, to)| (from..to).contains(&height))
+        };
+        let mut idle: Vec<(&String, u64)> = self.peers.iter()
+            .filter(|(address, peer)| !peer.exhausted && !self.requests.contains_key(*address))
+            .map(|(address, peer)| (address, peer.best_height))
+            .collect();
+        idle.sort_by_key(|&(address, best_height)| (Reverse(best_height), address.clone()));
+        let mut batches = Vec::new();
+        let mut next = self.headers.first_key_value().map_or(0, |(&height, _)| height);
+        let last = self.headers.last_key_value().map_or(0, |(&height, _)| height);
+        for (address, best_height) in idle {
+            while next <= last && !missing(next) {
+                next += 1;
+            }
+            if next > last || best_height < next {
+                continue;
+            }
+            let mut count = 0;
+            while count < BODY_BATCH.min(MAX_BLOCKS_PER_REQUEST as u64) && next + count <= best_height && missing(next + count) {
+                count += 1;
+            }
+            batches.push((address.clone(), next, count));
+            next += count;
+        }
+        for (address, from_height, count) in batches {
+            self.requests.insert(address.clone(), Request { kind: RequestKind::Bodies, from_height, count, sent_ms: now });
+            messages.push((address, Message::GetBlocks { from_height, max: count as u32 }));
+        }
+        (timed_out, messages)
+    }
+}
+
+pub fn best_height(blockchain: &Blockchain) -> u64 {
+    blockchain.blocks.len().saturating_sub(1) as u64
+}
+
+// Imports what has arrived, penalises peers that let requests expire and
+// sends the next requests.
+pub async fn step(network: &Network) {
+    let mut blockchain = network.blockchain.lock().await;
+    let mut peers = network.peers.lock().await;
+    let mut gossip = network.gossip.lock().await;
+    let mut sync = network.sync.lock().await;
+
+    let imported = sync.import_ready(&mut blockchain);
+    if !imported.is_empty() {
+        gossip.connect_orphans(&mut blockchain, imported);
+        log::info!("Synced to height {}", best_height(&blockchain));
+    }
+    let (timed_out, requests) = sync.next_requests(&blockchain);
+    for address in timed_out {
+        log::info!("Sync request to {} timed out", address);
+        peers.penalize(&address, Offence::Timeout);
+    }
+    for (address, message) in requests {
+        if !gossip.send_to(&address, message) {
+            sync.requests.remove(&address);
+        }
+    }
+}
+
+// Keeps sync moving while the node runs.
+pub async fn run_sync(network: Network) {
+    let mut last_status_poll = tokio::time::Instant::now();
+    loop {
+        tokio::time::sleep(SYNC_INTERVAL).await;
+        step(&network).await;
+        if last_status_poll.elapsed() >= STATUS_INTERVAL {
+            let pending: Vec<InventoryItem> = network.blockchain.lock().await.mempool.entries().into_iter()
+                .take(MAX_INVENTORY_ITEMS)
+                .map(|entry| InventoryItem::Transaction(entry.hash))
+                .collect();
+            let gossip = network.gossip.lock().await;
+            gossip.send_to_all(&Message::GetStatus, None);
+            if !pending.is_empty() {
+                gossip.send_to_all(&Message::Inventory(pending), None);
+            }
+            last_status_poll = tokio::time::Instant::now();
+        }
+    }
+}
+
+// Syncs until no connected peer has blocks we lack, or nothing arrived for
+// `SYNC_STALL_TIMEOUT`.
+pub async fn catch_up(network: &Network) {
+    let mut height = best_height(&*network.blockchain.lock().await);
+    let mut last_progress = Instant::now();
+    loop {
+        step(network).await;
+        let current = best_height(&*network.blockchain.lock().await);
+        if current != height {
+            height = current;
+            last_progress = Instant::now();
+        }
+        if network.sync.lock().await.is_idle(current) || last_progress.elapsed() > SYNC_STALL_TIMEOUT {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
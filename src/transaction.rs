@@ -0,0 +1,174 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use secp256k1::{Secp256k1, SecretKey, PublicKey, Message, ecdsa::Signature};
+use hex::decode;
+use crate::block::{BlockHash, write_bytes};
+use crate::public_key_serde::SerializablePublicKey;
+use crate::smart_contract::{ContractAction, ContractEvent, ContractRequest};
+
+pub type TxHash = BlockHash;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransactionPayload {
+    GpuRegistration {
+        node_id: String,
+        gpu_type: String,
+        vram_capacity: f64,
+        cuda_cores: u32,
+    },
+    // Cast by a current authority to add or remove `node_id` from the set.
+    AuthorityVote {
+        node_id: String,
+        public_key: SerializablePublicKey,
+        add: bool,
+    },
+    // A contract deploy or call, bound to one chain by its chain ID.
+    Contract(ContractRequest),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub sender: SerializablePublicKey,
+    pub nonce: u64,
+    pub fee: u64,
+    pub payload: TransactionPayload,
+    pub signature: String,
+}
+
+// What applying a transaction did. Only contract calls use gas.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub tx_hash: TxHash,
+    pub success: bool,
+    pub gas_used: u64,
+    // Output of a contract call, or why the transaction failed.
+    pub output: String,
+    pub events: Vec<ContractEvent>,
+}
+
+impl Transaction {
+    pub fn new(sender: PublicKey, nonce: u64, fee: u64, payload: TransactionPayload) -> Self {
+        Transaction {
+            sender: SerializablePublicKey(sender),
+            nonce,
+            fee,
+            payload,
+            signature: String::new(),
+        }
+    }
+
+    // Everything the sender signs: compressed public key, nonce, fee and the
+    // length-prefixed bincode encoding of the payload.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let payload = bincode::serialize(&self.payload).expect("Payload should serialize");
+        let mut buf = Vec::with_capacity(33 + 8 + 8 + 4 + payload.len());
+        buf.extend_from_slice(&self.sender.0.serialize());
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_be_bytes());
+        write_bytes(&mut buf, &payload);
+        buf
+    }
+
+    // Most gas the transaction may use, which counts towards the block's gas limit.
+    pub fn gas_limit(&self) -> u64 {
+        match &self.payload {
+            TransactionPayload::Contract(ContractRequest { action: ContractAction::Execute { gas_limit, .. }, .. }) => *gas_limit,
+            _ => 0,
+        }
+    }
+
+    pub fn signing_hash(&self) -> BlockHash {
+        BlockHash(Sha256::digest(&self.signing_bytes()).into())
+    }
+
+    // Transaction ID, committed to by the block's Merkle root. Covers the signature.
+    pub fn hash(&self) -> TxHash {
+        let mut buf = self.signing_bytes();
+        write_bytes(&mut buf, self.signature.as_bytes());
+        BlockHash(Sha256::digest(&buf).into())
+    }
+
+    pub fn sign(&mut self, secret_key: &SecretKey) {
+        let secp = Secp256k1::new();
+        let message = Message::from_slice(self.signing_hash().as_bytes()).expect("Hash should be 32 bytes");
+        self.signature = secp.sign_ecdsa(&message, secret_key).to_string();
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        let secp = Secp256k1::new();
+        let sig_bytes = decode(&self.signature).map_err(|_| "Signature is not valid hex".to_string())?;
+        let sig = Signature::from_der(&sig_bytes).map_err(|_| "Invalid signature format".to_string())?;
+        let message = Message::from_slice(self.signing_hash().as_bytes()).expect("Hash should be 32 bytes");
+        secp.verify_ecdsa(&message, &sig, &self.sender.0)
+            .map_err(|_| "Transaction signature verification failed".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smart_contract::ContractType;
+
+    fn secret(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn call(chain_id: &str) -> Transaction {
+        let sender = PublicKey::from_secret_key(&Secp256k1::new(), &secret(1));
+        let mut tx = Transaction::new(sender, 7, 1, TransactionPayload::Contract(ContractRequest {
+            chain_id: chain_id.to_string(),
+            contract_id: "kv".to_string(),
+            action: ContractAction::Execute { input: "set a 1".to_string(), gas_limit: 1_000 },
+        }));
+        tx.sign(&secret(1));
+        tx
+    }
+
+    #[test]
+    fn signature_covers_the_sender_nonce_and_payload() {
+        assert!(call("main").verify_signature().is_ok());
+
+        let mut replayed = call("main");
+        replayed.nonce += 1;
+        let mut other_chain = call("main");
+        other_chain.payload = call("other").payload;
+        let mut other_sender = call("main");
+        other_sender.sender = SerializablePublicKey(PublicKey::from_secret_key(&Secp256k1::new(), &secret(2)));
+        let mut forged = call("main");
+        forged.sign(&secret(2));
+        for tx in [replayed, other_chain, other_sender, forged] {
+            assert_eq!(tx.verify_signature(), Err("Transaction signature verification failed".to_string()));
+        }
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        let mut tx = call("main");
+        tx.signature = "zz".to_string();
+        assert_eq!(tx.verify_signature(), Err("Signature is not valid hex".to_string()));
+        tx.signature = "00".repeat(70);
+        assert_eq!(tx.verify_signature(), Err("Invalid signature format".to_string()));
+        tx.signature.clear();
+        assert!(tx.verify_signature().is_err());
+    }
+
+    // POST /transactions decodes its body like this; only `Contract`, which
+    // names its chain, may carry a contract deploy or call.
+    #[test]
+    fn contract_calls_without_a_chain_id_are_refused() {
+        let mut json = serde_json::to_value(call("main")).unwrap();
+        assert!(serde_json::from_value::<Transaction>(json.clone()).is_ok());
+        for payload in [
+            serde_json::json!({ "ContractCall": { "id": "kv", "input": "set a 1", "gas_limit": 1_000 } }),
+            serde_json::json!({ "LegacyContractCall": { "id": "kv", "input": "set a 1" } }),
+            serde_json::json!({ "ContractDeploy": {
+                "id": "kv",
+                "code": [],
+                "contract_type": ContractType::MinerRegistration { gpu_type: "A100".to_string(), ram_capacity: 80.0 },
+            } }),
+        ] {
+            json["payload"] = payload;
+            assert!(serde_json::from_value::<Transaction>(json.clone()).is_err());
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use hkdf::Hkdf;
+use secp256k1::{Secp256k1, SecretKey, PublicKey, ecdh::SharedSecret};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::protocol::{Codec, Message};
+use crate::public_key_serde::SerializablePublicKey;
+
+const TRANSPORT_DOMAIN: &[u8] = b"cognichain/transport";
+// Largest plaintext sealed into one record; each record adds a 16-byte tag.
+const MAX_RECORD_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+// Whether this node encrypts its peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    // Plaintext only; peers asking for encryption are answered in plaintext.
+    Disabled,
+    // Ask for encryption but fall back to plaintext if the peer declines.
+    #[default]
+    Enabled,
+    // Refuse any peer that does not encrypt.
+    Required,
+}
+
+impl Encryption {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" | "disabled" => Ok(Encryption::Disabled),
+            "on" | "enabled" => Ok(Encryption::Enabled),
+            "required" => Ok(Encryption::Required),
+            other => Err(format!("Unknown encryption mode '{}', expected off, on or required", other)),
+        }
+    }
+}
+
+// One direction of an encrypted connection.
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8]) -> Self {
+        CipherState { cipher: ChaCha20Poly1305::new(Key::from_slice(key)), counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> io::Result<[u8; 12]> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::other("Transport nonce exhausted"))?;
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| io::Error::other("Failed to encrypt record"))
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher.decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Record failed authentication"))
+    }
+}
+
+struct Session {
+    send: CipherState,
+    recv: CipherState,
+}
+
+// A peer connection that is either plaintext or sealed with ChaCha20-Poly1305
+// records (u32 big-endian length, then ciphertext). Keys come from an ECDH
+// exchange of ephemeral secp256k1 keys, so every connection has fresh keys.
+pub struct SecureStream<S> {
+    inner: S,
+    session: Option<Session>,
+    session_id: [u8; 32],
+    // Plaintext written but not yet sealed, and sealed bytes not yet sent.
+    pending: Vec<u8>,
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    // The record being received, and the opened record being handed out.
+    incoming: Vec<u8>,
+    readable: Vec<u8>,
+    readable_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SecureStream<S> {
+    fn plaintext(inner: S) -> Self {
+        SecureStream::with_session(inner, None, [0u8; 32])
+    }
+
+    fn with_session(inner: S, session: Option<Session>, session_id: [u8; 32]) -> Self {
+        SecureStream {
+            inner,
+            session,
+            session_id,
+            pending: Vec::new(),
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+            incoming: Vec::new(),
+            readable: Vec::new(),
+            readable_pos: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.session.is_some()
+    }
+
+    // Unique to this connection when encrypted, all zeros otherwise. The
+    // identity handshake signs it so a relay cannot splice two sessions.
+    pub fn session_id(&self) -> [u8; 32] {
+        self.session_id
+    }
+
+    // Client side: offer an ephemeral key unless encryption is disabled.
+    pub async fn connect(mut inner: S, codec: &Codec, mode: Encryption) -> Result<Self, String> {
+        let ephemeral = (mode != Encryption::Disabled).then(ephemeral_key);
+        let offer = Message::Transport { ephemeral_key: ephemeral.map(|(_, public)| SerializablePublicKey(public)) };
+        let answer = match codec.request(&mut inner, &offer).await? {
+            Message::Transport { ephemeral_key } => ephemeral_key.map(|key| key.0),
+            other => return Err(format!("Expected transport negotiation, got {:?}", other)),
+        };
+        match (ephemeral, answer) {
+            (Some((secret, public)), Some(peer)) => Ok(SecureStream::encrypted(inner, &secret, &public, &peer, true)),
+            (_, None) if mode == Encryption::Required => Err("Peer does not support encryption".to_string()),
+            (None, Some(_)) => Err("Peer encrypted a connection we asked to keep in plaintext".to_string()),
+            (_, None) => Ok(SecureStream::plaintext(inner)),
+        }
+    }
+
+    // Server side of `connect`.
+    pub async fn accept(mut inner: S, codec: &Codec, mode: Encryption) -> Result<Self, String> {
+        let offer = match codec.read(&mut inner).await?.ok_or_else(|| "Peer closed the connection".to_string())? {
+            Message::Transport { ephemeral_key } => ephemeral_key.map(|key| key.0),
+            other => return Err(format!("Expected transport negotiation, got {:?}", other)),
+        };
+        match offer {
+            Some(peer) if mode != Encryption::Disabled => {
+                let (secret, public) = ephemeral_key();
+                codec.write(&mut inner, &Message::Transport { ephemeral_key: Some(SerializablePublicKey(public)) }).await?;
+                Ok(SecureStream::encrypted(inner, &secret, &public, &peer, false))
+            }
+            None if mode == Encryption::Required => {
+                codec.write(&mut inner, &Message::Transport { ephemeral_key: None }).await?;
+                Err("Peer asked for a plaintext connection".to_string())
+            }
+            _ => {
+                codec.write(&mut inner, &Message::Transport { ephemeral_key: None }).await?;
+                Ok(SecureStream::plaintext(inner))
+            }
+        }
+    }
+
+    fn encrypted(inner: S, secret: &SecretKey, public: &PublicKey, peer: &PublicKey, initiator: bool) -> Self {
+        let shared = SharedSecret::new(peer, secret);
+        let (initiator_key, responder_key) = if initiator { (public, peer) } else { (peer, public) };
+        let mut salt = Vec::with_capacity(66);
+        salt.extend_from_slice(&initiator_key.serialize());
+        salt.extend_from_slice(&responder_key.serialize());
+
+        let mut okm = [0u8; 96];
+        Hkdf::<Sha256>::new(Some(&salt), &shared).expand(TRANSPORT_DOMAIN, &mut okm)
+            .expect("96 bytes is a valid HKDF output length");
+        let (to_responder, to_initiator) = (CipherState::new(&okm[..32]), CipherState::new(&okm[32..64]));
+        let session = if initiator {
+            Session { send: to_responder, recv: to_initiator }
+        } else {
+            Session { send: to_initiator, recv: to_responder }
+        };
+        let mut session_id = [0u8; 32];
+        session_id.copy_from_slice(&okm[64..]);
+        SecureStream::with_session(inner, Some(session), session_id)
+    }
+
+    // Seals any pending plaintext and writes every sealed byte to the socket.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            while self.outgoing_pos < self.outgoing.len() {
+                let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.outgoing_pos..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.outgoing_pos += n;
+            }
+            self.outgoing.clear();
+            self.outgoing_pos = 0;
+            if self.pending.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let session = self.session.as_mut().expect("Only encrypted streams buffer plaintext");
+            let record = session.send.seal(&self.pending)?;
+            self.pending.clear();
+            self.outgoing.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            self.outgoing.extend_from_slice(&record);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.session.is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.readable_pos < this.readable.len() {
+                let n = buf.remaining().min(this.readable.len() - this.readable_pos);
+                buf.put_slice(&this.readable[this.readable_pos..this.readable_pos + n]);
+                this.readable_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let needed = if this.incoming.len() < 4 {
+                4
+            } else {
+                4 + u32::from_be_bytes([this.incoming[0], this.incoming[1], this.incoming[2], this.incoming[3]]) as usize
+            };
+            if this.incoming.len() == needed && needed > 4 {
+                let session = this.session.as_mut().expect("Checked above");
+                this.readable = session.recv.open(&this.incoming[4..])?;
+                this.readable_pos = 0;
+                this.incoming.clear();
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let want = (needed - this.incoming.len()).min(chunk.len());
+            let mut chunk_buf = ReadBuf::new(&mut chunk[..want]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            let filled = chunk_buf.filled();
+            if filled.is_empty() {
+                return if this.incoming.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.incoming.extend_from_slice(filled);
+            if this.incoming.len() == 4 {
+                let len = u32::from_be_bytes([this.incoming[0], this.incoming[1], this.incoming[2], this.incoming[3]]) as usize;
+                if !(TAG_LEN + 1..=MAX_RECORD_SIZE + TAG_LEN).contains(&len) {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid record length {}", len))));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.session.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.pending.len() >= MAX_RECORD_SIZE {
+            ready!(this.poll_drain(cx))?;
+        }
+        let n = buf.len().min(MAX_RECORD_SIZE - this.pending.len());
+        this.pending.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn ephemeral_key() -> (SecretKey, PublicKey) {
+    loop {
+        if let Ok(secret) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+            return (secret, PublicKey::from_secret_key(&Secp256k1::new(), &secret));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    // The two ends of an encrypted connection over `client` and `server`,
+    // keyed as `connect` and `accept` would key them.
+    fn encrypted_pair(client: DuplexStream, server: DuplexStream) -> (SecureStream<DuplexStream>, SecureStream<DuplexStream>) {
+        let (client_secret, client_public) = ephemeral_key();
+        let (server_secret, server_public) = ephemeral_key();
+        (
+            SecureStream::encrypted(client, &client_secret, &client_public, &server_public, true),
+            SecureStream::encrypted(server, &server_secret, &server_public, &client_public, false),
+        )
+    }
+
+    // Seals `payload` as one record and returns the bytes sent on the wire.
+    async fn sealed(payload: &[u8]) -> (Vec<u8>, SecureStream<DuplexStream>, DuplexStream) {
+        let (client, mut wire) = duplex(1024 * 1024);
+        let (server, raw) = duplex(1024 * 1024);
+        let (mut sender, receiver) = encrypted_pair(client, server);
+        sender.write_all(payload).await.unwrap();
+        sender.flush().await.unwrap();
+        drop(sender);
+        let mut record = Vec::new();
+        wire.read_to_end(&mut record).await.unwrap();
+        (record, receiver, raw)
+    }
+
+    #[tokio::test]
+    async fn encrypted_stream_round_trips_payloads_larger_than_a_record() {
+        let (client, server) = duplex(16 * 1024);
+        let (mut client, mut server) = encrypted_pair(client, server);
+        let payload: Vec<u8> = (0..3 * MAX_RECORD_SIZE + 123).map(|i| (i % 251) as u8).collect();
+
+        let send = async {
+            client.write_all(&payload).await.unwrap();
+            client.flush().await.unwrap();
+            let mut echoed = vec![0; payload.len()];
+            client.read_exact(&mut echoed).await.unwrap();
+            echoed
+        };
+        let echo = async {
+            let mut received = vec![0; payload.len()];
+            server.read_exact(&mut received).await.unwrap();
+            server.write_all(&received).await.unwrap();
+            server.flush().await.unwrap();
+        };
+        let (echoed, ()) = tokio::join!(send, echo);
+        assert_eq!(echoed, payload);
+    }
+
+    #[tokio::test]
+    async fn ciphertext_does_not_contain_the_plaintext() {
+        let (record, _, _) = sealed(b"a message worth hiding").await;
+        assert_eq!(record.len(), 4 + 22 + TAG_LEN);
+        assert!(!record.windows(7).any(|window| window == b"message"));
+    }
+
+    #[tokio::test]
+    async fn tampered_record_fails_authentication() {
+        for position in [4, 10, 4 + 22 + TAG_LEN - 1] {
+            let (mut record, mut receiver, mut raw) = sealed(b"a message worth hiding").await;
+            record[position] ^= 1;
+            raw.write_all(&record).await.unwrap();
+            let mut buf = [0; 64];
+            let error = receiver.read(&mut buf).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn record_length_must_be_within_bounds() {
+        for len in [0, TAG_LEN, MAX_RECORD_SIZE + TAG_LEN + 1, u32::MAX as usize] {
+            let (_, mut receiver, mut raw) = sealed(b"unused").await;
+            raw.write_all(&(len as u32).to_be_bytes()).await.unwrap();
+            let mut buf = [0; 64];
+            let error = receiver.read(&mut buf).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("Invalid record length"), "{}", error);
+        }
+        // The smallest valid length is read in full and then fails authentication.
+        let (_, mut receiver, mut raw) = sealed(b"unused").await;
+        raw.write_all(&(TAG_LEN as u32 + 1).to_be_bytes()).await.unwrap();
+        raw.write_all(&[0; TAG_LEN + 1]).await.unwrap();
+        let error = receiver.read(&mut [0; 64]).await.unwrap_err();
+        assert!(error.to_string().contains("authentication"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn encryption_modes_agree_or_refuse() {
+        use Encryption::*;
+        // Client mode, server mode, then whether each side ends up encrypted,
+        // or `None` where it refuses the connection.
+        let cases = [
+            (Disabled, Disabled, Some(false), Some(false)),
+            (Disabled, Enabled, Some(false), Some(false)),
+            (Disabled, Required, Some(false), None),
+            (Enabled, Disabled, Some(false), Some(false)),
+            (Enabled, Enabled, Some(true), Some(true)),
+            (Enabled, Required, Some(true), Some(true)),
+            (Required, Disabled, None, Some(false)),
+            (Required, Enabled, Some(true), Some(true)),
+            (Required, Required, Some(true), Some(true)),
+        ];
+        let codec = Codec::default();
+        for (client_mode, server_mode, client_expected, server_expected) in cases {
+            let (client, server) = duplex(64 * 1024);
+            let (client, server) = tokio::join!(SecureStream::connect(client, &codec, client_mode), SecureStream::accept(server, &codec, server_mode));
+            let case = format!("{:?} to {:?}", client_mode, server_mode);
+            assert_eq!(client.as_ref().ok().map(SecureStream::is_encrypted), client_expected, "client, {}", case);
+            assert_eq!(server.as_ref().ok().map(SecureStream::is_encrypted), server_expected, "server, {}", case);
+
+            if let (Ok(mut client), Ok(mut server)) = (client, server) {
+                assert_eq!(client.session_id(), server.session_id());
+                let (_, received) = tokio::join!(codec.write(&mut client, &Message::GetStatus), codec.read(&mut server));
+                assert_eq!(received, Ok(Some(Message::GetStatus)), "{}", case);
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, HashMap};
+use secp256k1::PublicKey;
+use wasmi::core::TrapCode;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+// Contracts import host functions from this module and export their memory
+// and a `call` function taking no arguments.
+pub const HOST_MODULE: &str = "env";
+pub const ENTRY_POINT: &str = "call";
+const MEMORY_EXPORT: &str = "memory";
+
+pub const MAX_CODE_SIZE: usize = 512 * 1024;
+pub const MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+// Highest gas limit a single call may ask for.
+pub const MAX_CALL_GAS: u64 = 10_000_000;
+pub const MAX_STORAGE_KEY_SIZE: usize = 256;
+pub const MAX_STORAGE_VALUE_SIZE: usize = 64 * 1024;
+pub const MAX_OUTPUT_SIZE: usize = 64 * 1024;
+pub const MAX_EVENTS: usize = 64;
+pub const MAX_EVENT_SIZE: usize = 4 * 1024;
+
+// The host API, all in `env`:
+//   input_len() -> i32                  length of the call input
+//   read_input(ptr)                     copies the input to `ptr`
+//   storage_get(key, key_len, val, cap) -> i32
+//                                       copies up to `cap` bytes of the value to
+//                                       `val`; returns its full length, or -1
+//   storage_set(key, key_len, val, val_len)
+//   caller(ptr) -> i32                  writes the 33-byte compressed caller key
+//                                       to `ptr` and returns 33, or 0 if none
+//   block_height() -> i64
+//   emit_event(ptr, len)
+//   set_output(ptr, len)                the call's return value
+// Storage keys and values are UTF-8 so they fit the contract's string state.
+const HOST_FUNCTIONS: &[&str] = &[
+    "input_len", "read_input", "storage_get", "storage_set", "caller", "block_height", "emit_event", "set_output",
+];
+
+// Gas costs. Every WASM instruction costs one gas, which is wasmi's fuel, so
+// nodes must run the same wasmi version to agree on gas used. Host calls cost
+// a base amount plus the bytes they move; storage writes cost more per byte
+// since they are kept for good.
+pub struct GasSchedule {
+    // Loading and instantiating the contract.
+    pub call: u64,
+    pub host_call: u64,
+    pub per_byte: u64,
+    pub storage_read: u64,
+    pub storage_write: u64,
+    pub storage_per_byte: u64,
+    pub event: u64,
+}
+
+pub const GAS: GasSchedule = GasSchedule {
+    call: 5_000,
+    host_call: 100,
+    per_byte: 1,
+    storage_read: 500,
+    storage_write: 5_000,
+    storage_per_byte: 20,
+    event: 1_000,
+};
+
+pub const OUT_OF_GAS: &str = "Out of gas";
+
+// What a call can see of the chain it runs on.
+#[derive(Debug, Clone, Copy)]
+pub struct CallContext {
+    pub caller: Option<PublicKey>,
+    pub block_height: u64,
+}
+
+// What a call did. A call that failed changed no state and emitted no
+// events, but still used gas.
+#[derive(Debug)]
+pub struct Execution {
+    pub gas_used: u64,
+    pub result: Result<Vec<u8>, String>,
+    pub events: Vec<Vec<u8>>,
+    // Storage keys the call wrote. There is no host call that deletes a key,
+    // so a written key stays until the contract itself is removed.
+    pub written: Vec<String>,
+}
+
+impl Execution {
+    pub fn failed(gas_used: u64, error: String) -> Self {
+        Execution { gas_used, result: Err(error), events: Vec::new(), written: Vec::new() }
+    }
+}
+
+struct Host {
+    input: Vec<u8>,
+    context: CallContext,
+    state: HashMap<String, String>,
+    // Writes are kept apart and applied only when the call succeeds.
+    writes: BTreeMap<String, String>,
+    output: Vec<u8>,
+    events: Vec<Vec<u8>>,
+    limits: StoreLimits,
+}
+
+fn engine() -> Engine {
+    let mut config = Config::default();
+    // Float results may differ in NaN bits across machines.
+    config.floats(false);
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+fn compile(engine: &Engine, code: &[u8]) -> Result<Module, String> {
+    if code.len() > MAX_CODE_SIZE {
+        return Err(format!("Contract code is {} bytes, more than the {} allowed", code.len(), MAX_CODE_SIZE));
+    }
+    Module::new(engine, code).map_err(|e| format!("Invalid contract module: {}", e))
+}
+
+// Checks a module before it is deployed: it must compile, import nothing
+// but the host API, and export its memory and entry point.
+pub fn validate(code: &[u8]) -> Result<(), String> {
+    let engine = engine();
+    let module = compile(&engine, code)?;
+    for import in module.imports() {
+        if import.module() != HOST_MODULE || !HOST_FUNCTIONS.contains(&import.name()) || import.ty().func().is_none() {
+            return Err(format!("Contract imports unsupported {}::{}", import.module(), import.name()));
+        }
+    }
+    match module.get_export(MEMORY_EXPORT) {
+        Some(export) if export.memory().is_some() => {}
+        _ => return Err(format!("Contract does not export `{}`", MEMORY_EXPORT)),
+    }
+    match module.get_export(ENTRY_POINT).as_ref().and_then(|export| export.func()) {
+        Some(ty) if ty.params().is_empty() && ty.results().is_empty() => {}
+        _ => return Err(format!("Contract does not export `{}` as a function without parameters or results", ENTRY_POINT)),
+    }
+    // Linking catches host functions imported with the wrong signature.
+    let mut store = Store::new(&engine, Host::new(HashMap::new(), Vec::new(), CallContext { caller: None, block_height: 0 }));
+    store.limiter(|host| &mut host.limits);
+    linker(&engine)?.instantiate(&mut store, &module)
+        .map_err(|e| format!("Contract cannot be linked: {}", e))?;
+    Ok(())
+}
+
+// Runs the contract's entry point against `state` with at most `gas_limit`
+// gas. The state only changes if the call completes.
+pub fn execute(code: &[u8], state: &mut HashMap<String, String>, input: &[u8], context: CallContext, gas_limit: u64) -> Execution {
+    if gas_limit < GAS.call {
+        return Execution::failed(gas_limit, OUT_OF_GAS.to_string());
+    }
+    let engine = engine();
+    let module = match compile(&engine, code) {
+        Ok(module) => module,
+        Err(e) => return Execution::failed(GAS.call, e),
+    };
+    let mut store = Store::new(&engine, Host::new(std::mem::take(state), input.to_vec(), context));
+    store.limiter(|host| &mut host.limits);
+    store.set_fuel(gas_limit - GAS.call).expect("Fuel metering is enabled");
+
+    let result = linker(&engine).and_then(|linker| {
+        let instance = linker.instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| call_error("Contract cannot be instantiated", e))?;
+        let entry = instance.get_typed_func::<(), ()>(&store, ENTRY_POINT)
+            .map_err(|e| format!("Contract has no entry point: {}", e))?;
+        entry.call(&mut store, ()).map_err(|e| call_error("Contract execution failed", e))
+    });
+
+    let gas_used = gas_limit - store.get_fuel().expect("Fuel metering is enabled");
+    let host = store.into_data();
+    *state = host.state;
+    match result {
+        Ok(()) => {
+            let written = host.writes.keys().cloned().collect();
+            state.extend(host.writes);
+            Execution { gas_used, result: Ok(host.output), events: host.events, written }
+        }
+        Err(e) => Execution::failed(gas_used, e),
+    }
+}
+
+fn call_error(what: &str, error: wasmi::Error) -> String {
+    match error.as_trap_code() {
+        Some(TrapCode::OutOfFuel) => OUT_OF_GAS.to_string(),
+        _ => format!("{}: {}", what, error),
+    }
+}
+
+impl Host {
+    fn new(state: HashMap<String, String>, input: Vec<u8>, context: CallContext) -> Self {
+        Host {
+            input,
+            context,
+            state,
+            writes: BTreeMap::new(),
+            output: Vec::new(),
+            events: Vec::new(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY_BYTES)
+                .memories(1)
+                .tables(1)
+                .instances(1)
+                .build(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&String> {
+        self.writes.get(key).or_else(|| self.state.get(key))
+    }
+}
+
+fn trap(message: impl Into<String>) -> wasmi::Error {
+    wasmi::Error::new(message.into())
+}
+
+// Takes `gas` from what the call has left, or ends it if that is not enough.
+fn charge(caller: &mut Caller<'_, Host>, gas: u64) -> Result<(), wasmi::Error> {
+    let left = caller.get_fuel().expect("Fuel metering is enabled");
+    match left.checked_sub(gas) {
+        Some(left) => {
+            caller.set_fuel(left).expect("Fuel metering is enabled");
+            Ok(())
+        }
+        None => {
+            caller.set_fuel(0).expect("Fuel metering is enabled");
+            Err(TrapCode::OutOfFuel.into())
+        }
+    }
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, wasmi::Error> {
+    caller.get_export(MEMORY_EXPORT)
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("Contract does not export its memory"))
+}
+
+fn read_memory(caller: &Caller<'_, Host>, ptr: i32, len: i32, max: usize) -> Result<Vec<u8>, wasmi::Error> {
+    let len = len as u32 as usize;
+    if len > max {
+        return Err(trap(format!("{} bytes passed to the host, more than the {} allowed", len, max)));
+    }
+    let start = ptr as u32 as usize;
+    memory(caller)?.data(caller)
+        .get(start..start + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| trap("Memory access out of bounds"))
+}
+
+fn write_memory(caller: &mut Caller<'_, Host>, ptr: i32, bytes: &[u8]) -> Result<(), wasmi::Error> {
+    let memory = memory(caller)?;
+    memory.write(caller, ptr as u32 as usize, bytes).map_err(|_| trap("Memory access out of bounds"))
+}
+
+fn read_string(caller: &Caller<'_, Host>, ptr: i32, len: i32, max: usize) -> Result<String, wasmi::Error> {
+    String::from_utf8(read_memory(caller, ptr, len, max)?).map_err(|_| trap("Storage keys and values must be UTF-8"))
+}
+
+fn linker(engine: &Engine) -> Result<Linker<Host>, String> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(HOST_MODULE, "input_len", |mut caller: Caller<'_, Host>| -> Result<i32, wasmi::Error> {
+        charge(&mut caller, GAS.host_call)?;
+        Ok(caller.data().input.len() as i32)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "read_input", |mut caller: Caller<'_, Host>, ptr: i32| -> Result<(), wasmi::Error> {
+        let input = caller.data().input.clone();
+        charge(&mut caller, GAS.host_call + GAS.per_byte * input.len() as u64)?;
+        write_memory(&mut caller, ptr, &input)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "storage_get", |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, value_ptr: i32, value_cap: i32| -> Result<i32, wasmi::Error> {
+        charge(&mut caller, GAS.host_call + GAS.storage_read)?;
+        let key = read_string(&caller, key_ptr, key_len, MAX_STORAGE_KEY_SIZE)?;
+        let value = match caller.data().get(&key) {
+            Some(value) => value.clone().into_bytes(),
+            None => return Ok(-1),
+        };
+        let copied = value.len().min(value_cap.max(0) as usize);
+        charge(&mut caller, GAS.per_byte * (key.len() + copied) as u64)?;
+        write_memory(&mut caller, value_ptr, &value[..copied])?;
+        Ok(value.len() as i32)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "storage_set", |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, GAS.host_call + GAS.storage_write)?;
+        let key = read_string(&caller, key_ptr, key_len, MAX_STORAGE_KEY_SIZE)?;
+        let value = read_string(&caller, value_ptr, value_len, MAX_STORAGE_VALUE_SIZE)?;
+        charge(&mut caller, GAS.storage_per_byte * (key.len() + value.len()) as u64)?;
+        caller.data_mut().writes.insert(key, value);
+        Ok(())
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "caller", |mut caller: Caller<'_, Host>, ptr: i32| -> Result<i32, wasmi::Error> {
+        charge(&mut caller, GAS.host_call)?;
+        match caller.data().context.caller {
+            Some(key) => {
+                let key = key.serialize();
+                write_memory(&mut caller, ptr, &key)?;
+                Ok(key.len() as i32)
+            }
+            None => Ok(0),
+        }
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "block_height", |mut caller: Caller<'_, Host>| -> Result<i64, wasmi::Error> {
+        charge(&mut caller, GAS.host_call)?;
+        Ok(caller.data().context.block_height as i64)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "emit_event", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, GAS.host_call + GAS.event)?;
+        if caller.data().events.len() >= MAX_EVENTS {
+            return Err(trap(format!("More than {} events", MAX_EVENTS)));
+        }
+        let event = read_memory(&caller, ptr, len, MAX_EVENT_SIZE)?;
+        charge(&mut caller, GAS.per_byte * event.len() as u64)?;
+        caller.data_mut().events.push(event);
+        Ok(())
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap(HOST_MODULE, "set_output", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+        charge(&mut caller, GAS.host_call)?;
+        let output = read_memory(&caller, ptr, len, MAX_OUTPUT_SIZE)?;
+        charge(&mut caller, GAS.per_byte * output.len() as u64)?;
+        caller.data_mut().output = output;
+        Ok(())
+    }).map_err(|e| e.to_string())?;
+    Ok(linker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 64 * 1024;
+
+    // A contract importing the storage, event and output host calls, with
+    // "key" at 0 and "value" at 16 in its memory, whose entry point runs `body`.
+    fn contract(body: &str) -> Vec<u8> {
+        wat::parse_str(format!(r#"(module
+            (import "env" "storage_get" (func $storage_get (param i32 i32 i32 i32) (result i32)))
+            (import "env" "storage_set" (func $storage_set (param i32 i32 i32 i32)))
+            (import "env" "emit_event" (func $emit_event (param i32 i32)))
+            (import "env" "set_output" (func $set_output (param i32 i32)))
+            (memory (export "memory") 2)
+            (data (i32.const 0) "key")
+            (data (i32.const 16) "value")
+            (func (export "call") (local $i i32) {}))"#, body)).unwrap()
+    }
+
+    fn run(code: &[u8], state: &mut HashMap<String, String>) -> Execution {
+        execute(code, state, b"", CallContext { caller: None, block_height: 1 }, MAX_CALL_GAS)
+    }
+
+    fn error(execution: &Execution) -> &str {
+        execution.result.as_ref().unwrap_err()
+    }
+
+    #[test]
+    fn only_the_host_api_may_be_imported() {
+        assert!(validate(&contract("")).is_ok());
+        for import in [r#"(import "wasi" "fd_write" (func))"#, r#"(import "env" "random" (func (result i32)))"#, r#"(import "env" "table" (table 1 funcref))"#] {
+            let code = wat::parse_str(format!(r#"(module {} (memory (export "memory") 1) (func (export "call")))"#, import)).unwrap();
+            assert!(validate(&code).unwrap_err().contains("unsupported"), "{}", import);
+        }
+    }
+
+    #[test]
+    fn memory_and_entry_point_must_be_exported() {
+        for module in [
+            r#"(module (memory 1) (func (export "call")))"#,
+            r#"(module (memory (export "memory") 1))"#,
+            r#"(module (memory (export "memory") 1) (func (export "call") (param i32)))"#,
+            r#"(module (memory (export "memory") 1) (func (export "call") (result i32) i32.const 0))"#,
+        ] {
+            assert!(validate(&wat::parse_str(module).unwrap()).unwrap_err().contains("does not export"), "{}", module);
+        }
+        assert!(validate(b"not wasm").unwrap_err().contains("Invalid contract module"));
+    }
+
+    #[test]
+    fn host_functions_must_be_imported_with_their_signature() {
+        let code = wat::parse_str(r#"(module
+            (import "env" "storage_set" (func (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "call")))"#).unwrap();
+        assert!(validate(&code).unwrap_err().contains("cannot be linked"));
+    }
+
+    #[test]
+    fn memory_cannot_grow_past_the_limit() {
+        let max_pages = MAX_MEMORY_BYTES / PAGE_SIZE;
+        let grow = |pages: usize| contract(&format!("(if (i32.eq (memory.grow (i32.const {})) (i32.const -1)) (then unreachable))", pages));
+        assert!(run(&grow(max_pages - 2), &mut HashMap::new()).result.is_ok());
+        assert!(run(&grow(max_pages - 1), &mut HashMap::new()).result.is_err());
+
+        let too_large = wat::parse_str(format!(r#"(module (memory (export "memory") {}) (func (export "call")))"#, max_pages + 1)).unwrap();
+        assert!(validate(&too_large).is_err());
+    }
+
+    #[test]
+    fn storage_writes_are_visible_within_the_call_and_kept_when_it_succeeds() {
+        let code = contract("
+            (call $storage_set (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 5))
+            (drop (call $storage_get (i32.const 0) (i32.const 3) (i32.const 32) (i32.const 16)))
+            (call $set_output (i32.const 32) (i32.const 5))");
+        let mut state = HashMap::new();
+        let execution = run(&code, &mut state);
+        assert_eq!(execution.result, Ok(b"value".to_vec()));
+        assert_eq!(execution.written, vec!["key".to_string()]);
+        assert_eq!(state.get("key").map(String::as_str), Some("value"));
+    }
+
+    #[test]
+    fn calls_emitting_too_many_events_fail_without_changing_state() {
+        let emit = |count: usize| contract(&format!("
+            (call $storage_set (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 5))
+            (loop $emit
+                (call $emit_event (i32.const 16) (i32.const 5))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br_if $emit (i32.lt_u (local.get $i) (i32.const {}))))", count));
+        assert_eq!(run(&emit(MAX_EVENTS), &mut HashMap::new()).events.len(), MAX_EVENTS);
+
+        let mut state = HashMap::from([("key".to_string(), "old".to_string())]);
+        let execution = run(&emit(MAX_EVENTS + 1), &mut state);
+        assert!(error(&execution).contains(&format!("More than {} events", MAX_EVENTS)));
+        assert!(execution.events.is_empty() && execution.written.is_empty());
+        assert_eq!(state, HashMap::from([("key".to_string(), "old".to_string())]));
+    }
+
+    #[test]
+    fn output_is_limited_in_size() {
+        let output = |len: usize| contract(&format!("(call $set_output (i32.const 0) (i32.const {}))", len));
+        assert_eq!(run(&output(MAX_OUTPUT_SIZE), &mut HashMap::new()).result.map(|output| output.len()), Ok(MAX_OUTPUT_SIZE));
+        let execution = run(&output(MAX_OUTPUT_SIZE + 1), &mut HashMap::new());
+        assert!(error(&execution).contains("more than the"));
+    }
+
+    #[test]
+    fn running_out_of_gas_charges_the_whole_limit_and_keeps_the_state() {
+        let code = contract("
+            (call $storage_set (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 5))
+            (loop $forever (br $forever))");
+        let mut state = HashMap::from([("key".to_string(), "old".to_string())]);
+        let execution = execute(&code, &mut state, b"", CallContext { caller: None, block_height: 1 }, 100_000);
+        assert_eq!(error(&execution), OUT_OF_GAS);
+        assert_eq!(execution.gas_used, 100_000);
+        assert!(execution.written.is_empty());
+        assert_eq!(state, HashMap::from([("key".to_string(), "old".to_string())]));
+
+        let execution = execute(&code, &mut state, b"", CallContext { caller: None, block_height: 1 }, GAS.call - 1);
+        assert_eq!((error(&execution), execution.gas_used), (OUT_OF_GAS, GAS.call - 1));
+    }
+
+    #[test]
+    fn gas_used_is_the_same_on_every_run() {
+        let code = contract("
+            (call $storage_set (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 5))
+            (loop $count
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br_if $count (i32.lt_u (local.get $i) (i32.const 1000))))
+            (call $emit_event (i32.const 16) (i32.const 5))");
+        let gas_used = run(&code, &mut HashMap::new()).gas_used;
+        assert!(gas_used > GAS.call + GAS.storage_write + GAS.event + 1000);
+        for _ in 0..3 {
+            assert_eq!(run(&code, &mut HashMap::new()).gas_used, gas_used);
+        }
+
+        // Exactly that much gas is enough, and one less is not.
+        let context = CallContext { caller: None, block_height: 1 };
+        assert!(execute(&code, &mut HashMap::new(), b"", context, gas_used).result.is_ok());
+        let execution = execute(&code, &mut HashMap::new(), b"", context, gas_used - 1);
+        assert_eq!((error(&execution), execution.gas_used), (OUT_OF_GAS, gas_used - 1));
+    }
+}
+